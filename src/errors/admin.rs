@@ -0,0 +1,65 @@
+use super::server::{Err, ServerError};
+use aws_sdk_cognitoidentityprovider::error::{ProvideErrorMetadata, SdkError};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum AdminError {
+    UserNotFound,
+    UserExists,
+    GroupNotFound,
+    GroupExists,
+    InvalidParameter(String),
+}
+
+impl AdminError {
+    // Cognito's own exceptions for things the caller got wrong, rather than we did
+    pub fn from_sdk<E: ProvideErrorMetadata + Error + 'static>(e: SdkError<E>) -> ServerError {
+        let admin_error = match e.code() {
+            Some("UserNotFoundException") => AdminError::UserNotFound,
+            Some("UsernameExistsException") => AdminError::UserExists,
+            // UserNotFoundException already covers the user side
+            Some("ResourceNotFoundException") => AdminError::GroupNotFound,
+            Some("GroupExistsException") => AdminError::GroupExists,
+            Some("InvalidParameterException") | Some("InvalidPasswordException") => {
+                AdminError::InvalidParameter(e.message().unwrap_or_default().into())
+            }
+            _ => return e.into(),
+        };
+
+        admin_error.into()
+    }
+}
+
+impl Display for AdminError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            AdminError::UserNotFound => f.write_str("User not found"),
+            AdminError::UserExists => f.write_str("User already exists"),
+            AdminError::GroupNotFound => f.write_str("Group not found"),
+            AdminError::GroupExists => f.write_str("Group already exists"),
+            AdminError::InvalidParameter(e) => write!(f, "Invalid parameter: {}", e),
+        }
+    }
+}
+
+impl Err for AdminError {}
+
+impl From<AdminError> for ServerError {
+    fn from(e: AdminError) -> Self {
+        let status = match e {
+            AdminError::UserNotFound | AdminError::GroupNotFound => 404,
+            AdminError::UserExists | AdminError::GroupExists => 409,
+            AdminError::InvalidParameter(..) => 400,
+        };
+
+        // Admins are trusted with the reason
+        ServerError::new(
+            Some(e.to_string()),
+            Some(e.to_string()),
+            Arc::new(e),
+            status,
+        )
+    }
+}
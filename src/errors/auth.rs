@@ -1 +1,53 @@
+use super::server::{Err, ServerError};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
 
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum AuthError {
+    NotConfigured(&'static str),
+    MissingCredentials,
+    InvalidToken(String),
+    TokenExpired,
+    InsufficientScope(String),
+    NotInGroup(String),
+    InvalidRequest(String),
+    TokenNotFound,
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            AuthError::NotConfigured(var) => {
+                write!(f, "Authentication is not configured, missing {}", var)
+            }
+            AuthError::MissingCredentials => f.write_str("No credentials on the request"),
+            AuthError::InvalidToken(e) => write!(f, "Invalid token: {}", e),
+            AuthError::TokenExpired => f.write_str("Token has expired"),
+            AuthError::InsufficientScope(scope) => write!(f, "Token lacks the {} scope", scope),
+            AuthError::NotInGroup(group) => write!(f, "Not a member of the {} group", group),
+            AuthError::InvalidRequest(e) => write!(f, "Invalid request: {}", e),
+            AuthError::TokenNotFound => f.write_str("Token not found"),
+        }
+    }
+}
+
+impl Err for AuthError {}
+
+impl From<AuthError> for ServerError {
+    fn from(e: AuthError) -> Self {
+        let (message, status) = match e {
+            AuthError::NotConfigured(..) => ("Internal Server Error".into(), 500),
+            AuthError::MissingCredentials
+            | AuthError::InvalidToken(..)
+            | AuthError::TokenExpired => ("Unauthorized".into(), 401),
+            AuthError::InsufficientScope(..) | AuthError::NotInGroup(..) => {
+                ("Forbidden".into(), 403)
+            }
+            // Only reachable with valid credentials, so the reason can be shown
+            AuthError::InvalidRequest(..) => (e.to_string(), 400),
+            AuthError::TokenNotFound => ("Not Found".into(), 404),
+        };
+
+        ServerError::new(Some(e.to_string()), Some(message), Arc::new(e), status)
+    }
+}
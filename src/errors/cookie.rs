@@ -0,0 +1,49 @@
+use super::server::{Err, ServerError};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CookieCryptoError {
+    InvalidKey,
+    UnknownKey,
+    Malformed,
+    Undecryptable,
+    NoSigningKey,
+    BadSignature,
+}
+
+impl Display for CookieCryptoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            CookieCryptoError::InvalidKey => {
+                f.write_str("Cookie key is not a base64 encoded 256 bit key")
+            }
+            CookieCryptoError::UnknownKey => f.write_str("Cookie was sealed with an unknown key"),
+            CookieCryptoError::Malformed => f.write_str("Cookie value is not a sealed token"),
+            CookieCryptoError::Undecryptable => f.write_str("Cookie failed to decrypt"),
+            CookieCryptoError::NoSigningKey => f.write_str("COOKIE_SIGNING_KEYS is not set"),
+            CookieCryptoError::BadSignature => f.write_str("Cookie signature does not match"),
+        }
+    }
+}
+
+impl Err for CookieCryptoError {}
+
+impl From<CookieCryptoError> for ServerError {
+    fn from(e: CookieCryptoError) -> Self {
+        match e {
+            CookieCryptoError::InvalidKey | CookieCryptoError::NoSigningKey => ServerError::new(
+                Some(e.to_string()),
+                Some("Internal Server Error".into()),
+                Arc::new(e),
+                500,
+            ),
+            _ => ServerError::new(
+                Some(e.to_string()),
+                Some("Unauthorized".into()),
+                Arc::new(e),
+                401,
+            ),
+        }
+    }
+}
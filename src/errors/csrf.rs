@@ -0,0 +1,35 @@
+use super::server::{Err, ServerError};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CsrfError {
+    MissingToken,
+    TokenMismatch,
+    OriginNotAllowed,
+    MissingOrigin,
+}
+
+impl Display for CsrfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            CsrfError::MissingToken => f.write_str("Missing CSRF token"),
+            CsrfError::TokenMismatch => f.write_str("CSRF token mismatch"),
+            CsrfError::OriginNotAllowed => f.write_str("Origin not allowed"),
+            CsrfError::MissingOrigin => f.write_str("Missing Origin or Referer header"),
+        }
+    }
+}
+
+impl Err for CsrfError {}
+
+impl From<CsrfError> for ServerError {
+    fn from(e: CsrfError) -> Self {
+        ServerError::new(
+            Some(e.to_string()),
+            Some("Forbidden".into()),
+            Arc::new(e),
+            403,
+        )
+    }
+}
@@ -1,4 +1,12 @@
+pub mod admin;
 pub mod auth;
+pub mod cookie;
+pub mod csrf;
 pub mod login;
+pub mod oauth;
+pub mod organization;
+pub mod policy;
+pub mod proxy;
 pub mod server;
+pub mod tenant;
 pub mod user_token;
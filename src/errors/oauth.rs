@@ -0,0 +1,66 @@
+use super::server::{Err, ServerError};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum OAuthError {
+    NotConfigured(&'static str),
+    MissingState,
+    StateMismatch,
+    MissingCode,
+    ProviderError(String),
+    TokenExchange(String),
+    PasswordLoginDisabled(String),
+    UnsupportedGrantType(String),
+    InvalidClient,
+    InvalidScope(String),
+}
+
+impl Display for OAuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            OAuthError::NotConfigured(var) => write!(f, "OAuth is not configured, missing {}", var),
+            OAuthError::MissingState => f.write_str("No pending authorization for this browser"),
+            OAuthError::StateMismatch => f.write_str("OAuth state does not match"),
+            OAuthError::MissingCode => f.write_str("Authorization code missing from callback"),
+            OAuthError::ProviderError(e) => write!(f, "Identity provider returned an error: {}", e),
+            OAuthError::TokenExchange(e) => write!(f, "Token exchange failed: {}", e),
+            OAuthError::UnsupportedGrantType(grant) => {
+                write!(f, "Unsupported grant type {}", grant)
+            }
+            OAuthError::InvalidClient => f.write_str("Client authentication failed"),
+            OAuthError::InvalidScope(e) => write!(f, "Invalid scope: {}", e),
+            OAuthError::PasswordLoginDisabled(idp) => {
+                write!(
+                    f,
+                    "Password login is disabled for this domain, sign in with {}",
+                    idp
+                )
+            }
+        }
+    }
+}
+
+impl Err for OAuthError {}
+
+impl From<OAuthError> for ServerError {
+    fn from(e: OAuthError) -> Self {
+        let (message, status) = match e {
+            OAuthError::NotConfigured(..) => ("Internal Server Error".into(), 500),
+            OAuthError::MissingState | OAuthError::StateMismatch | OAuthError::MissingCode => {
+                ("Bad Request".into(), 400)
+            }
+            OAuthError::ProviderError(..) | OAuthError::InvalidClient => {
+                ("Unauthorized".into(), 401)
+            }
+            OAuthError::UnsupportedGrantType(..) | OAuthError::InvalidScope(..) => {
+                (e.to_string(), 400)
+            }
+            OAuthError::TokenExchange(..) => ("Bad Gateway".into(), 502),
+            // The caller needs to know which IdP to go to instead
+            OAuthError::PasswordLoginDisabled(..) => (e.to_string(), 403),
+        };
+
+        ServerError::new(Some(e.to_string()), Some(message), Arc::new(e), status)
+    }
+}
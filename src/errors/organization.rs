@@ -0,0 +1,52 @@
+use super::server::{Err, ServerError};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum OrgError {
+    NotFound,
+    NotAMember,
+    MemberExists,
+    MemberNotFound,
+    InvitationNotFound,
+    InviteeNotFound,
+    RoleRequired(String),
+    LastOwner,
+    Mismatch,
+}
+
+impl Display for OrgError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            OrgError::NotFound => f.write_str("Organization not found"),
+            OrgError::NotAMember => f.write_str("Not a member of the organization"),
+            OrgError::MemberExists => f.write_str("Already a member of the organization"),
+            OrgError::MemberNotFound => f.write_str("Member not found"),
+            OrgError::InvitationNotFound => f.write_str("Invitation not found"),
+            OrgError::InviteeNotFound => f.write_str("No user with that email"),
+            OrgError::RoleRequired(role) => write!(f, "Requires the {} role", role),
+            OrgError::LastOwner => f.write_str("An organization must keep at least one owner"),
+            OrgError::Mismatch => {
+                f.write_str("Organization in the body does not match the request")
+            }
+        }
+    }
+}
+
+impl Err for OrgError {}
+
+impl From<OrgError> for ServerError {
+    fn from(e: OrgError) -> Self {
+        let (message, status) = match e {
+            // Outsiders can't tell an organization they aren't in from one that doesn't exist
+            OrgError::NotFound | OrgError::NotAMember => ("Organization not found".into(), 404),
+            OrgError::MemberNotFound | OrgError::InvitationNotFound | OrgError::InviteeNotFound => {
+                (e.to_string(), 404)
+            }
+            OrgError::MemberExists | OrgError::LastOwner => (e.to_string(), 409),
+            OrgError::RoleRequired(..) | OrgError::Mismatch => (e.to_string(), 403),
+        };
+
+        ServerError::new(Some(e.to_string()), Some(message), Arc::new(e), status)
+    }
+}
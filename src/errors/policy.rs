@@ -0,0 +1,37 @@
+use super::server::{Err, ServerError};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum PolicyError {
+    Invalid(String),
+    Denied { rule: String, reason: String },
+    InvalidPath(String),
+}
+
+impl Display for PolicyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            PolicyError::Invalid(e) => write!(f, "Invalid policy: {}", e),
+            PolicyError::Denied { rule, reason } => {
+                write!(f, "Denied by policy rule {}: {}", rule, reason)
+            }
+            PolicyError::InvalidPath(path) => write!(f, "Invalid path {}", path),
+        }
+    }
+}
+
+impl Err for PolicyError {}
+
+impl From<PolicyError> for ServerError {
+    fn from(e: PolicyError) -> Self {
+        let (message, status) = match &e {
+            PolicyError::Invalid(..) => ("Internal Server Error".into(), 500),
+            // The rule ID tells whoever is debugging which rule to look at, the reason stays in logs
+            PolicyError::Denied { rule, .. } => (format!("Forbidden by policy rule {}", rule), 403),
+            PolicyError::InvalidPath(..) => ("Bad Request".into(), 400),
+        };
+
+        ServerError::new(Some(e.to_string()), Some(message), Arc::new(e), status)
+    }
+}
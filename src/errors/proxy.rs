@@ -0,0 +1,44 @@
+use super::server::{Err, ServerError};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum ProxyError {
+    Invalid(String),
+    NoUpstream(String),
+    InvalidPath(String),
+    BadUpgrade(String),
+    Unavailable(String),
+    Timeout,
+}
+
+impl Display for ProxyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ProxyError::Invalid(e) => write!(f, "Invalid proxy configuration: {}", e),
+            ProxyError::NoUpstream(path) => write!(f, "No upstream for {}", path),
+            ProxyError::InvalidPath(path) => write!(f, "Invalid path {}", path),
+            ProxyError::BadUpgrade(e) => write!(f, "Invalid WebSocket upgrade: {}", e),
+            ProxyError::Unavailable(e) => write!(f, "Upstream unavailable: {}", e),
+            ProxyError::Timeout => f.write_str("Upstream timed out"),
+        }
+    }
+}
+
+impl Err for ProxyError {}
+
+impl From<ProxyError> for ServerError {
+    fn from(e: ProxyError) -> Self {
+        let (message, status) = match &e {
+            ProxyError::Invalid(..) => ("Internal Server Error".into(), 500),
+            ProxyError::NoUpstream(..) => ("Not Found".into(), 404),
+            ProxyError::BadUpgrade(..) => (e.to_string(), 400),
+            ProxyError::InvalidPath(..) => ("Bad Request".into(), 400),
+            // What went wrong upstream stays in the logs
+            ProxyError::Unavailable(..) => ("Bad Gateway".into(), 502),
+            ProxyError::Timeout => ("Gateway Timeout".into(), 504),
+        };
+
+        ServerError::new(Some(e.to_string()), Some(message), Arc::new(e), status)
+    }
+}
@@ -30,7 +30,7 @@ pub struct ServerError {
 
 impl Display for ServerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result {
-        write!(f, "{}", self)
+        write!(f, "{} ({})", self.message, self.cause)
     }
 }
 
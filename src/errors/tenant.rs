@@ -0,0 +1,36 @@
+use super::server::{Err, ServerError};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum TenantError {
+    Invalid(String),
+    Unknown(String),
+    HeaderNotTrusted(String),
+}
+
+impl Display for TenantError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            TenantError::Invalid(e) => write!(f, "Invalid tenant configuration: {}", e),
+            TenantError::Unknown(e) => write!(f, "Unknown tenant: {}", e),
+            TenantError::HeaderNotTrusted(e) => {
+                write!(f, "X-Tenant {} does not match the request's host", e)
+            }
+        }
+    }
+}
+
+impl Err for TenantError {}
+
+impl From<TenantError> for ServerError {
+    fn from(e: TenantError) -> Self {
+        let (message, status) = match &e {
+            TenantError::Invalid(..) => ("Internal Server Error".into(), 500),
+            TenantError::Unknown(..) => ("Unknown tenant".into(), 404),
+            TenantError::HeaderNotTrusted(..) => ("Forbidden".into(), 403),
+        };
+
+        ServerError::new(Some(e.to_string()), Some(message), Arc::new(e), status)
+    }
+}
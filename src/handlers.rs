@@ -1,39 +1,98 @@
-use crate::operations::auth::AuthCredentials;
-use crate::operations::user::{UserAuthCredentials, UserLoginRequest};
-use crate::operations::user_token::UserToken;
-use crate::{errors::server::ServerError, operations::auth::AuthClient};
-use actix_web::{web, HttpRequest, HttpResponse};
+use crate::operations::audit::{AuditLog, AuditRecord};
+use crate::operations::authenticated_user::{
+    authenticate, bearer_token, presented_token, AuthenticatedUser, Credential, VerifiedIdToken,
+};
+use crate::operations::client_credentials::{
+    client_authentication, ClientCredentialsRequest, ServiceTokenCache,
+};
+use crate::operations::cookie_policy::{CookiePolicy, TOKEN_COOKIES};
+use crate::operations::denylist::TokenDenylist;
+use crate::operations::forward_auth::ForwardAuth;
+use crate::operations::home_realm::HomeRealmDirectory;
+use crate::operations::introspection::{IntrospectionCache, IntrospectionRequest, MAX_BATCH_SIZE};
+use crate::operations::oauth::{
+    is_local_path, HostedUi, OAuthAuthorizeQuery, OAuthCallbackQuery, PendingAuthorization,
+    STATE_COOKIE,
+};
+use crate::operations::organization::{
+    set_home_org, CreateOrgRequest, InviteMemberRequest, OrgJson, OrgMember, Organizations,
+    UpdateMemberRequest, ORGS_SCOPE,
+};
+use crate::operations::personal_access_token::{
+    CreateTokenRequest, PersonalAccessTokens, TOKENS_SCOPE,
+};
+use crate::operations::proxy::ReverseProxy;
+use crate::operations::session_token::SessionKeys;
+use crate::operations::tenant::{CurrentTenant, Tenant};
+use crate::operations::token_validity::TokenValidityCache;
+use crate::operations::user::{
+    ChangePasswordRequest, TokenMode, UserAuthCredentials, UserLoginRequest,
+};
+use crate::operations::user_directory::{
+    CreateGroupRequest, CreateUserRequest, ListGroupMembersQuery, ListUsersQuery, UserDirectory,
+};
+use crate::operations::ws_ticket::{RedeemTicketRequest, WsTickets};
+use crate::{
+    errors::{
+        admin::AdminError, auth::AuthError, oauth::OAuthError, organization::OrgError,
+        server::ServerError,
+    },
+    operations::auth::AuthClient,
+};
+use actix_web::{http::header, web, Either, HttpRequest, HttpResponse};
+use aws_config::SdkConfig;
 use dotenv::dotenv;
-use jsonwebtokens_cognito::KeySet;
-use std::env::{self};
+use reqwest::Client;
+use serde_json::json;
 
 pub async fn login_user_handler(
     req: HttpRequest,
-    params: web::Form<UserLoginRequest>,
+    params: Either<web::Json<UserLoginRequest>, web::Form<UserLoginRequest>>,
+    cookie_policy: web::Data<CookiePolicy>,
+    token_validity: web::Data<TokenValidityCache>,
+    session_keys: web::Data<SessionKeys>,
+    home_realms: web::Data<HomeRealmDirectory>,
+    tenant: CurrentTenant,
 ) -> Result<HttpResponse, ServerError> {
+    let cookie_policy = cookie_policy.with_domain(tenant.cookie_domain.as_ref());
+    let params = match params {
+        Either::Left(json) => json.into_inner(),
+        Either::Right(form) => form.into_inner(),
+    };
+
     // gather variables
     dotenv().ok();
-    let config = aws_config::load_from_env().await;
-
-    let secret: Option<String> = env::var("COGNITO_SECRET").ok();
-    let client_id: Option<String> = env::var("APP_CLIENT_ID").ok();
-    let user_pool_id: Option<String> = env::var("USER_POOL_ID").ok();
 
-    // println!("{:?}\n{:?}\n{:?}\n", secret, client_id, user_pool_id);
+    // SSO domains skip the password attempt and go to their IdP through the hosted UI
+    if let Some(mapping) = home_realms.lookup(&tenant, &params.email).await? {
+        match params.token_mode(&req) {
+            TokenMode::Cookie => {
+                return start_authorization(
+                    &req,
+                    &cookie_policy,
+                    &tenant,
+                    Some(&mapping.identity_provider),
+                    Some(&params.email),
+                    None,
+                    params.remember_me(),
+                );
+            }
+            TokenMode::Body if mapping.enforced => {
+                return Err(OAuthError::PasswordLoginDisabled(mapping.identity_provider).into());
+            }
+            TokenMode::Body => {}
+        }
+    }
 
-    let mut credentials = AuthCredentials::prepare(
-        secret.clone(),
-        client_id.clone(),
-        user_pool_id.clone(),
-        "ADMIN_USER_PASSWORD_AUTH",
-    );
+    let mut credentials = tenant.credentials("ADMIN_USER_PASSWORD_AUTH");
 
     credentials.set_hash();
-    let updater = format!("{}{}", params.email, client_id.clone().unwrap());
+    let updater = format!("{}{}", params.email, tenant.client_id);
     credentials.update_hash(updater.as_bytes());
     credentials.set_encoding();
 
-    let mut auth_output = AuthClient::new(credentials, &config);
+    let cognito_config = tenant.sdk_config().await;
+    let mut auth_output = AuthClient::new(credentials, cognito_config);
     auth_output.prepare();
 
     let user_credentials = params.package_data();
@@ -43,10 +102,29 @@ pub async fn login_user_handler(
     let authentication_result: aws_sdk_cognitoidentityprovider::types::AuthenticationResultType =
         authentication_result?.clone();
 
-    let mut user_login_res =
-        UserAuthCredentials::build(authentication_result, req.headers().get("host").cloned());
+    let mut user_login_res = UserAuthCredentials::build(
+        authentication_result,
+        req.headers().get("host").cloned(),
+        cookie_policy.is_secure_request(&req),
+    );
+    user_login_res.add_session_token(&session_keys, &tenant.id)?;
+
+    if params.token_mode(&req) == TokenMode::Body {
+        return user_login_res.json_response();
+    }
 
-    user_login_res.cookify();
+    if params.remember_me() {
+        let lifetimes = token_validity
+            .get(
+                cognito_config,
+                Some(tenant.user_pool_id.clone()),
+                Some(tenant.client_id.clone()),
+            )
+            .await;
+        user_login_res.set_lifetimes(Some(lifetimes));
+    }
+
+    user_login_res.cookify(&cookie_policy, &req);
 
     let res = user_login_res.response();
     println!("Res: {:?}", res);
@@ -54,30 +132,1154 @@ pub async fn login_user_handler(
     res
 }
 
-pub async fn authorize_user_handler(req: HttpRequest) -> Result<HttpResponse, ServerError> {
-    let client_id: Option<String> = env::var("APP_CLIENT_ID").ok();
+// nginx auth_request and Traefik ForwardAuth point here
+pub async fn authorize_user_handler(
+    req: HttpRequest,
+    forward_auth: web::Data<ForwardAuth>,
+) -> Result<HttpResponse, ServerError> {
+    forward_auth.check(&req).await
+}
+
+// Revokes the caller's session here and the refresh token with Cognito, and clears its cookies.
+// An expired or missing access token still logs out; there is just no session left to revoke.
+pub async fn logout_handler(
+    req: HttpRequest,
+    user: Option<AuthenticatedUser>,
+    cookie_policy: web::Data<CookiePolicy>,
+    denylist: web::Data<TokenDenylist>,
+    tenant: CurrentTenant,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let cookie_policy = cookie_policy.with_domain(tenant.cookie_domain.as_ref());
+    // Failures here are logged rather than returned, so the cookies are cleared regardless
+    if let Some(claims) = user.as_ref().and_then(|user| user.claims.as_ref()) {
+        if let Err(e) = denylist.revoke_token(&config, claims).await {
+            println!("Failed to revoke access token: {}", e.cause);
+        }
+    }
+
+    let refresh_token = cookie_policy
+        .read(&req, "refresh_token")
+        .and_then(|refresh_token| cookie_policy.open("refresh_token", &refresh_token).ok());
+    if let Some(refresh_token) = refresh_token {
+        let directory = UserDirectory::new(tenant.sdk_config().await, tenant.user_pool_id.clone());
+        if let Err(e) = directory
+            .revoke_refresh_token(
+                &refresh_token,
+                &tenant.client_id,
+                tenant.client_secret.as_deref(),
+            )
+            .await
+        {
+            println!("Failed to revoke refresh token: {}", e.cause);
+        }
+    }
+
+    clear_session_cookies(&req, &cookie_policy)
+}
+
+pub async fn change_password_handler(
+    req: HttpRequest,
+    user: AuthenticatedUser,
+    params: web::Json<ChangePasswordRequest>,
+    cookie_policy: web::Data<CookiePolicy>,
+    denylist: web::Data<TokenDenylist>,
+    tenant: CurrentTenant,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    // Cognito only takes the user's own access token for this
+    if !matches!(user.credential, Credential::CognitoUser) {
+        return Err(AuthError::InsufficientScope("session".into()).into());
+    }
+    let access_token = presented_token(&req)?.ok_or(AuthError::MissingCredentials)?;
+
+    let cookie_policy = cookie_policy.with_domain(tenant.cookie_domain.as_ref());
 
-    let user_auth_credentials: UserAuthCredentials = req.into();
-    let access_token = user_auth_credentials.tokens.get("access_token");
-    if let Some(UserToken::Cookie(token)) = access_token {
-        if let (Some(region), Some(user_pool_id)) = (
-            env::var("COGNITO_REGION").ok(),
-            env::var("USER_POOL_ID").ok(),
-        ) {
-            let keyset = KeySet::new(region, user_pool_id)?;
-            keyset.prefetch_jwks().await?;
+    let directory = UserDirectory::new(tenant.sdk_config().await, tenant.user_pool_id.clone());
 
-            let verifier = keyset
-                .new_access_token_verifier(&[&client_id.unwrap()])
-                .build()?;
+    directory
+        .change_password(
+            &access_token,
+            &params.previous_password,
+            &params.proposed_password,
+        )
+        .await?;
 
-            let foo = keyset.verify(&token.value(), &verifier).await?;
+    // Every sign-in was made with the old password, this one included
+    directory.sign_out_self(&access_token).await?;
+    denylist.revoke_user(&config, &tenant.id, &user.sub).await?;
+
+    clear_session_cookies(&req, &cookie_policy)
+}
 
-            println!("{:?}", foo);
+fn clear_session_cookies(
+    req: &HttpRequest,
+    cookie_policy: &CookiePolicy,
+) -> Result<HttpResponse, ServerError> {
+    let mut res = HttpResponse::NoContent().finish();
+    for name in TOKEN_COOKIES.iter().chain(&["session_token"]) {
+        for cookie in cookie_policy.removals(req, name) {
+            res.add_cookie(&cookie)?;
         }
     }
 
-    let res = HttpResponse::Unauthorized().finish();
+    Ok(res)
+}
+
+// Everything under a configured upstream prefix, once the caller is signed in
+pub async fn proxy_handler(
+    req: HttpRequest,
+    payload: web::Payload,
+    user: AuthenticatedUser,
+    proxy: web::Data<ReverseProxy>,
+    cookie_policy: web::Data<CookiePolicy>,
+    session_keys: web::Data<SessionKeys>,
+    tenant: CurrentTenant,
+) -> Result<HttpResponse, ServerError> {
+    proxy
+        .forward(&req, payload, &user, &cookie_policy, &session_keys, &tenant)
+        .await
+}
+
+pub async fn oauth_authorize_handler(
+    req: HttpRequest,
+    query: web::Query<OAuthAuthorizeQuery>,
+    cookie_policy: web::Data<CookiePolicy>,
+    home_realms: web::Data<HomeRealmDirectory>,
+    tenant: CurrentTenant,
+) -> Result<HttpResponse, ServerError> {
+    let mut identity_provider = query.identity_provider.clone();
+
+    // An enforced domain goes to its IdP whatever was asked for, so the hosted UI never
+    // offers it a password form; other mapped domains only when no IdP was picked
+    if let Some(login_hint) = &query.login_hint {
+        if let Some(mapping) = home_realms.lookup(&tenant, login_hint).await? {
+            if mapping.enforced || identity_provider.is_none() {
+                identity_provider = Some(mapping.identity_provider);
+            }
+        }
+    }
+
+    start_authorization(
+        &req,
+        &cookie_policy.with_domain(tenant.cookie_domain.as_ref()),
+        &tenant,
+        identity_provider.as_deref(),
+        query.login_hint.as_deref(),
+        query.return_to.clone(),
+        query.remember_me(),
+    )
+}
+
+fn start_authorization(
+    req: &HttpRequest,
+    cookie_policy: &CookiePolicy,
+    tenant: &Tenant,
+    identity_provider: Option<&str>,
+    login_hint: Option<&str>,
+    return_to: Option<String>,
+    remember_me: bool,
+) -> Result<HttpResponse, ServerError> {
+    let hosted_ui = HostedUi::for_tenant(tenant)?;
+    let redirect_uri = hosted_ui.redirect_uri(req, cookie_policy);
+
+    let pending = PendingAuthorization::new(redirect_uri, return_to, remember_me);
+
+    let location = hosted_ui.authorize_url(
+        &pending,
+        &pending.redirect_uri,
+        identity_provider,
+        login_hint,
+    );
+
+    // 303 so a form POST to /login turns into a GET on the hosted UI
+    let mut res = HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, location))
+        .finish();
+    res.add_cookie(&pending.cookie(cookie_policy, req)?)?;
+
+    Ok(res)
+}
+
+pub async fn oauth_callback_handler(
+    req: HttpRequest,
+    query: web::Query<OAuthCallbackQuery>,
+    client: web::Data<Client>,
+    cookie_policy: web::Data<CookiePolicy>,
+    token_validity: web::Data<TokenValidityCache>,
+    session_keys: web::Data<SessionKeys>,
+    tenant: CurrentTenant,
+) -> Result<HttpResponse, ServerError> {
+    let cookie_policy = cookie_policy.with_domain(tenant.cookie_domain.as_ref());
+    let hosted_ui = HostedUi::for_tenant(&tenant)?;
+
+    if let Some(error) = &query.error {
+        let description = query.error_description.clone().unwrap_or_default();
+        return Err(OAuthError::ProviderError(format!("{} {}", error, description)).into());
+    }
+
+    let pending = PendingAuthorization::from_request(&req, &cookie_policy)?;
+    pending.check_state(query.state.as_deref().unwrap_or_default())?;
+    let code = query.code.as_deref().ok_or(OAuthError::MissingCode)?;
+
+    let tokens = hosted_ui
+        .exchange_code(&client, code, &pending.redirect_uri, &pending.code_verifier)
+        .await?;
+
+    if let Some(id_token) = &tokens.id_token {
+        tenant
+            .verifier
+            .verify_id_token(id_token, Some(&pending.nonce), Some(&tokens.access_token))
+            .await?;
+    }
+
+    let mut user_login_res = UserAuthCredentials::build(
+        tokens.into(),
+        req.headers().get("host").cloned(),
+        cookie_policy.is_secure_request(&req),
+    );
+    user_login_res.add_session_token(&session_keys, &tenant.id)?;
+
+    if pending.remember_me {
+        let lifetimes = token_validity
+            .get(
+                tenant.sdk_config().await,
+                Some(tenant.user_pool_id.clone()),
+                Some(hosted_ui.client_id.clone()),
+            )
+            .await;
+        user_login_res.set_lifetimes(Some(lifetimes));
+    }
+
+    user_login_res.cookify(&cookie_policy, &req);
+
+    let return_to = pending
+        .return_to
+        .as_deref()
+        .filter(|path| is_local_path(path))
+        .unwrap_or("/");
+    let mut res = user_login_res.redirect_response(return_to)?;
+    res.add_cookie(&cookie_policy.removal(
+        STATE_COOKIE,
+        req.headers().get("host"),
+        cookie_policy.is_secure_request(&req),
+    ))?;
 
     Ok(res)
 }
+
+pub async fn me_handler(id_token: VerifiedIdToken) -> HttpResponse {
+    let claims = id_token.0;
+
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(json!({
+            "sub": claims.sub,
+            "username": claims.username,
+            "email": claims.email,
+            "email_verified": claims.email_verified.unwrap_or(false),
+            "phone_number": claims.phone_number,
+            "phone_number_verified": claims.phone_number_verified.unwrap_or(false),
+            "name": claims.name,
+            "given_name": claims.given_name,
+            "family_name": claims.family_name,
+            "groups": claims.groups,
+            "attributes": claims.custom,
+        }))
+}
+
+pub async fn jwks_handler(session_keys: web::Data<SessionKeys>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "public, max-age=300"))
+        .json(session_keys.jwks())
+}
+
+// Tokens are created from a signed-in session only, so a leaked token can't mint its own
+// successors, and services have no user whose access a token could stand in for
+pub async fn create_token_handler(
+    user: AuthenticatedUser,
+    params: web::Json<CreateTokenRequest>,
+    tokens: web::Data<PersonalAccessTokens>,
+    tenant: CurrentTenant,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    if user.is_personal_access_token() || user.is_service() {
+        return Err(AuthError::InsufficientScope("session".into()).into());
+    }
+
+    let created = tokens
+        .create(&config, &tenant.id, &user.sub, params.into_inner())
+        .await?;
+
+    Ok(HttpResponse::Created()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(created))
+}
+
+pub async fn list_tokens_handler(
+    user: AuthenticatedUser,
+    tokens: web::Data<PersonalAccessTokens>,
+    tenant: CurrentTenant,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    user.require_scope(TOKENS_SCOPE)?;
+
+    let tokens = tokens.list(&config, &tenant, &user.sub).await?;
+
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+pub async fn revoke_token_handler(
+    user: AuthenticatedUser,
+    path: web::Path<String>,
+    tokens: web::Data<PersonalAccessTokens>,
+    tenant: CurrentTenant,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    user.require_scope(TOKENS_SCOPE)?;
+
+    tokens.revoke(&config, &tenant, &user.sub, &path).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Only the client_credentials grant; users get their tokens through /login or the hosted UI
+pub async fn token_handler(
+    req: HttpRequest,
+    params: web::Form<ClientCredentialsRequest>,
+    client: web::Data<Client>,
+    service_tokens: web::Data<ServiceTokenCache>,
+    tenant: CurrentTenant,
+) -> Result<HttpResponse, ServerError> {
+    if params.grant_type != "client_credentials" {
+        return Err(OAuthError::UnsupportedGrantType(params.grant_type.clone()).into());
+    }
+
+    let (client_id, client_secret) = client_authentication(
+        &req,
+        params.client_id.as_deref(),
+        params.client_secret.as_deref(),
+    )
+    .ok_or(OAuthError::InvalidClient)?;
+
+    let token = service_tokens
+        .token(
+            &client,
+            &tenant,
+            &client_id,
+            &client_secret,
+            params.scope.as_deref(),
+        )
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(token))
+}
+
+// For other services to check tokens they were handed. The caller is one of the tenant's M2M
+// clients, either with its credentials, which Cognito checks by issuing it a token, or with
+// a token it already has.
+pub async fn introspect_handler(
+    req: HttpRequest,
+    params: Either<web::Json<IntrospectionRequest>, web::Form<IntrospectionRequest>>,
+    client: web::Data<Client>,
+    service_tokens: web::Data<ServiceTokenCache>,
+    introspection: web::Data<IntrospectionCache>,
+    tenant: CurrentTenant,
+) -> Result<HttpResponse, ServerError> {
+    let params = match params {
+        Either::Left(json) => json.into_inner(),
+        Either::Right(form) => form.into_inner(),
+    };
+
+    // Checked before the client, so a malformed request costs no call to Cognito
+    if !matches!(
+        (&params.token, params.tokens.len()),
+        (Some(_), 0) | (None, 1..=MAX_BATCH_SIZE)
+    ) {
+        return Err(AuthError::InvalidRequest(format!(
+            "send either token or 1 to {} tokens",
+            MAX_BATCH_SIZE
+        ))
+        .into());
+    }
+
+    authenticate_client(
+        &req,
+        &client,
+        &service_tokens,
+        &tenant,
+        params.client_id.as_deref(),
+        params.client_secret.as_deref(),
+    )
+    .await?;
+
+    let body = match params.token {
+        Some(token) => json!(introspection.introspect(&req, &tenant, &token).await?),
+        None => json!({
+            "results": introspection.introspect_all(&req, &tenant, &params.tokens).await
+        }),
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(body))
+}
+
+// Browsers trade their session for a ticket to put in the WebSocket URL
+pub async fn ws_ticket_handler(
+    req: HttpRequest,
+    user: AuthenticatedUser,
+    tickets: web::Data<WsTickets>,
+    tenant: CurrentTenant,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let denylist = req.app_data::<web::Data<TokenDenylist>>();
+    let ticket = tickets
+        .issue(&config, denylist.map(|d| d.as_ref()), &tenant.id, &user)
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(ticket))
+}
+
+// WebSocket services redeem the ticket they were connected with, authenticated like /introspect
+pub async fn redeem_ws_ticket_handler(
+    req: HttpRequest,
+    params: Either<web::Json<RedeemTicketRequest>, web::Form<RedeemTicketRequest>>,
+    client: web::Data<Client>,
+    service_tokens: web::Data<ServiceTokenCache>,
+    tickets: web::Data<WsTickets>,
+    tenant: CurrentTenant,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let params = match params {
+        Either::Left(json) => json.into_inner(),
+        Either::Right(form) => form.into_inner(),
+    };
+
+    authenticate_client(
+        &req,
+        &client,
+        &service_tokens,
+        &tenant,
+        params.client_id.as_deref(),
+        params.client_secret.as_deref(),
+    )
+    .await?;
+
+    let denylist = req.app_data::<web::Data<TokenDenylist>>();
+    let claims = tickets
+        .redeem(
+            &config,
+            denylist.map(|d| d.as_ref()),
+            &tenant.id,
+            &params.ticket,
+        )
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(claims))
+}
+
+// A resource server, by its client_credentials token or by its client id and secret
+async fn authenticate_client(
+    req: &HttpRequest,
+    client: &Client,
+    service_tokens: &ServiceTokenCache,
+    tenant: &Tenant,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+) -> Result<(), ServerError> {
+    if bearer_token(req).is_some() {
+        return match authenticate(req).await?.is_service() {
+            true => Ok(()),
+            false => Err(OAuthError::InvalidClient.into()),
+        };
+    }
+
+    let (client_id, client_secret) =
+        client_authentication(req, client_id, client_secret).ok_or(OAuthError::InvalidClient)?;
+    service_tokens
+        .token(client, tenant, &client_id, &client_secret, None)
+        .await?;
+
+    Ok(())
+}
+
+// Every admin call is audited, callers turned away for not being admins included, so the
+// check is made here rather than by an extractor that would reject them unrecorded
+async fn admin_action<T>(
+    req: &HttpRequest,
+    audit: &AuditLog,
+    config: &SdkConfig,
+    tenant: &Tenant,
+    action: &str,
+    target: Option<&str>,
+    operation: impl AsyncFnOnce(&UserDirectory) -> Result<T, ServerError>,
+) -> Result<T, ServerError> {
+    let (actor, result) = match authenticate(req).await {
+        Ok(user) => {
+            let result = match user.require_admin(tenant) {
+                Ok(()) => {
+                    let directory =
+                        UserDirectory::new(tenant.sdk_config().await, tenant.user_pool_id.clone());
+                    operation(&directory).await
+                }
+                Err(e) => Err(e.into()),
+            };
+            (Some(user), result)
+        }
+        Err(e) => (None, Err(e)),
+    };
+
+    audit
+        .record(
+            config,
+            AuditRecord::new(req, actor.as_ref(), action, target, &result),
+        )
+        .await;
+
+    result
+}
+
+pub async fn admin_list_users_handler(
+    req: HttpRequest,
+    tenant: CurrentTenant,
+    query: web::Query<ListUsersQuery>,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let users = admin_action(
+        &req,
+        &audit,
+        &config,
+        &tenant,
+        "ListUsers",
+        query.filter.as_deref(),
+        async |directory| directory.list(&query).await,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(users))
+}
+
+pub async fn admin_get_user_handler(
+    req: HttpRequest,
+    tenant: CurrentTenant,
+    path: web::Path<String>,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let user = admin_action(
+        &req,
+        &audit,
+        &config,
+        &tenant,
+        "AdminGetUser",
+        Some(&path),
+        async |directory| directory.get(&path).await,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+pub async fn admin_create_user_handler(
+    req: HttpRequest,
+    tenant: CurrentTenant,
+    params: web::Json<CreateUserRequest>,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let params = params.into_inner();
+    let email = params.email.clone();
+    let user = admin_action(
+        &req,
+        &audit,
+        &config,
+        &tenant,
+        "AdminCreateUser",
+        Some(&email),
+        async |directory| directory.create(params).await,
+    )
+    .await?;
+
+    Ok(HttpResponse::Created().json(user))
+}
+
+pub async fn admin_disable_user_handler(
+    req: HttpRequest,
+    tenant: CurrentTenant,
+    path: web::Path<String>,
+    audit: web::Data<AuditLog>,
+    denylist: web::Data<TokenDenylist>,
+    tokens: web::Data<PersonalAccessTokens>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    admin_action(
+        &req,
+        &audit,
+        &config,
+        &tenant,
+        "AdminDisableUser",
+        Some(&path),
+        async |directory| {
+            directory.disable(&path).await?;
+            let sub = user_sub(directory, &path).await?;
+            revoke_sessions(&config, &denylist, &tenant, &sub).await?;
+            tokens.revoke_all(&config, &tenant, &sub).await
+        },
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn admin_enable_user_handler(
+    req: HttpRequest,
+    tenant: CurrentTenant,
+    path: web::Path<String>,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    admin_action(
+        &req,
+        &audit,
+        &config,
+        &tenant,
+        "AdminEnableUser",
+        Some(&path),
+        async |directory| directory.enable(&path).await,
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn admin_reset_password_handler(
+    req: HttpRequest,
+    tenant: CurrentTenant,
+    path: web::Path<String>,
+    audit: web::Data<AuditLog>,
+    denylist: web::Data<TokenDenylist>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    admin_action(
+        &req,
+        &audit,
+        &config,
+        &tenant,
+        "AdminResetUserPassword",
+        Some(&path),
+        async |directory| {
+            directory.reset_password(&path).await?;
+            let sub = user_sub(directory, &path).await?;
+            revoke_sessions(&config, &denylist, &tenant, &sub).await
+        },
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn admin_delete_user_handler(
+    req: HttpRequest,
+    tenant: CurrentTenant,
+    path: web::Path<String>,
+    audit: web::Data<AuditLog>,
+    denylist: web::Data<TokenDenylist>,
+    tokens: web::Data<PersonalAccessTokens>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    admin_action(
+        &req,
+        &audit,
+        &config,
+        &tenant,
+        "AdminDeleteUser",
+        Some(&path),
+        async |directory| {
+            // Looked up first, since nothing is left to look up once the account is gone
+            let sub = user_sub(directory, &path).await?;
+            directory.delete(&path).await?;
+            revoke_sessions(&config, &denylist, &tenant, &sub).await?;
+            tokens.revoke_all(&config, &tenant, &sub).await
+        },
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn admin_sign_out_user_handler(
+    req: HttpRequest,
+    tenant: CurrentTenant,
+    path: web::Path<String>,
+    audit: web::Data<AuditLog>,
+    denylist: web::Data<TokenDenylist>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    admin_action(
+        &req,
+        &audit,
+        &config,
+        &tenant,
+        "AdminUserGlobalSignOut",
+        Some(&path),
+        async |directory| {
+            directory.sign_out(&path).await?;
+            let sub = user_sub(directory, &path).await?;
+            revoke_sessions(&config, &denylist, &tenant, &sub).await
+        },
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Cognito stops the user signing in or refreshing, but the access tokens they hold stay valid
+// until the denylist says otherwise
+async fn revoke_sessions(
+    config: &SdkConfig,
+    denylist: &TokenDenylist,
+    tenant: &Tenant,
+    sub: &str,
+) -> Result<(), ServerError> {
+    denylist.revoke_user(config, &tenant.id, sub).await
+}
+
+async fn user_sub(directory: &UserDirectory, username: &str) -> Result<String, ServerError> {
+    let mut account = directory.get(username).await?;
+    Ok(account
+        .attributes
+        .remove("sub")
+        .ok_or(AdminError::UserNotFound)?)
+}
+
+pub async fn admin_list_user_groups_handler(
+    req: HttpRequest,
+    tenant: CurrentTenant,
+    path: web::Path<String>,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let groups = admin_action(
+        &req,
+        &audit,
+        &config,
+        &tenant,
+        "AdminListGroupsForUser",
+        Some(&path),
+        async |directory| directory.groups_for_user(&path).await,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(groups))
+}
+
+pub async fn admin_list_groups_handler(
+    req: HttpRequest,
+    tenant: CurrentTenant,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let groups = admin_action(
+        &req,
+        &audit,
+        &config,
+        &tenant,
+        "ListGroups",
+        None,
+        async |directory| directory.list_groups().await,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(groups))
+}
+
+pub async fn admin_create_group_handler(
+    req: HttpRequest,
+    tenant: CurrentTenant,
+    params: web::Json<CreateGroupRequest>,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let params = params.into_inner();
+    let name = params.name.clone();
+    let group = admin_action(
+        &req,
+        &audit,
+        &config,
+        &tenant,
+        "CreateGroup",
+        Some(&name),
+        async |directory| directory.create_group(params).await,
+    )
+    .await?;
+
+    Ok(HttpResponse::Created().json(group))
+}
+
+pub async fn admin_delete_group_handler(
+    req: HttpRequest,
+    tenant: CurrentTenant,
+    path: web::Path<String>,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    admin_action(
+        &req,
+        &audit,
+        &config,
+        &tenant,
+        "DeleteGroup",
+        Some(&path),
+        async |directory| directory.delete_group(&path).await,
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn admin_list_group_members_handler(
+    req: HttpRequest,
+    tenant: CurrentTenant,
+    path: web::Path<String>,
+    query: web::Query<ListGroupMembersQuery>,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let members = admin_action(
+        &req,
+        &audit,
+        &config,
+        &tenant,
+        "ListUsersInGroup",
+        Some(&path),
+        async |directory| directory.list_group_members(&path, &query).await,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(members))
+}
+
+pub async fn admin_add_group_member_handler(
+    req: HttpRequest,
+    tenant: CurrentTenant,
+    path: web::Path<(String, String)>,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let (group, username) = path.into_inner();
+    admin_action(
+        &req,
+        &audit,
+        &config,
+        &tenant,
+        "AdminAddUserToGroup",
+        Some(&format!("{}/{}", group, username)),
+        async |directory| directory.add_to_group(&username, &group).await,
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn admin_remove_group_member_handler(
+    req: HttpRequest,
+    tenant: CurrentTenant,
+    path: web::Path<(String, String)>,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let (group, username) = path.into_inner();
+    admin_action(
+        &req,
+        &audit,
+        &config,
+        &tenant,
+        "AdminRemoveUserFromGroup",
+        Some(&format!("{}/{}", group, username)),
+        async |directory| directory.remove_from_group(&username, &group).await,
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn create_org_handler(
+    req: HttpRequest,
+    user: AuthenticatedUser,
+    tenant: CurrentTenant,
+    params: web::Json<CreateOrgRequest>,
+    organizations: web::Data<Organizations>,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    user.require_scope(ORGS_SCOPE)?;
+
+    let result = organizations
+        .create(&config, &tenant.id, &user, &params.name)
+        .await;
+    let target = result.as_ref().ok().map(|(org, _)| org.id.clone());
+    audit
+        .record(
+            &config,
+            AuditRecord::new(
+                &req,
+                Some(&user),
+                "CreateOrganization",
+                target.as_deref(),
+                &result,
+            ),
+        )
+        .await;
+    let (org, membership) = result?;
+
+    // Services have no account of their own to carry a home organization
+    if !user.is_service() {
+        let directory = UserDirectory::new(tenant.sdk_config().await, tenant.user_pool_id.clone());
+        let account = directory
+            .get(user.username.as_deref().unwrap_or(&user.sub))
+            .await?;
+        set_home_org(&directory, &account, &org.id).await?;
+    }
+
+    Ok(HttpResponse::Created().json(json!({
+        "organization": org,
+        "membership": membership,
+    })))
+}
+
+pub async fn list_orgs_handler(
+    user: AuthenticatedUser,
+    tenant: CurrentTenant,
+    organizations: web::Data<Organizations>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    user.require_scope(ORGS_SCOPE)?;
+
+    let memberships = organizations
+        .memberships_for_user(&config, &tenant.id, &user.sub)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(memberships))
+}
+
+pub async fn get_org_handler(
+    member: OrgMember,
+    organizations: web::Data<Organizations>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let org = organizations
+        .get(&config, &member.membership.org_id)
+        .await?
+        .ok_or(OrgError::NotFound)?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "organization": org,
+        "membership": member.membership,
+    })))
+}
+
+pub async fn list_org_members_handler(
+    member: OrgMember,
+    organizations: web::Data<Organizations>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let members = organizations
+        .members(&config, &member.membership.org_id)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(members))
+}
+
+pub async fn invite_org_member_handler(
+    req: HttpRequest,
+    member: OrgMember,
+    tenant: CurrentTenant,
+    params: OrgJson<InviteMemberRequest>,
+    organizations: web::Data<Organizations>,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let directory = UserDirectory::new(tenant.sdk_config().await, tenant.user_pool_id.clone());
+
+    let target = format!("{}/{}", member.membership.org_id, params.0.email);
+    let result = organizations
+        .invite(&config, &directory, &tenant, &member, params.0)
+        .await;
+    audit
+        .record(
+            &config,
+            AuditRecord::new(
+                &req,
+                Some(&member.user),
+                "InviteOrgMember",
+                Some(&target),
+                &result,
+            ),
+        )
+        .await;
+
+    Ok(HttpResponse::Created().json(result?))
+}
+
+pub async fn list_invitations_handler(
+    user: AuthenticatedUser,
+    tenant: CurrentTenant,
+    organizations: web::Data<Organizations>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    user.require_scope(ORGS_SCOPE)?;
+
+    let invitations = organizations
+        .invitations_for_user(&config, &tenant.id, &user.sub)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(invitations))
+}
+
+// The invitee joins, and takes the organization as their home one if they had none
+pub async fn accept_invitation_handler(
+    req: HttpRequest,
+    user: AuthenticatedUser,
+    tenant: CurrentTenant,
+    path: web::Path<String>,
+    organizations: web::Data<Organizations>,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    user.require_scope(ORGS_SCOPE)?;
+    let directory = UserDirectory::new(tenant.sdk_config().await, tenant.user_pool_id.clone());
+
+    let result = organizations
+        .accept(&config, &directory, &tenant.id, &user, &path)
+        .await;
+    audit
+        .record(
+            &config,
+            AuditRecord::new(
+                &req,
+                Some(&user),
+                "AcceptOrgInvitation",
+                Some(&path),
+                &result,
+            ),
+        )
+        .await;
+
+    Ok(HttpResponse::Created().json(result?))
+}
+
+pub async fn update_org_member_handler(
+    req: HttpRequest,
+    member: OrgMember,
+    path: web::Path<(String, String)>,
+    params: OrgJson<UpdateMemberRequest>,
+    organizations: web::Data<Organizations>,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let (org_id, user_sub) = path.into_inner();
+
+    let target = format!("{}/{}", org_id, user_sub);
+    let result = organizations
+        .update_member(&config, &member, &user_sub, params.0.role)
+        .await;
+    audit
+        .record(
+            &config,
+            AuditRecord::new(
+                &req,
+                Some(&member.user),
+                "UpdateOrgMember",
+                Some(&target),
+                &result,
+            ),
+        )
+        .await;
+    result?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn remove_org_member_handler(
+    req: HttpRequest,
+    member: OrgMember,
+    tenant: CurrentTenant,
+    path: web::Path<(String, String)>,
+    organizations: web::Data<Organizations>,
+    audit: web::Data<AuditLog>,
+    config: web::Data<SdkConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let directory = UserDirectory::new(tenant.sdk_config().await, tenant.user_pool_id.clone());
+    let (org_id, user_sub) = path.into_inner();
+
+    let target = format!("{}/{}", org_id, user_sub);
+    let result = organizations
+        .remove(&config, &directory, &member, &user_sub)
+        .await;
+    audit
+        .record(
+            &config,
+            AuditRecord::new(
+                &req,
+                Some(&member.user),
+                "RemoveOrgMember",
+                Some(&target),
+                &result,
+            ),
+        )
+        .await;
+    result?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::test_support::tenants;
+    use actix_web::{test, App};
+
+    async fn introspect(body: serde_json::Value) -> u16 {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Client::new()))
+                .app_data(web::Data::new(ServiceTokenCache::from_env()))
+                .app_data(web::Data::new(IntrospectionCache::from_env()))
+                .app_data(web::Data::new(tenants(None)))
+                .route("/introspect", web::post().to(introspect_handler)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/introspect")
+            .insert_header(("host", "login.acme.com"))
+            .set_json(body)
+            .to_request();
+
+        test::call_service(&app, req).await.status().as_u16()
+    }
+
+    #[actix_rt::test]
+    async fn introspect_takes_one_token_or_a_bounded_batch() {
+        assert_eq!(introspect(json!({})).await, 400);
+        assert_eq!(
+            introspect(json!({"token": "a", "tokens": ["b"]})).await,
+            400
+        );
+        let tokens = vec!["token"; MAX_BATCH_SIZE + 1];
+        assert_eq!(introspect(json!({ "tokens": tokens })).await, 400);
+    }
+
+    #[actix_rt::test]
+    async fn introspect_needs_an_authenticated_client() {
+        assert_eq!(introspect(json!({"token": "a"})).await, 401);
+        assert_eq!(introspect(json!({"tokens": ["a", "b"]})).await, 401);
+    }
+}
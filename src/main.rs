@@ -1,21 +1,236 @@
 // use crate::data_structs::{login_user, register_user, validate_token};
 use actix_web::{web, App, HttpServer};
 use reqwest::Client;
+use std::sync::Arc;
 mod errors;
 mod handlers;
+mod middleware;
 mod operations;
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "actix_web=debug");
+    dotenv::dotenv().ok();
     let client = web::Data::new(Client::new());
+    // The default region's config, for the tables and logs shared by every tenant
+    let config = web::Data::new(aws_config::load_from_env().await);
+    let cookie_policy = operations::cookie_policy::CookiePolicy::from_env();
+    let session_keys = web::Data::new(operations::session_token::SessionKeys::from_env());
+    let tenants = Arc::new(
+        operations::tenant::TenantRegistry::from_env().unwrap_or_else(|e| panic!("{}", e.cause)),
+    );
+    let personal_access_tokens =
+        web::Data::new(operations::personal_access_token::PersonalAccessTokens::from_env());
+    let service_tokens =
+        web::Data::new(operations::client_credentials::ServiceTokenCache::from_env());
+    let introspection = web::Data::new(operations::introspection::IntrospectionCache::from_env());
+    let audit = web::Data::new(operations::audit::AuditLog::from_env());
+    let organizations = web::Data::new(operations::organization::Organizations::from_env());
+    let denylist = web::Data::new(operations::denylist::TokenDenylist::from_env());
+    let forward_auth = web::Data::new(
+        operations::forward_auth::ForwardAuth::from_env().unwrap_or_else(|e| panic!("{}", e)),
+    );
+    let proxy = web::Data::new(
+        operations::proxy::ReverseProxy::from_env(client.get_ref().clone())
+            .unwrap_or_else(|e| panic!("{}", e)),
+    );
+    let ws_tickets = web::Data::new(operations::ws_ticket::WsTickets::from_env());
+    let home_realms = web::Data::new(operations::home_realm::HomeRealmDirectory::from_env(
+        &config,
+    ));
+    let token_validity = web::Data::new(operations::token_validity::TokenValidityCache::from_env());
+    let user_emails = web::Data::new(operations::user_attributes::UserEmails::from_env());
+    let policy_engine =
+        Arc::new(operations::policy::PolicyEngine::from_env().unwrap_or_else(|e| panic!("{}", e)));
+    let mut csrf_policy = operations::csrf::CsrfPolicy::from_env();
+    for prefix in proxy.origin_checked_prefixes() {
+        csrf_policy.check_origin_only(prefix);
+    }
+    csrf_policy.exempt("/auth");
+    let mut cors_policy = operations::cors::CorsPolicy::from_env();
+    cors_policy.allow_header(&csrf_policy.header_name);
+    cors_policy.allow_header(operations::authenticated_user::ID_TOKEN_HEADER);
+    cors_policy.allow_header(operations::tenant::TENANT_HEADER);
+
+    #[cfg(feature = "ext-authz")]
+    if let Some(server) =
+        operations::ext_authz::ExtAuthz::serve_from_env(operations::ext_authz::CheckContext {
+            forward_auth: forward_auth.clone(),
+            tenants: web::Data::from(tenants.clone()),
+            cookie_policy: web::Data::new(cookie_policy.clone()),
+            personal_access_tokens: personal_access_tokens.clone(),
+            denylist: denylist.clone(),
+            config: config.clone(),
+            user_emails: user_emails.clone(),
+        })
+    {
+        actix_rt::spawn(async move {
+            if let Err(e) = server.await {
+                println!("Envoy ext_authz listener stopped: {}", e);
+            }
+        });
+    }
 
     HttpServer::new(move || {
         App::new()
             .app_data(client.clone())
+            .app_data(config.clone())
+            .app_data(web::Data::new(cookie_policy.clone()))
+            .app_data(token_validity.clone())
+            .app_data(session_keys.clone())
+            .app_data(web::Data::from(tenants.clone()))
+            .app_data(personal_access_tokens.clone())
+            .app_data(service_tokens.clone())
+            .app_data(introspection.clone())
+            .app_data(audit.clone())
+            .app_data(organizations.clone())
+            .app_data(denylist.clone())
+            .app_data(forward_auth.clone())
+            .app_data(proxy.clone())
+            .app_data(ws_tickets.clone())
+            .app_data(home_realms.clone())
+            .app_data(user_emails.clone())
+            .wrap(middleware::policy::Authorization::new(
+                policy_engine.clone(),
+            ))
+            .wrap(middleware::csrf::Csrf::new(
+                csrf_policy.clone(),
+                cookie_policy.clone(),
+            ))
+            // before the policy and CSRF checks, which need the tenant and the unprefixed path
+            .wrap(middleware::tenant::TenantResolver::new(tenants.clone()))
+            // registered last so it runs first and answers preflights before anything else
+            .wrap(cors_policy.cors())
             // .route("/register", web::post().to(handlers::register_user_handler))
             .route("/login", web::post().to(handlers::login_user_handler))
-            .route("/auth", web::get().to(handlers::authorize_user_handler))
+            // nginx and Traefik ask with a GET whatever the original method was; other
+            // methods only ever check, so the CSRF layer lets them through too
+            .route("/auth", web::route().to(handlers::authorize_user_handler))
+            .route("/me", web::get().to(handlers::me_handler))
+            .route("/logout", web::post().to(handlers::logout_handler))
+            .route(
+                "/password",
+                web::post().to(handlers::change_password_handler),
+            )
+            .route(
+                "/.well-known/jwks.json",
+                web::get().to(handlers::jwks_handler),
+            )
+            .route(
+                "/oauth/authorize",
+                web::get().to(handlers::oauth_authorize_handler),
+            )
+            .route(
+                "/oauth/callback",
+                web::get().to(handlers::oauth_callback_handler),
+            )
+            .route("/token", web::post().to(handlers::token_handler))
+            .route("/introspect", web::post().to(handlers::introspect_handler))
+            .route("/ws-ticket", web::post().to(handlers::ws_ticket_handler))
+            .route(
+                "/ws-ticket/redeem",
+                web::post().to(handlers::redeem_ws_ticket_handler),
+            )
+            .route("/tokens", web::post().to(handlers::create_token_handler))
+            .route("/tokens", web::get().to(handlers::list_tokens_handler))
+            .route(
+                "/tokens/{id}",
+                web::delete().to(handlers::revoke_token_handler),
+            )
+            .route(
+                "/admin/users",
+                web::get().to(handlers::admin_list_users_handler),
+            )
+            .route(
+                "/admin/users",
+                web::post().to(handlers::admin_create_user_handler),
+            )
+            .route(
+                "/admin/users/{username}",
+                web::get().to(handlers::admin_get_user_handler),
+            )
+            .route(
+                "/admin/users/{username}",
+                web::delete().to(handlers::admin_delete_user_handler),
+            )
+            .route(
+                "/admin/users/{username}/disable",
+                web::post().to(handlers::admin_disable_user_handler),
+            )
+            .route(
+                "/admin/users/{username}/enable",
+                web::post().to(handlers::admin_enable_user_handler),
+            )
+            .route(
+                "/admin/users/{username}/reset-password",
+                web::post().to(handlers::admin_reset_password_handler),
+            )
+            .route(
+                "/admin/users/{username}/sign-out",
+                web::post().to(handlers::admin_sign_out_user_handler),
+            )
+            .route(
+                "/admin/users/{username}/groups",
+                web::get().to(handlers::admin_list_user_groups_handler),
+            )
+            .route(
+                "/admin/groups",
+                web::get().to(handlers::admin_list_groups_handler),
+            )
+            .route(
+                "/admin/groups",
+                web::post().to(handlers::admin_create_group_handler),
+            )
+            .route(
+                "/admin/groups/{group}",
+                web::delete().to(handlers::admin_delete_group_handler),
+            )
+            .route(
+                "/admin/groups/{group}/users",
+                web::get().to(handlers::admin_list_group_members_handler),
+            )
+            .route(
+                "/admin/groups/{group}/users/{username}",
+                web::put().to(handlers::admin_add_group_member_handler),
+            )
+            .route(
+                "/admin/groups/{group}/users/{username}",
+                web::delete().to(handlers::admin_remove_group_member_handler),
+            )
+            .route("/orgs", web::get().to(handlers::list_orgs_handler))
+            .route("/orgs", web::post().to(handlers::create_org_handler))
+            .route("/orgs/{org_id}", web::get().to(handlers::get_org_handler))
+            .route(
+                "/invitations",
+                web::get().to(handlers::list_invitations_handler),
+            )
+            .route(
+                "/orgs/{org_id}/invitation",
+                web::post().to(handlers::accept_invitation_handler),
+            )
+            .route(
+                "/orgs/{org_id}/members",
+                web::get().to(handlers::list_org_members_handler),
+            )
+            .route(
+                "/orgs/{org_id}/members",
+                web::post().to(handlers::invite_org_member_handler),
+            )
+            .route(
+                "/orgs/{org_id}/members/{user_sub}",
+                web::put().to(handlers::update_org_member_handler),
+            )
+            .route(
+                "/orgs/{org_id}/members/{user_sub}",
+                web::delete().to(handlers::remove_org_member_handler),
+            )
+            .configure(|cfg| {
+                for prefix in proxy.prefixes() {
+                    cfg.service(
+                        web::scope(prefix).default_service(web::to(handlers::proxy_handler)),
+                    );
+                }
+            })
     })
     .bind("0.0.0.0:3000")?
     .run()
@@ -0,0 +1,147 @@
+use crate::errors::server::ServerError;
+use crate::operations::cookie_policy::CookiePolicy;
+use crate::operations::csrf::CsrfPolicy;
+use crate::operations::tenant::CurrentTenant;
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpMessage,
+};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use std::rc::Rc;
+
+pub struct Csrf {
+    policy: Rc<CsrfPolicy>,
+    cookie_policy: Rc<CookiePolicy>,
+}
+
+impl Csrf {
+    pub fn new(policy: CsrfPolicy, cookie_policy: CookiePolicy) -> Csrf {
+        Csrf {
+            policy: Rc::new(policy),
+            cookie_policy: Rc::new(cookie_policy),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Csrf
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = CsrfMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfMiddleware {
+            service: Rc::new(service),
+            policy: self.policy.clone(),
+            cookie_policy: self.cookie_policy.clone(),
+        }))
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: Rc<S>,
+    policy: Rc<CsrfPolicy>,
+    cookie_policy: Rc<CookiePolicy>,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // The token cookie goes wherever the tenant's token cookies go
+        let cookie_policy = match req.extensions().get::<CurrentTenant>() {
+            Some(tenant) => self
+                .cookie_policy
+                .with_domain(tenant.cookie_domain.as_ref()),
+            None => (*self.cookie_policy).clone(),
+        };
+
+        if let Err(e) = self.policy.validate(req.request(), &cookie_policy) {
+            let res = req.error_response(ServerError::from(e));
+            return Box::pin(async move { Ok(res.map_into_right_body()) });
+        }
+
+        let token_cookie = match req.cookie(&self.policy.cookie_name) {
+            Some(_) => None,
+            None => Some(self.policy.cookie(
+                CsrfPolicy::generate_token(),
+                &cookie_policy,
+                req.request(),
+            )),
+        };
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let mut res = service.call(req).await?;
+
+            if let Some(cookie) = token_cookie {
+                res.response_mut().add_cookie(&cookie)?;
+            }
+
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::tenant::TenantResolver;
+    use crate::operations::tenant::TenantRegistry;
+    use crate::operations::test_support::cookie_policy;
+    use actix_web::{
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpResponse,
+    };
+    use std::sync::Arc;
+
+    #[actix_rt::test]
+    async fn token_cookie_uses_the_tenant_cookie_domain() {
+        let tenants = TenantRegistry::parse(
+            r#"
+            [[tenant]]
+            id = "acme"
+            region = "eu-west-1"
+            user_pool_id = "eu-west-1_acme"
+            client_id = "acme-client"
+            hosts = ["login.acme.com"]
+            cookie_domain = ".acme.com"
+            "#,
+        )
+        .unwrap();
+        let app = init_service(
+            App::new()
+                .wrap(Csrf::new(CsrfPolicy::from_env(), cookie_policy()))
+                .wrap(TenantResolver::new(Arc::new(tenants)))
+                .route("/me", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/me")
+            .insert_header(("host", "login.acme.com"))
+            .to_request();
+        let res = call_service(&app, req).await;
+
+        let cookie = res
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() == "csrf_token")
+            .unwrap();
+        assert_eq!(cookie.domain(), Some("acme.com"));
+    }
+}
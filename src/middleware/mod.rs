@@ -0,0 +1,3 @@
+pub mod csrf;
+pub mod policy;
+pub mod tenant;
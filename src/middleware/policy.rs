@@ -0,0 +1,105 @@
+use crate::errors::{policy::PolicyError, server::ServerError};
+use crate::operations::authenticated_user::authenticate;
+use crate::operations::policy::{DefaultAction, PolicyEngine, PolicyMode};
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use std::{rc::Rc, sync::Arc};
+
+const DEFAULT_RULE: &str = "default";
+
+pub struct Authorization {
+    engine: Arc<PolicyEngine>,
+}
+
+impl Authorization {
+    pub fn new(engine: Arc<PolicyEngine>) -> Authorization {
+        Authorization { engine }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Authorization
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthorizationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthorizationMiddleware {
+            service: Rc::new(service),
+            engine: self.engine.clone(),
+        }))
+    }
+}
+
+pub struct AuthorizationMiddleware<S> {
+    service: Rc<S>,
+    engine: Arc<PolicyEngine>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthorizationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let policy = self.engine.current();
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            // The router's own view of the path, so percent-encoding can't slip past a rule
+            let path = req.match_info().as_str().to_string();
+
+            let decision: Result<(), ServerError> = match policy.find(req.method(), &path) {
+                Some((rule, captures)) if rule.requires_authentication() => {
+                    match authenticate(req.request()).await {
+                        Ok(user) => rule
+                            .check(&user, &captures, req.query_string(), req.headers())
+                            .map_err(|e| e.into()),
+                        Err(e) => Err(e),
+                    }
+                }
+                Some(_) => Ok(()),
+                None => match policy.default {
+                    DefaultAction::Allow => Ok(()),
+                    DefaultAction::Authenticated => authenticate(req.request()).await.map(|_| ()),
+                    DefaultAction::Deny => Err(PolicyError::Denied {
+                        rule: DEFAULT_RULE.into(),
+                        reason: "no rule matches".into(),
+                    }
+                    .into()),
+                },
+            };
+
+            if let Err(e) = decision {
+                if policy.mode == PolicyMode::Enforce {
+                    let res = req.error_response(e);
+                    return Ok(res.map_into_right_body());
+                }
+                println!(
+                    "Policy (report only) would deny {} {}: {}",
+                    req.method(),
+                    path,
+                    e
+                );
+            }
+
+            let res = service.call(req).await?;
+            Ok(res.map_into_left_body())
+        })
+    }
+}
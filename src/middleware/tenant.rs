@@ -0,0 +1,92 @@
+use crate::errors::server::ServerError;
+use crate::operations::tenant::{CurrentTenant, TenantRegistry};
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{uri::PathAndQuery, Uri},
+    Error, HttpMessage,
+};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use std::{rc::Rc, sync::Arc};
+
+// Picks the tenant before anything else looks at the request, and strips a tenant's path
+// prefix so the routes and policy rules only ever see the path below it
+pub struct TenantResolver {
+    registry: Arc<TenantRegistry>,
+}
+
+impl TenantResolver {
+    pub fn new(registry: Arc<TenantRegistry>) -> TenantResolver {
+        TenantResolver { registry }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for TenantResolver
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = TenantResolverMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TenantResolverMiddleware {
+            service: Rc::new(service),
+            registry: self.registry.clone(),
+        }))
+    }
+}
+
+pub struct TenantResolverMiddleware<S> {
+    service: Rc<S>,
+    registry: Arc<TenantRegistry>,
+}
+
+impl<S, B> Service<ServiceRequest> for TenantResolverMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let tenant = match self.registry.resolve(req.request()) {
+            Ok(tenant) => tenant,
+            Err(e) => {
+                let res = req.error_response(ServerError::from(e));
+                return Box::pin(async move { Ok(res.map_into_right_body()) });
+            }
+        };
+
+        if let Some(path) = tenant.strip_prefix(req.path()) {
+            let path_and_query = match req.query_string() {
+                "" => path.to_string(),
+                query => format!("{}?{}", path, query),
+            };
+
+            let mut parts = req.head().uri.clone().into_parts();
+            if let Ok(path_and_query) = PathAndQuery::try_from(path_and_query) {
+                parts.path_and_query = Some(path_and_query);
+                if let Ok(uri) = Uri::from_parts(parts) {
+                    req.match_info_mut().get_mut().update(&uri);
+                    req.head_mut().uri = uri;
+                }
+            }
+        }
+
+        req.extensions_mut().insert(CurrentTenant(tenant));
+
+        let service = self.service.clone();
+        Box::pin(async move {
+            let res = service.call(req).await?;
+            Ok(res.map_into_left_body())
+        })
+    }
+}
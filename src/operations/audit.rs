@@ -0,0 +1,159 @@
+use crate::errors::server::ServerError;
+use crate::operations::authenticated_user::AuthenticatedUser;
+use crate::operations::session_token::{now, random_id};
+use crate::operations::tenant::CurrentTenant;
+use actix_web::{HttpMessage, HttpRequest};
+use aws_config::SdkConfig;
+use aws_sdk_dynamodb::{types::AttributeValue, Client};
+use serde::Serialize;
+use std::{collections::HashMap, env};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    pub id: String,
+    pub timestamp: i64,
+    pub actor: String,
+    pub actor_sub: String,
+    pub tenant: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    pub status: u16,
+    pub outcome: String,
+    pub ip: Option<String>,
+}
+
+impl AuditRecord {
+    // A request that never authenticated is recorded with an anonymous actor
+    pub fn new<T>(
+        req: &HttpRequest,
+        actor: Option<&AuthenticatedUser>,
+        action: &str,
+        target: Option<&str>,
+        result: &Result<T, ServerError>,
+    ) -> AuditRecord {
+        let (status, outcome) = match result {
+            Ok(_) => (200, "success".to_string()),
+            Err(e) => (e.status_code, e.cause.clone()),
+        };
+
+        AuditRecord {
+            id: random_id(),
+            timestamp: now(),
+            actor: actor
+                .map(|actor| actor.actor())
+                .unwrap_or_else(|| "anonymous".into()),
+            actor_sub: actor.map(|actor| actor.sub.clone()).unwrap_or_default(),
+            tenant: req
+                .extensions()
+                .get::<CurrentTenant>()
+                .map(|tenant| tenant.id.clone()),
+            action: action.into(),
+            target: target.map(|target| target.into()),
+            status,
+            outcome,
+            // The connection's own address; forwarding headers are whatever the client says
+            ip: req.peer_addr().map(|peer| peer.ip().to_string()),
+        }
+    }
+
+    fn to_item(&self) -> HashMap<String, AttributeValue> {
+        let mut item = HashMap::from([
+            ("id".to_string(), AttributeValue::S(self.id.clone())),
+            (
+                "timestamp".to_string(),
+                AttributeValue::N(self.timestamp.to_string()),
+            ),
+            ("actor".to_string(), AttributeValue::S(self.actor.clone())),
+            (
+                "actor_sub".to_string(),
+                AttributeValue::S(self.actor_sub.clone()),
+            ),
+            ("action".to_string(), AttributeValue::S(self.action.clone())),
+            (
+                "status".to_string(),
+                AttributeValue::N(self.status.to_string()),
+            ),
+            (
+                "outcome".to_string(),
+                AttributeValue::S(self.outcome.clone()),
+            ),
+        ]);
+        if let Some(tenant) = &self.tenant {
+            item.insert("tenant".into(), AttributeValue::S(tenant.clone()));
+        }
+        if let Some(target) = &self.target {
+            item.insert("target".into(), AttributeValue::S(target.clone()));
+        }
+        if let Some(ip) = &self.ip {
+            item.insert("ip".into(), AttributeValue::S(ip.clone()));
+        }
+        item
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    table: Option<String>,
+}
+
+impl AuditLog {
+    // AUDIT_TABLE is a DynamoDB table keyed on "id"; without it records go to stdout
+    pub fn from_env() -> AuditLog {
+        AuditLog {
+            table: env::var("AUDIT_TABLE").ok(),
+        }
+    }
+
+    // A failed write is logged rather than failing the action it describes, which has
+    // already happened by the time it's recorded
+    pub async fn record(&self, config: &SdkConfig, record: AuditRecord) {
+        let json = serde_json::to_string(&record).unwrap_or_default();
+
+        let table = match &self.table {
+            Some(table) => table,
+            None => {
+                println!("Audit: {}", json);
+                return;
+            }
+        };
+
+        let result = Client::new(config)
+            .put_item()
+            .table_name(table)
+            .set_item(Some(record.to_item()))
+            .send()
+            .await;
+
+        if let Err(e) = result {
+            println!("Failed to write audit record {}: {}", json, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::auth::AuthError;
+    use crate::operations::test_support::user;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn records_the_connections_address_not_forwarded_ones() {
+        let req = TestRequest::default()
+            .peer_addr("192.0.2.7:50000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "10.0.0.1"))
+            .insert_header(("forwarded", "for=10.0.0.1"))
+            .to_http_request();
+
+        let result: Result<(), ServerError> = Ok(());
+        let record = AuditRecord::new(&req, Some(&user(&[], &[])), "Test", None, &result);
+        assert_eq!(record.ip.as_deref(), Some("192.0.2.7"));
+        assert_eq!(record.actor, "alice");
+        assert_eq!(record.status, 200);
+
+        let result: Result<(), ServerError> = Err(AuthError::MissingCredentials.into());
+        let record = AuditRecord::new(&req, None, "Test", Some("target"), &result);
+        assert_eq!(record.actor, "anonymous");
+        assert_eq!(record.status, 401);
+    }
+}
@@ -1,14 +1,15 @@
-use serde::{Deserialize, Serialize};
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
 use std::collections::HashMap;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-struct AuthKey {
-    kid: String,
-    alg: String,
-    kty: String,
-    e: String,
-    n: String,
-    intended_use: String,
+pub struct AuthKey {
+    pub kid: String,
+    pub alg: String,
+    pub kty: String,
+    pub e: String,
+    pub n: String,
+    #[serde(rename = "use")]
+    pub intended_use: String,
 }
 
 impl AuthKey {
@@ -42,10 +43,23 @@ impl AuthKey {
     }
 }
 
-struct AuthSet {
+#[derive(Debug, Clone)]
+pub struct AuthSet {
     auth_set: HashMap<String, AuthKey>,
 }
 
+// Published in JWKS form: {"keys": [...]}
+impl Serialize for AuthSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut keys: Vec<&AuthKey> = self.auth_set.values().collect();
+        keys.sort_by(|a, b| a.kid.cmp(&b.kid));
+
+        let mut jwks = serializer.serialize_struct("AuthSet", 1)?;
+        jwks.serialize_field("keys", &keys)?;
+        jwks.end()
+    }
+}
+
 impl AuthSet {
     pub fn new() -> AuthSet {
         AuthSet {
@@ -61,3 +75,59 @@ impl AuthSet {
         self.auth_set.insert(auth_key.kid.clone(), auth_key)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn key(kid: &str) -> AuthKey {
+        AuthKey::build(
+            kid.into(),
+            "RS256".into(),
+            "RSA".into(),
+            "AQAB".into(),
+            "modulus".into(),
+            "sig".into(),
+        )
+    }
+
+    #[test]
+    fn serializes_as_jwks_sorted_by_kid() {
+        let mut auth_set = AuthSet::new();
+        auth_set.insert(key("b"));
+        auth_set.insert(key("a"));
+
+        assert_eq!(
+            serde_json::to_value(&auth_set).unwrap(),
+            json!({
+                "keys": [
+                    {"kid": "a", "alg": "RS256", "kty": "RSA", "e": "AQAB", "n": "modulus", "use": "sig"},
+                    {"kid": "b", "alg": "RS256", "kty": "RSA", "e": "AQAB", "n": "modulus", "use": "sig"},
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn insert_replaces_keys_with_the_same_kid() {
+        let mut auth_set = AuthSet::new();
+        assert!(auth_set.insert(key("a")).is_none());
+        assert!(auth_set.insert(key("a")).is_some());
+        assert_eq!(
+            serde_json::to_value(&auth_set).unwrap()["keys"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn empty_set_has_no_keys() {
+        assert_eq!(
+            serde_json::to_value(AuthSet::new()).unwrap(),
+            json!({"keys": []})
+        );
+    }
+}
@@ -0,0 +1,284 @@
+use crate::errors::{auth::AuthError, server::ServerError};
+use crate::operations::claims::{AccessTokenClaims, IdTokenClaims};
+use crate::operations::cookie_policy::CookiePolicy;
+use crate::operations::denylist::TokenDenylist;
+use crate::operations::personal_access_token::PersonalAccessTokens;
+use crate::operations::tenant::{shared_config, CurrentTenant, Tenant};
+use actix_web::{dev::Payload, http::header, web, FromRequest, HttpMessage, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+
+pub const ID_TOKEN_HEADER: &str = "X-Id-Token";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credential {
+    CognitoUser,
+    Service { client_id: String },
+    PersonalAccessToken { token_id: String },
+}
+
+// Whoever is behind the request, whether they came with a Cognito access token (as a Bearer
+// header or the access_token cookie), a personal access token, or are a service holding a
+// client_credentials token
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub sub: String,
+    pub username: Option<String>,
+    pub groups: Vec<String>,
+    pub scopes: Vec<String>,
+    pub credential: Credential,
+    pub expires_at: i64,
+    // Personal access tokens have no claims of their own
+    pub claims: Option<AccessTokenClaims>,
+}
+
+impl AuthenticatedUser {
+    pub fn is_personal_access_token(&self) -> bool {
+        matches!(self.credential, Credential::PersonalAccessToken { .. })
+    }
+
+    pub fn is_service(&self) -> bool {
+        matches!(self.credential, Credential::Service { .. })
+    }
+
+    // A Cognito token is the user's own session and may do anything they can; personal access
+    // tokens and services only what they were granted, e.g. a resource server's orders/read
+    pub fn require_scope(&self, scope: &str) -> Result<(), AuthError> {
+        let limited = self.is_personal_access_token() || self.is_service();
+        match limited && !self.scopes.iter().any(|s| s == scope) {
+            true => Err(AuthError::InsufficientScope(scope.into())),
+            false => Ok(()),
+        }
+    }
+
+    pub fn in_group(&self, group: &str) -> bool {
+        self.groups.iter().any(|g| g == group)
+    }
+
+    // Each tenant names its own admin group, since groups belong to its pool. Personal access
+    // tokens and services carry no groups, so only a signed-in admin gets through.
+    pub fn require_admin(&self, tenant: &Tenant) -> Result<(), AuthError> {
+        let group = tenant
+            .admin_group
+            .as_deref()
+            .ok_or(AuthError::NotConfigured("ADMIN_GROUP"))?;
+
+        match self.in_group(group) {
+            true => Ok(()),
+            false => Err(AuthError::NotInGroup(group.into())),
+        }
+    }
+
+    // What audit records name the actor by
+    pub fn actor(&self) -> String {
+        match &self.credential {
+            Credential::CognitoUser => self.username.clone().unwrap_or_else(|| self.sub.clone()),
+            Credential::Service { client_id } => format!("client:{}", client_id),
+            Credential::PersonalAccessToken { token_id } => {
+                format!("{} (token {})", self.sub, token_id)
+            }
+        }
+    }
+
+    pub fn claim(&self, name: &str) -> Option<String> {
+        match &self.claims {
+            Some(claims) => claims.claim(name),
+            None if name == "sub" => Some(self.sub.clone()),
+            None => None,
+        }
+    }
+
+    pub fn claim_matches(&self, name: &str, expected: &str) -> bool {
+        match &self.claims {
+            Some(claims) => claims.claim_matches(name, expected),
+            None => self.claim(name).as_deref() == Some(expected),
+        }
+    }
+
+    fn from_cognito_claims(claims: AccessTokenClaims) -> AuthenticatedUser {
+        let credential = match claims.username {
+            Some(_) => Credential::CognitoUser,
+            None => Credential::Service {
+                client_id: claims.client_id.clone(),
+            },
+        };
+
+        AuthenticatedUser {
+            sub: claims.sub.clone(),
+            username: claims.username.clone(),
+            groups: claims.groups.clone(),
+            scopes: claims.scope.clone(),
+            credential,
+            expires_at: claims.exp,
+            claims: Some(claims),
+        }
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = ServerError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { authenticate(&req).await })
+    }
+}
+
+// Kept in the request's extensions, so the policy middleware and the handler's extractor
+// verify the token only once between them
+pub async fn authenticate(req: &HttpRequest) -> Result<AuthenticatedUser, ServerError> {
+    let cached = req.extensions().get::<AuthenticatedUser>().cloned();
+    if let Some(user) = cached {
+        return Ok(user);
+    }
+
+    let user = verify(req).await?;
+    req.extensions_mut().insert(user.clone());
+
+    Ok(user)
+}
+
+async fn verify(req: &HttpRequest) -> Result<AuthenticatedUser, ServerError> {
+    let token = presented_token(req)?.ok_or(AuthError::MissingCredentials)?;
+    verify_token(req, &token).await
+}
+
+// The access token the request came with, as a Bearer header or the access_token cookie
+pub fn presented_token(req: &HttpRequest) -> Result<Option<String>, ServerError> {
+    match bearer_token(req) {
+        Some(token) => Ok(Some(token.to_string())),
+        None => cookie_token(req, "access_token"),
+    }
+}
+
+// Any token this backend accepts, checked against the request's tenant, whether it came
+// with the request or was handed over to be introspected
+pub async fn verify_token(
+    req: &HttpRequest,
+    token: &str,
+) -> Result<AuthenticatedUser, ServerError> {
+    if PersonalAccessTokens::is_token(token) {
+        let tokens = req
+            .app_data::<web::Data<PersonalAccessTokens>>()
+            .ok_or(AuthError::NotConfigured("PAT_TABLE"))?;
+        let config = shared_config(req).await;
+        let tenant = CurrentTenant::get(req)?;
+        let details = tokens.authenticate(&config, &tenant, token).await?;
+
+        return Ok(AuthenticatedUser {
+            claims: None,
+            sub: details.user_sub,
+            username: None,
+            groups: vec![],
+            scopes: details.scopes,
+            credential: Credential::PersonalAccessToken {
+                token_id: details.id,
+            },
+            expires_at: details.expires_at,
+        });
+    }
+
+    // Only the resolved tenant's pool can have issued it, so a token from another brand fails here
+    let tenant = CurrentTenant::get(req)?;
+    let claims = tenant.verifier.verify_access_token(token).await?;
+    if let Some(denylist) = req.app_data::<web::Data<TokenDenylist>>() {
+        let config = shared_config(req).await;
+        denylist.check(&config, &tenant.id, &claims).await?;
+    }
+
+    Ok(AuthenticatedUser::from_cognito_claims(claims))
+}
+
+// The signed-in user's profile from their ID token, verified rather than just decoded, so
+// handlers can show what it says. Browsers send it as the id_token cookie, API clients in
+// an X-Id-Token header.
+#[derive(Debug, Clone)]
+pub struct VerifiedIdToken(pub IdTokenClaims);
+
+impl FromRequest for VerifiedIdToken {
+    type Error = ServerError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let cached = req.extensions().get::<VerifiedIdToken>().cloned();
+            if let Some(id_token) = cached {
+                return Ok(id_token);
+            }
+
+            let token = match req
+                .headers()
+                .get(ID_TOKEN_HEADER)
+                .and_then(|v| v.to_str().ok())
+            {
+                Some(token) => token.to_string(),
+                None => cookie_token(&req, "id_token")?.ok_or(AuthError::MissingCredentials)?,
+            };
+            let access_token = presented_token(&req)?;
+
+            let tenant = CurrentTenant::get(&req)?;
+            let claims = tenant
+                .verifier
+                .verify_id_token(&token, None, access_token.as_deref())
+                .await?;
+            // Logging out or being signed out everywhere revokes the ID token with the rest
+            if let Some(denylist) = req.app_data::<web::Data<TokenDenylist>>() {
+                let config = shared_config(&req).await;
+                denylist
+                    .check_id_token(&config, &tenant.id, &claims)
+                    .await?;
+            }
+
+            let id_token = VerifiedIdToken(claims);
+            req.extensions_mut().insert(id_token.clone());
+            Ok(id_token)
+        })
+    }
+}
+
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| v.len() > 7 && v[..7].eq_ignore_ascii_case("bearer "))
+        .map(|v| v[7..].trim())
+        .filter(|v| !v.is_empty())
+}
+
+fn cookie_token(req: &HttpRequest, name: &str) -> Result<Option<String>, ServerError> {
+    let cookie_policy = match req.app_data::<web::Data<CookiePolicy>>() {
+        Some(cookie_policy) => cookie_policy,
+        None => return Ok(None),
+    };
+
+    match cookie_policy.read(req, name) {
+        Some(value) => Ok(Some(cookie_policy.open(name, &value)?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::test_support::{service, tenant, user};
+
+    #[test]
+    fn admins_are_members_of_the_tenants_admin_group() {
+        let tenant = tenant(Some("admins"));
+        assert_eq!(user(&["admins"], &[]).require_admin(&tenant), Ok(()));
+        assert_eq!(
+            user(&["staff"], &[]).require_admin(&tenant),
+            Err(AuthError::NotInGroup("admins".into()))
+        );
+        assert!(service("client", &[]).require_admin(&tenant).is_err());
+    }
+
+    #[test]
+    fn tenants_without_an_admin_group_have_no_admins() {
+        assert_eq!(
+            user(&["admins"], &[]).require_admin(&tenant(None)),
+            Err(AuthError::NotConfigured("ADMIN_GROUP"))
+        );
+    }
+}
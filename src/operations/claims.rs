@@ -0,0 +1,354 @@
+use crate::errors::{auth::AuthError, server::ServerError};
+use jsonwebtokens::raw;
+use serde::{
+    de::{DeserializeOwned, Deserializer},
+    ser::{SerializeMap, Serializer},
+    Deserialize, Serialize,
+};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+const CUSTOM_PREFIX: &str = "custom:";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessTokenClaims {
+    pub sub: String,
+    pub iss: String,
+    pub client_id: String,
+    pub token_use: String,
+    // Absent from client_credentials tokens, which have no user behind them
+    pub username: Option<String>,
+    #[serde(rename = "cognito:groups", default)]
+    pub groups: Vec<String>,
+    #[serde(default, with = "space_separated")]
+    pub scope: Vec<String>,
+    pub auth_time: Option<i64>,
+    pub iat: i64,
+    pub exp: i64,
+    pub jti: Option<String>,
+    pub origin_jti: Option<String>,
+    #[serde(flatten)]
+    pub custom: CustomAttributes,
+}
+
+impl AccessTokenClaims {
+    // By the name the claim has in the token, for rules that name claims as strings
+    pub fn claim(&self, name: &str) -> Option<String> {
+        match name {
+            "sub" => Some(self.sub.clone()),
+            "iss" => Some(self.iss.clone()),
+            "client_id" => Some(self.client_id.clone()),
+            "token_use" => Some(self.token_use.clone()),
+            "username" => self.username.clone(),
+            "jti" => self.jti.clone(),
+            "origin_jti" => self.origin_jti.clone(),
+            _ => self.custom.get(name).map(|v| v.to_string()),
+        }
+    }
+
+    // Custom attributes compare by value when both sides are numbers or booleans, so a rule's
+    // "1.0" matches a token's 1; everything else compares as a string
+    pub fn claim_matches(&self, name: &str, expected: &str) -> bool {
+        let actual = match self.claim(name) {
+            Some(actual) => actual,
+            None => return false,
+        };
+
+        if self.custom.get(name) == Some(actual.as_str()) {
+            let custom = &self.custom;
+            if let (Some(actual), Ok(expected)) = (custom.integer(name), expected.parse::<i64>()) {
+                return actual == expected;
+            }
+            if let (Some(actual), Ok(expected)) = (custom.number(name), expected.parse::<f64>()) {
+                return actual == expected;
+            }
+            if let (Some(actual), Ok(expected)) = (custom.boolean(name), expected.parse::<bool>()) {
+                return actual == expected;
+            }
+        }
+
+        actual == expected
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub iss: String,
+    pub aud: String,
+    pub token_use: String,
+    #[serde(rename = "cognito:username")]
+    pub username: Option<String>,
+    #[serde(rename = "cognito:groups", default)]
+    pub groups: Vec<String>,
+    pub email: Option<String>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    pub email_verified: Option<bool>,
+    pub phone_number: Option<String>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    pub phone_number_verified: Option<bool>,
+    pub name: Option<String>,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub nonce: Option<String>,
+    pub at_hash: Option<String>,
+    pub auth_time: Option<i64>,
+    pub iat: i64,
+    pub exp: i64,
+    pub jti: Option<String>,
+    pub origin_jti: Option<String>,
+    #[serde(flatten)]
+    pub custom: CustomAttributes,
+}
+
+// The user pool's custom:* attributes, keyed without the prefix. Cognito puts them in tokens
+// as strings whatever their declared type.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CustomAttributes(BTreeMap<String, String>);
+
+impl CustomAttributes {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        let name = name.strip_prefix(CUSTOM_PREFIX).unwrap_or(name);
+        self.0.get(name).map(|v| v.as_str())
+    }
+
+    // The typed accessors give None for a value that doesn't parse, as for a missing one
+    pub fn integer(&self, name: &str) -> Option<i64> {
+        self.get(name)?.trim().parse().ok()
+    }
+
+    pub fn number(&self, name: &str) -> Option<f64> {
+        self.get(name)?.trim().parse().ok()
+    }
+
+    pub fn boolean(&self, name: &str) -> Option<bool> {
+        match self.get(name)?.trim() {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+// Flattened into the claims structs, so this sees every claim the struct didn't name
+impl<'de> Deserialize<'de> for CustomAttributes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let claims = BTreeMap::<String, Value>::deserialize(deserializer)?;
+
+        Ok(CustomAttributes(
+            claims
+                .into_iter()
+                .filter_map(|(name, value)| {
+                    let name = name.strip_prefix(CUSTOM_PREFIX)?.to_string();
+                    match value {
+                        Value::String(value) => Some((name, value)),
+                        Value::Null => None,
+                        value => Some((name, value.to_string())),
+                    }
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl Serialize for CustomAttributes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, value) in &self.0 {
+            map.serialize_entry(&format!("{}{}", CUSTOM_PREFIX, name), value)?;
+        }
+        map.end()
+    }
+}
+
+pub fn from_value<T: DeserializeOwned>(claims: Value) -> Result<T, ServerError> {
+    serde_json::from_value(claims)
+        .map_err(|e| AuthError::InvalidToken(format!("unexpected claims: {}", e)).into())
+}
+
+// Only for tokens that came straight from Cognito over TLS, never for ones a client presented
+pub fn decode_unverified<T: DeserializeOwned>(token: &str) -> Result<T, ServerError> {
+    from_value(raw::decode_only(token)?.claims)
+}
+
+// Federated users' email_verified arrives as "true"/"false"
+fn lenient_bool<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<bool>, D::Error> {
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::Bool(value)) => Some(value),
+        Some(Value::String(value)) => Some(value == "true"),
+        _ => None,
+    })
+}
+
+mod space_separated {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(scopes: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&scopes.join(" "))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<String>, D::Error> {
+        Ok(Option::<String>::deserialize(deserializer)?
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|s| s.to_string())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn access_token(extra: Value) -> AccessTokenClaims {
+        let mut claims = json!({
+            "sub": "sub-1",
+            "iss": "https://cognito-idp.eu-west-1.amazonaws.com/eu-west-1_acme",
+            "client_id": "acme-client",
+            "token_use": "access",
+            "iat": 1700000000,
+            "exp": 1700003600,
+        });
+        claims
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        from_value(claims).unwrap()
+    }
+
+    fn id_token(extra: Value) -> IdTokenClaims {
+        let mut claims = json!({
+            "sub": "sub-1",
+            "iss": "https://cognito-idp.eu-west-1.amazonaws.com/eu-west-1_acme",
+            "aud": "acme-client",
+            "token_use": "id",
+            "iat": 1700000000,
+            "exp": 1700003600,
+        });
+        claims
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        from_value(claims).unwrap()
+    }
+
+    // Only custom:* claims land in the flattened map, whatever JSON type they came as
+    #[test]
+    fn custom_attributes_are_collected_from_the_flatten() {
+        let claims = access_token(json!({
+            "custom:org_id": "acme",
+            "custom:seats": 12,
+            "custom:ratio": "0.5",
+            "custom:beta": "true",
+            "custom:trial": false,
+            "custom:gone": null,
+            "version": 2,
+        }));
+
+        assert_eq!(claims.custom.get("org_id"), Some("acme"));
+        assert_eq!(claims.custom.get("custom:org_id"), Some("acme"));
+        assert_eq!(claims.custom.get("seats"), Some("12"));
+        assert_eq!(claims.custom.get("gone"), None);
+        assert_eq!(claims.custom.get("version"), None);
+        assert_eq!(claims.claim("custom:org_id").as_deref(), Some("acme"));
+        assert_eq!(claims.claim("sub").as_deref(), Some("sub-1"));
+    }
+
+    #[test]
+    fn typed_accessors_parse_the_strings() {
+        let claims = access_token(json!({
+            "custom:seats": "12",
+            "custom:count": 7,
+            "custom:ratio": "0.5",
+            "custom:beta": "true",
+            "custom:trial": false,
+            "custom:name": "acme",
+        }));
+        let custom = &claims.custom;
+
+        assert_eq!(custom.integer("seats"), Some(12));
+        assert_eq!(custom.integer("custom:count"), Some(7));
+        assert_eq!(custom.integer("ratio"), None);
+        assert_eq!(custom.number("ratio"), Some(0.5));
+        assert_eq!(custom.number("seats"), Some(12.0));
+        assert_eq!(custom.boolean("beta"), Some(true));
+        assert_eq!(custom.boolean("trial"), Some(false));
+        assert_eq!(custom.boolean("name"), None);
+        assert_eq!(custom.integer("missing"), None);
+    }
+
+    #[test]
+    fn claims_match_by_value() {
+        let claims = access_token(json!({
+            "custom:seats": 12,
+            "custom:ratio": "0.50",
+            "custom:beta": true,
+            "custom:org_id": "acme",
+        }));
+
+        assert!(claims.claim_matches("seats", "12"));
+        assert!(claims.claim_matches("custom:seats", "12.0"));
+        assert!(!claims.claim_matches("seats", "13"));
+        assert!(claims.claim_matches("ratio", "0.5"));
+        assert!(claims.claim_matches("beta", "true"));
+        assert!(!claims.claim_matches("beta", "false"));
+        assert!(claims.claim_matches("org_id", "acme"));
+        assert!(!claims.claim_matches("org_id", "globex"));
+        assert!(claims.claim_matches("sub", "sub-1"));
+        assert!(!claims.claim_matches("missing", ""));
+    }
+
+    #[test]
+    fn custom_attributes_serialize_with_their_prefix() {
+        let claims = access_token(json!({"custom:org_id": "acme", "scope": "orgs tokens"}));
+        let value = serde_json::to_value(&claims).unwrap();
+        assert_eq!(value["custom:org_id"], "acme");
+        assert_eq!(value["scope"], "orgs tokens");
+        assert!(value.get("org_id").is_none());
+    }
+
+    #[test]
+    fn scope_is_space_separated() {
+        assert_eq!(
+            access_token(json!({"scope": "openid  orgs tokens"})).scope,
+            vec!["openid", "orgs", "tokens"]
+        );
+        assert!(access_token(json!({})).scope.is_empty());
+        assert!(access_token(json!({"scope": null})).scope.is_empty());
+    }
+
+    #[test]
+    fn verified_flags_take_bools_and_strings() {
+        assert_eq!(
+            id_token(json!({"email_verified": true})).email_verified,
+            Some(true)
+        );
+        assert_eq!(
+            id_token(json!({"email_verified": "true"})).email_verified,
+            Some(true)
+        );
+        assert_eq!(
+            id_token(json!({"email_verified": "false"})).email_verified,
+            Some(false)
+        );
+        assert_eq!(id_token(json!({})).email_verified, None);
+        assert_eq!(
+            id_token(json!({"phone_number_verified": 1})).phone_number_verified,
+            None
+        );
+    }
+
+    #[test]
+    fn groups_come_from_cognito_groups() {
+        let groups = json!({"cognito:groups": ["admins", "staff"], "groups": ["other"]});
+        assert_eq!(access_token(groups.clone()).groups, vec!["admins", "staff"]);
+        assert_eq!(id_token(groups).groups, vec!["admins", "staff"]);
+        assert!(access_token(json!({})).groups.is_empty());
+
+        let id = id_token(json!({"cognito:username": "alice"}));
+        assert_eq!(id.username.as_deref(), Some("alice"));
+    }
+}
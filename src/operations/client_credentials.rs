@@ -0,0 +1,278 @@
+use crate::errors::{oauth::OAuthError, server::ServerError};
+use crate::operations::oauth::HostedUi;
+use crate::operations::tenant::Tenant;
+use actix_web::{http::header, HttpRequest};
+use base64::{engine::general_purpose, Engine};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    env,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Handing out a token that expires mid-request only moves the failure downstream
+const DEFAULT_REFRESH_MARGIN: u64 = 60;
+
+#[derive(Debug, Deserialize)]
+pub struct ClientCredentialsRequest {
+    pub grant_type: String,
+    pub scope: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceToken {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Clone)]
+struct CachedToken {
+    access_token: String,
+    token_type: String,
+    expires_at: Instant,
+}
+
+// Service tokens per client and scope, so a chatty service doesn't hit Cognito's token
+// endpoint (and its quota) on every call
+#[derive(Debug)]
+pub struct ServiceTokenCache {
+    tokens: Mutex<HashMap<String, CachedToken>>,
+    refresh_margin: Duration,
+}
+
+impl ServiceTokenCache {
+    pub fn from_env() -> ServiceTokenCache {
+        ServiceTokenCache {
+            tokens: Mutex::new(HashMap::new()),
+            refresh_margin: Duration::from_secs(
+                env::var("M2M_TOKEN_REFRESH_MARGIN")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(DEFAULT_REFRESH_MARGIN),
+            ),
+        }
+    }
+
+    pub async fn token(
+        &self,
+        client: &Client,
+        tenant: &Tenant,
+        client_id: &str,
+        client_secret: &str,
+        scope: Option<&str>,
+    ) -> Result<ServiceToken, ServerError> {
+        // Only the tenant's M2M clients may use the client_credentials grant
+        if !tenant.verifier.is_service_client(client_id) {
+            return Err(OAuthError::InvalidClient.into());
+        }
+
+        let scope = scope.map(normalize_scope).filter(|scope| !scope.is_empty());
+        let key = cache_key(&tenant.id, client_id, client_secret, scope.as_deref());
+
+        if let Some(token) = self.cached(&key) {
+            return Ok(token);
+        }
+
+        let res = HostedUi::for_tenant(tenant)?
+            .client_credentials(client, client_id, client_secret, scope.as_deref())
+            .await?;
+
+        let expires_in = Duration::from_secs(u64::try_from(res.expires_in).unwrap_or_default());
+        let cached = CachedToken {
+            access_token: res.access_token,
+            token_type: res.token_type,
+            expires_at: Instant::now() + expires_in,
+        };
+
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, token| token.expires_at > Instant::now());
+        tokens.insert(key, cached.clone());
+
+        Ok(self.to_service_token(&cached))
+    }
+
+    fn cached(&self, key: &str) -> Option<ServiceToken> {
+        let tokens = self.tokens.lock().unwrap();
+        let token = tokens.get(key)?;
+
+        match token.expires_at > Instant::now() + self.refresh_margin {
+            true => Some(self.to_service_token(token)),
+            false => None,
+        }
+    }
+
+    fn to_service_token(&self, token: &CachedToken) -> ServiceToken {
+        ServiceToken {
+            access_token: token.access_token.clone(),
+            token_type: token.token_type.clone(),
+            expires_in: token
+                .expires_at
+                .saturating_duration_since(Instant::now())
+                .as_secs(),
+        }
+    }
+}
+
+// HTTP Basic first, as RFC 6749 prefers, then client_id and client_secret in the body
+pub fn client_authentication(
+    req: &HttpRequest,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+) -> Option<(String, String)> {
+    let basic = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| v.len() > 6 && v[..6].eq_ignore_ascii_case("basic "));
+
+    // A malformed Basic header fails authentication rather than falling back to the body
+    if let Some(basic) = basic {
+        return general_purpose::STANDARD
+            .decode(basic[6..].trim())
+            .ok()
+            .and_then(|v| String::from_utf8(v).ok())
+            .and_then(|v| {
+                v.split_once(':')
+                    .map(|(id, secret)| (id.to_string(), secret.to_string()))
+            });
+    }
+
+    match (client_id, client_secret) {
+        (Some(id), Some(secret)) => Some((id.into(), secret.into())),
+        _ => None,
+    }
+}
+
+fn normalize_scope(scope: &str) -> String {
+    let mut scopes: Vec<&str> = scope.split_whitespace().collect();
+    scopes.sort_unstable();
+    scopes.dedup();
+    scopes.join(" ")
+}
+
+// The secret is part of the key, so knowing a client ID is never enough to be handed its token
+fn cache_key(tenant: &str, client_id: &str, client_secret: &str, scope: Option<&str>) -> String {
+    let mut hash = Sha256::new();
+    for part in [tenant, client_id, client_secret, scope.unwrap_or_default()] {
+        hash.update(part.as_bytes());
+        hash.update([0u8]);
+    }
+    general_purpose::STANDARD.encode(hash.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn basic(credentials: &str) -> HttpRequest {
+        TestRequest::post()
+            .insert_header((
+                "authorization",
+                format!("Basic {}", general_purpose::STANDARD.encode(credentials)),
+            ))
+            .to_http_request()
+    }
+
+    fn cache(refresh_margin: u64) -> ServiceTokenCache {
+        ServiceTokenCache {
+            tokens: Mutex::new(HashMap::new()),
+            refresh_margin: Duration::from_secs(refresh_margin),
+        }
+    }
+
+    fn store(cache: &ServiceTokenCache, key: &str, expires_in: u64) {
+        cache.tokens.lock().unwrap().insert(
+            key.into(),
+            CachedToken {
+                access_token: "token".into(),
+                token_type: "Bearer".into(),
+                expires_at: Instant::now() + Duration::from_secs(expires_in),
+            },
+        );
+    }
+
+    #[test]
+    fn basic_authentication_wins_over_the_body() {
+        assert_eq!(
+            client_authentication(&basic("id:s3cr:et"), Some("other"), Some("other")),
+            Some(("id".into(), "s3cr:et".into()))
+        );
+    }
+
+    #[test]
+    fn body_credentials_need_both_halves() {
+        let req = TestRequest::post().to_http_request();
+        assert_eq!(
+            client_authentication(&req, Some("id"), Some("secret")),
+            Some(("id".into(), "secret".into()))
+        );
+        assert_eq!(client_authentication(&req, Some("id"), None), None);
+        assert_eq!(client_authentication(&req, None, None), None);
+    }
+
+    #[test]
+    fn malformed_basic_headers_fail() {
+        let req = TestRequest::post()
+            .insert_header(("authorization", "Basic not-base64!"))
+            .to_http_request();
+        assert_eq!(
+            client_authentication(&req, Some("id"), Some("secret")),
+            None
+        );
+        assert_eq!(
+            client_authentication(&basic("no-colon"), Some("id"), Some("secret")),
+            None
+        );
+
+        // Other schemes aren't client authentication at all
+        let req = TestRequest::post()
+            .insert_header(("authorization", "Bearer token"))
+            .to_http_request();
+        assert_eq!(
+            client_authentication(&req, Some("id"), Some("secret")),
+            Some(("id".into(), "secret".into()))
+        );
+    }
+
+    #[test]
+    fn normalizes_scopes() {
+        assert_eq!(normalize_scope("b a  b\ta"), "a b");
+        assert_eq!(normalize_scope("  "), "");
+    }
+
+    #[test]
+    fn cache_keys_cover_every_part() {
+        let key = cache_key("acme", "id", "secret", Some("a b"));
+        assert_eq!(key, cache_key("acme", "id", "secret", Some("a b")));
+        assert_ne!(key, cache_key("other", "id", "secret", Some("a b")));
+        assert_ne!(key, cache_key("acme", "id", "wrong", Some("a b")));
+        assert_ne!(key, cache_key("acme", "id", "secret", None));
+        // The separators keep parts from running into each other
+        assert_ne!(
+            cache_key("acme", "ids", "ecret", None),
+            cache_key("acme", "id", "secret", None)
+        );
+    }
+
+    #[test]
+    fn tokens_are_refreshed_within_the_margin() {
+        let cache = cache(60);
+        store(&cache, "fresh", 3600);
+        store(&cache, "expiring", 30);
+        store(&cache, "expired", 0);
+
+        let token = cache.cached("fresh").unwrap();
+        assert_eq!(token.access_token, "token");
+        assert!(token.expires_in > 3500 && token.expires_in <= 3600);
+        assert!(cache.cached("expiring").is_none());
+        assert!(cache.cached("expired").is_none());
+        assert!(cache.cached("missing").is_none());
+    }
+}
@@ -0,0 +1,185 @@
+use crate::errors::{auth::AuthError, server::ServerError};
+use crate::operations::claims::{self, AccessTokenClaims, IdTokenClaims};
+use crate::operations::csrf::constant_time_eq;
+use base64::{engine::general_purpose, Engine};
+use jsonwebtokens_cognito::{Error as JwksError, KeySet};
+use sha2::{Digest, Sha256};
+use std::env;
+
+// One KeySet per user pool for the whole process, so Cognito's JWKS is fetched once and then
+// served from its cache instead of on every request
+#[derive(Debug, Clone)]
+pub struct CognitoVerifier {
+    keyset: KeySet,
+    client_ids: Vec<String>,
+    service_client_ids: Vec<String>,
+    configured: bool,
+}
+
+impl CognitoVerifier {
+    // APP_CLIENT_ID may list several comma separated app clients whose user tokens are
+    // accepted; M2M_CLIENT_IDS those whose client_credentials tokens are. Without
+    // COGNITO_REGION and USER_POOL_ID the process still starts, and tokens are refused.
+    pub fn from_env() -> Result<CognitoVerifier, ServerError> {
+        CognitoVerifier::new(
+            &env::var("COGNITO_REGION").unwrap_or_default(),
+            &env::var("USER_POOL_ID").unwrap_or_default(),
+            list_var("APP_CLIENT_ID"),
+            list_var("M2M_CLIENT_IDS"),
+        )
+    }
+
+    pub fn new(
+        region: &str,
+        user_pool_id: &str,
+        client_ids: Vec<String>,
+        service_client_ids: Vec<String>,
+    ) -> Result<CognitoVerifier, ServerError> {
+        Ok(CognitoVerifier {
+            keyset: KeySet::new(region, user_pool_id)?,
+            client_ids,
+            service_client_ids,
+            configured: !region.is_empty() && !user_pool_id.is_empty(),
+        })
+    }
+
+    fn check_configured(&self) -> Result<(), AuthError> {
+        match self.configured {
+            true => Ok(()),
+            false => Err(AuthError::NotConfigured("USER_POOL_ID")),
+        }
+    }
+
+    pub fn is_service_client(&self, client_id: &str) -> bool {
+        self.service_client_ids.iter().any(|id| id == client_id)
+    }
+
+    pub async fn verify_access_token(&self, token: &str) -> Result<AccessTokenClaims, ServerError> {
+        self.check_configured()?;
+        let client_ids: Vec<&str> = self
+            .client_ids
+            .iter()
+            .chain(&self.service_client_ids)
+            .map(|id| id.as_str())
+            .collect();
+        let verifier = self.keyset.new_access_token_verifier(&client_ids).build()?;
+
+        let claims: AccessTokenClaims = claims::from_value(
+            self.keyset
+                .verify(token, &verifier)
+                .await
+                .map_err(to_server_error)?,
+        )?;
+
+        // Only client_credentials tokens come without a username, and only M2M clients may
+        // present one; an M2M client can't pass a user token off as a service token or back
+        let allowed = match claims.username {
+            Some(_) => &self.client_ids,
+            None => &self.service_client_ids,
+        };
+        if !allowed.contains(&claims.client_id) {
+            return Err(AuthError::InvalidToken(format!(
+                "token from unexpected client {}",
+                claims.client_id
+            ))
+            .into());
+        }
+
+        Ok(claims)
+    }
+
+    // ID tokens are only ever issued to the user-facing app clients. The nonce is checked when
+    // the caller knows which one it sent. When the access token is at hand, at_hash ties the
+    // two together; without one the access token must at least verify and name the same user.
+    pub async fn verify_id_token(
+        &self,
+        token: &str,
+        nonce: Option<&str>,
+        access_token: Option<&str>,
+    ) -> Result<IdTokenClaims, ServerError> {
+        self.check_configured()?;
+        let client_ids: Vec<&str> = self.client_ids.iter().map(|id| id.as_str()).collect();
+        let verifier = self.keyset.new_id_token_verifier(&client_ids).build()?;
+
+        let claims: IdTokenClaims = claims::from_value(
+            self.keyset
+                .verify(token, &verifier)
+                .await
+                .map_err(to_server_error)?,
+        )?;
+
+        if let Some(nonce) = nonce {
+            let matches = claims
+                .nonce
+                .as_deref()
+                .map(|claimed| constant_time_eq(claimed.as_bytes(), nonce.as_bytes()))
+                .unwrap_or(false);
+            if !matches {
+                return Err(AuthError::InvalidToken("nonce does not match".into()).into());
+            }
+        }
+
+        match (&claims.at_hash, access_token) {
+            (Some(at_hash), Some(access_token)) => {
+                if !constant_time_eq(
+                    at_hash.as_bytes(),
+                    access_token_hash(access_token).as_bytes(),
+                ) {
+                    return Err(AuthError::InvalidToken(
+                        "at_hash does not match the access token".into(),
+                    )
+                    .into());
+                }
+            }
+            (None, Some(access_token)) => {
+                if self.verify_access_token(access_token).await?.sub != claims.sub {
+                    return Err(AuthError::InvalidToken(
+                        "ID token is for another user than the access token".into(),
+                    )
+                    .into());
+                }
+            }
+            (_, None) => {}
+        }
+
+        Ok(claims)
+    }
+}
+
+// The left half of the access token's SHA-256, as OpenID Connect defines at_hash for RS256
+fn access_token_hash(access_token: &str) -> String {
+    let digest = Sha256::digest(access_token.as_bytes());
+    general_purpose::URL_SAFE_NO_PAD.encode(&digest[..digest.len() / 2])
+}
+
+fn list_var(name: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+        .collect()
+}
+
+// A bad token is the caller's problem; only failing to reach Cognito is ours
+fn to_server_error(e: JwksError) -> ServerError {
+    match e {
+        JwksError::TokenExpiredAt(..) => AuthError::TokenExpired.into(),
+        JwksError::NetworkError(..) | JwksError::CacheMiss(..) => e.into(),
+        e => AuthError::InvalidToken(e.to_string()).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn at_hash_is_the_left_half_of_the_sha256() {
+        // The example from OpenID Connect Core appendix A.3
+        assert_eq!(
+            access_token_hash("jHkWEdUXMU1BwAsC4vtUsZwnNvTIxEl0z9K3vx5KF0Y"),
+            "77QmUPtjPfzWtF2AnpK9RQ"
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use crate::errors::cookie::CookieCryptoError;
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose, Engine};
+use std::{
+    collections::HashMap,
+    env,
+    fmt::{Debug, Formatter},
+};
+
+const NONCE_LEN: usize = 12;
+
+// Seals cookie values with AES-256-GCM. Sealed values look like "<kid>.<base64url(nonce | ciphertext)>"
+// so any of the configured keys can still open them after the primary key has been rotated.
+pub struct CookieSealer {
+    keys: HashMap<String, Aes256Gcm>,
+    primary: String,
+}
+
+impl Debug for CookieSealer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CookieSealer")
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .field("primary", &self.primary)
+            .finish()
+    }
+}
+
+impl CookieSealer {
+    pub fn new() -> CookieSealer {
+        CookieSealer {
+            keys: HashMap::new(),
+            primary: "".into(),
+        }
+    }
+
+    // COOKIE_ENCRYPTION_KEYS is "kid:base64key,kid:base64key"; new cookies are sealed with
+    // COOKIE_ENCRYPTION_PRIMARY_KEY, or the first key listed
+    pub fn from_env() -> Result<Option<CookieSealer>, CookieCryptoError> {
+        let keys = match env::var("COOKIE_ENCRYPTION_KEYS") {
+            Ok(keys) if !keys.trim().is_empty() => keys,
+            _ => return Ok(None),
+        };
+
+        let mut sealer = CookieSealer::new();
+
+        for entry in keys.split(',').map(|entry| entry.trim()) {
+            let (kid, key) = entry.split_once(':').ok_or(CookieCryptoError::InvalidKey)?;
+            let key = general_purpose::STANDARD
+                .decode(key)
+                .map_err(|_| CookieCryptoError::InvalidKey)?;
+            sealer.insert(kid, &key)?;
+        }
+
+        if let Ok(primary) = env::var("COOKIE_ENCRYPTION_PRIMARY_KEY") {
+            if !sealer.keys.contains_key(&primary) {
+                return Err(CookieCryptoError::UnknownKey);
+            }
+            sealer.primary = primary;
+        }
+
+        Ok(Some(sealer))
+    }
+
+    pub fn insert(&mut self, kid: &str, key: &[u8]) -> Result<(), CookieCryptoError> {
+        // The kid is the first segment of every sealed value
+        if kid.is_empty() || kid.contains('.') {
+            return Err(CookieCryptoError::InvalidKey);
+        }
+
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| CookieCryptoError::InvalidKey)?;
+        if self.keys.is_empty() {
+            self.primary = kid.into();
+        }
+        self.keys.insert(kid.into(), cipher);
+
+        Ok(())
+    }
+
+    // The cookie name is bound in as associated data, so a sealed value can't be replayed
+    // under another cookie's name
+    pub fn seal(&self, name: &str, value: &str) -> String {
+        let cipher = &self.keys[&self.primary];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: value.as_bytes(),
+                    aad: name.as_bytes(),
+                },
+            )
+            .expect("AES-GCM encryption of an in-memory buffer");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+
+        format!(
+            "{}.{}",
+            self.primary,
+            general_purpose::URL_SAFE_NO_PAD.encode(sealed)
+        )
+    }
+
+    pub fn open(&self, name: &str, sealed: &str) -> Result<String, CookieCryptoError> {
+        let (kid, data) = sealed.split_once('.').ok_or(CookieCryptoError::Malformed)?;
+        let cipher = self.keys.get(kid).ok_or(CookieCryptoError::UnknownKey)?;
+        let data = general_purpose::URL_SAFE_NO_PAD
+            .decode(data)
+            .map_err(|_| CookieCryptoError::Malformed)?;
+
+        if data.len() <= NONCE_LEN {
+            return Err(CookieCryptoError::Malformed);
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: name.as_bytes(),
+                },
+            )
+            .map_err(|_| CookieCryptoError::Undecryptable)?;
+
+        String::from_utf8(plaintext).map_err(|_| CookieCryptoError::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sealer(kids: &[(&str, u8)]) -> CookieSealer {
+        let mut sealer = CookieSealer::new();
+        for (kid, byte) in kids {
+            sealer.insert(kid, &[*byte; 32]).unwrap();
+        }
+        sealer
+    }
+
+    #[test]
+    fn opens_what_it_seals() {
+        let sealer = sealer(&[("k1", 1)]);
+        let sealed = sealer.seal("id_token", "value");
+
+        assert!(sealed.starts_with("k1."));
+        assert!(!sealed.contains("value"));
+        assert_eq!(sealer.open("id_token", &sealed).unwrap(), "value");
+    }
+
+    #[test]
+    fn seals_are_bound_to_the_cookie_name() {
+        let sealer = sealer(&[("k1", 1)]);
+        let sealed = sealer.seal("id_token", "value");
+
+        assert!(matches!(
+            sealer.open("access_token", &sealed),
+            Err(CookieCryptoError::Undecryptable)
+        ));
+    }
+
+    #[test]
+    fn old_keys_still_open_after_rotation() {
+        let old = sealer(&[("k1", 1)]);
+        let sealed = old.seal("id_token", "value");
+
+        let mut rotated = sealer(&[("k2", 2), ("k1", 1)]);
+        assert_eq!(rotated.primary, "k2");
+        assert_eq!(rotated.open("id_token", &sealed).unwrap(), "value");
+        assert!(rotated.seal("id_token", "value").starts_with("k2."));
+
+        rotated.keys.remove("k1");
+        assert!(matches!(
+            rotated.open("id_token", &sealed),
+            Err(CookieCryptoError::UnknownKey)
+        ));
+    }
+
+    #[test]
+    fn rejects_tampered_and_malformed_values() {
+        let sealer = sealer(&[("k1", 1)]);
+        let sealed = sealer.seal("id_token", "value");
+        let mut tampered = sealed.clone().into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+
+        assert!(sealer
+            .open("id_token", &String::from_utf8(tampered).unwrap())
+            .is_err());
+        assert!(matches!(
+            sealer.open("id_token", "no-kid"),
+            Err(CookieCryptoError::Malformed)
+        ));
+        assert!(matches!(
+            sealer.open("id_token", "k1.AAAA"),
+            Err(CookieCryptoError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_keys() {
+        let mut sealer = sealer(&[]);
+        assert!(sealer.insert("k.1", &[0; 32]).is_err());
+        assert!(sealer.insert("", &[0; 32]).is_err());
+        assert!(sealer.insert("k1", &[0; 16]).is_err());
+    }
+}
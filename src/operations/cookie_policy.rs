@@ -0,0 +1,586 @@
+use crate::errors::cookie::CookieCryptoError;
+use crate::operations::auth::HmacSha256;
+use crate::operations::cookie_crypto::CookieSealer;
+use crate::operations::csrf::constant_time_eq;
+use actix_web::{
+    cookie::{Cookie, CookieBuilder, SameSite},
+    HttpRequest,
+};
+use base64::{engine::general_purpose, Engine};
+use hmac::Mac;
+use reqwest::header::HeaderValue;
+use std::{env, sync::Arc};
+
+// Cookies that carry Cognito tokens, before any name prefix is applied
+pub const TOKEN_COOKIES: [&str; 3] = ["access_token", "id_token", "refresh_token"];
+
+// Leaves room for the name and attributes inside the 4096 bytes browsers allow per cookie
+const DEFAULT_CHUNK_SIZE: usize = 3800;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CookieDomain {
+    Explicit(String),
+    FromHost,
+    HostOnly,
+}
+
+impl CookieDomain {
+    pub fn parse(domain: Option<&str>) -> CookieDomain {
+        match domain {
+            None | Some("") | Some("host-only") => CookieDomain::HostOnly,
+            Some("from-host") => CookieDomain::FromHost,
+            Some(domain) => CookieDomain::Explicit(domain.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CookieSecure {
+    Always,
+    Never,
+    Auto,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CookiePrefix {
+    None,
+    Host,
+    Secure,
+}
+
+#[derive(Debug, Clone)]
+pub struct CookiePolicy {
+    pub domain: CookieDomain,
+    pub secure: CookieSecure,
+    pub same_site: SameSite,
+    pub path: String,
+    pub prefix: CookiePrefix,
+    pub trust_forwarded_proto: bool,
+    pub sealer: Option<Arc<CookieSealer>>,
+    // The first key signs; the rest still verify, so keys can be rotated
+    pub signing_keys: Vec<Vec<u8>>,
+    pub chunk_size: usize,
+}
+
+impl CookiePolicy {
+    pub fn from_env() -> CookiePolicy {
+        let domain = CookieDomain::parse(env::var("COOKIE_DOMAIN").ok().as_deref());
+
+        let secure = match env::var("COOKIE_SECURE").ok().as_deref() {
+            Some("always") | Some("true") => CookieSecure::Always,
+            Some("never") | Some("false") => CookieSecure::Never,
+            _ => CookieSecure::Auto,
+        };
+
+        let same_site = match env::var("COOKIE_SAME_SITE").ok().as_deref() {
+            Some("strict") => SameSite::Strict,
+            Some("none") => SameSite::None,
+            _ => SameSite::Lax,
+        };
+
+        let prefix = match env::var("COOKIE_PREFIX").ok().as_deref() {
+            Some("host") | Some("__Host-") => CookiePrefix::Host,
+            Some("secure") | Some("__Secure-") => CookiePrefix::Secure,
+            _ => CookiePrefix::None,
+        };
+
+        let mut policy = CookiePolicy::new(
+            domain,
+            secure,
+            same_site,
+            env::var("COOKIE_PATH").unwrap_or_else(|_| "/".into()),
+            prefix,
+            env::var("TRUST_FORWARDED_PROTO")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(false),
+        );
+
+        policy.chunk_size = env::var("COOKIE_CHUNK_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|size| *size > 0)
+            .unwrap_or(DEFAULT_CHUNK_SIZE);
+        policy.sealer = CookieSealer::from_env()
+            .expect("COOKIE_ENCRYPTION_KEYS is misconfigured")
+            .map(Arc::new);
+        policy.signing_keys = env::var("COOKIE_SIGNING_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|key| key.trim())
+            .filter(|key| !key.is_empty())
+            .map(|key| key.as_bytes().to_vec())
+            .collect();
+
+        policy
+    }
+
+    // Browsers reject prefixed and SameSite=None cookies that break these rules, so the
+    // policy is corrected here rather than producing cookies that silently never get set
+    pub fn new(
+        mut domain: CookieDomain,
+        mut secure: CookieSecure,
+        same_site: SameSite,
+        mut path: String,
+        prefix: CookiePrefix,
+        trust_forwarded_proto: bool,
+    ) -> CookiePolicy {
+        if prefix != CookiePrefix::None || same_site == SameSite::None {
+            secure = CookieSecure::Always;
+        }
+
+        if prefix == CookiePrefix::Host {
+            domain = CookieDomain::HostOnly;
+            path = "/".into();
+        }
+
+        CookiePolicy {
+            domain,
+            secure,
+            same_site,
+            path,
+            prefix,
+            trust_forwarded_proto,
+            sealer: None,
+            signing_keys: vec![],
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    // The same policy with a tenant's own cookie domain, which a __Host- prefix still overrides
+    pub fn with_domain(&self, domain: Option<&CookieDomain>) -> CookiePolicy {
+        let mut policy = self.clone();
+        if let Some(domain) = domain {
+            if self.prefix != CookiePrefix::Host {
+                policy.domain = domain.clone();
+            }
+        }
+        policy
+    }
+
+    pub fn name(&self, name: &str) -> String {
+        match self.prefix {
+            CookiePrefix::None => name.into(),
+            CookiePrefix::Host => format!("__Host-{}", name),
+            CookiePrefix::Secure => format!("__Secure-{}", name),
+        }
+    }
+
+    pub fn chunk_name(&self, name: &str, index: usize) -> String {
+        self.name(&format!("{}.{}", name, index))
+    }
+
+    // A value too large for one cookie is spread over "<name>.0", "<name>.1", ...
+    pub fn split(&self, value: &str) -> Option<Vec<String>> {
+        if value.len() <= self.chunk_size {
+            return None;
+        }
+
+        // Chunks end on char boundaries, so one may come up a few bytes short of the limit
+        let mut chunks = vec![];
+        let mut rest = value;
+        while !rest.is_empty() {
+            let mut end = rest.len().min(self.chunk_size);
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            if end == 0 {
+                end = rest.chars().next().map(char::len_utf8).unwrap_or_default();
+            }
+
+            let (chunk, tail) = rest.split_at(end);
+            chunks.push(chunk.to_string());
+            rest = tail;
+        }
+
+        Some(chunks)
+    }
+
+    // Reads a cookie written whole or in chunks, before it is opened
+    pub fn read(&self, req: &HttpRequest, name: &str) -> Option<String> {
+        if let Some(cookie) = req.cookie(&self.name(name)) {
+            return Some(cookie.value().into());
+        }
+
+        let mut value = String::new();
+        let mut index = 0;
+        while let Some(chunk) = req.cookie(&self.chunk_name(name, index)) {
+            value.push_str(chunk.value());
+            index += 1;
+        }
+
+        if index == 0 {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    pub fn has_cookie(&self, req: &HttpRequest, name: &str) -> bool {
+        req.cookie(&self.name(name)).is_some() || req.cookie(&self.chunk_name(name, 0)).is_some()
+    }
+
+    // The connection scheme, or the one reported by a trusted TLS-terminating proxy
+    pub fn is_secure_request(&self, req: &HttpRequest) -> bool {
+        if self.trust_forwarded_proto {
+            let forwarded = req
+                .headers()
+                .get("x-forwarded-proto")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.split(',').next())
+                .map(|v| v.trim().eq_ignore_ascii_case("https"));
+
+            if let Some(forwarded) = forwarded {
+                return forwarded;
+            }
+        }
+
+        req.app_config().secure()
+    }
+
+    pub fn secure(&self, secure_request: bool) -> bool {
+        match self.secure {
+            CookieSecure::Always => true,
+            CookieSecure::Never => false,
+            CookieSecure::Auto => secure_request,
+        }
+    }
+
+    pub fn domain(&self, host: Option<&HeaderValue>) -> Option<String> {
+        match &self.domain {
+            CookieDomain::Explicit(domain) => Some(domain.clone()),
+            CookieDomain::HostOnly => None,
+            CookieDomain::FromHost => host
+                .and_then(|host| host.to_str().ok())
+                .map(strip_port)
+                .filter(|host| !host.is_empty()),
+        }
+    }
+
+    pub fn seal(&self, name: &str, value: &str) -> String {
+        match &self.sealer {
+            Some(sealer) => sealer.seal(name, value),
+            None => value.into(),
+        }
+    }
+
+    pub fn open(&self, name: &str, value: &str) -> Result<String, CookieCryptoError> {
+        match &self.sealer {
+            Some(sealer) => sealer.open(name, value),
+            None => Ok(value.into()),
+        }
+    }
+
+    // Signed values are "<value>.<base64url(hmac)>", with the cookie name bound into the HMAC.
+    // Unlike sealing this doesn't depend on COOKIE_ENCRYPTION_KEYS being set.
+    pub fn sign(&self, name: &str, value: &str) -> Result<String, CookieCryptoError> {
+        let key = self
+            .signing_keys
+            .first()
+            .ok_or(CookieCryptoError::NoSigningKey)?;
+        Ok(format!("{}.{}", value, signature(key, name, value)))
+    }
+
+    pub fn verify(&self, name: &str, signed: &str) -> Result<String, CookieCryptoError> {
+        let (value, tag) = signed
+            .rsplit_once('.')
+            .ok_or(CookieCryptoError::Malformed)?;
+
+        match self
+            .signing_keys
+            .iter()
+            .any(|key| constant_time_eq(signature(key, name, value).as_bytes(), tag.as_bytes()))
+        {
+            true => Ok(value.into()),
+            false => Err(CookieCryptoError::BadSignature),
+        }
+    }
+
+    pub fn build(
+        &self,
+        name: &str,
+        value: String,
+        host: Option<&HeaderValue>,
+        secure_request: bool,
+    ) -> CookieBuilder<'static> {
+        let mut builder = Cookie::build(self.name(name), value)
+            .same_site(self.same_site)
+            .path(self.path.clone())
+            .secure(self.secure(secure_request))
+            .http_only(true);
+
+        if let Some(domain) = self.domain(host) {
+            builder = builder.domain(domain);
+        }
+
+        builder
+    }
+
+    pub fn removal(
+        &self,
+        name: &str,
+        host: Option<&HeaderValue>,
+        secure_request: bool,
+    ) -> Cookie<'static> {
+        let mut cookie = self.build(name, "".into(), host, secure_request).finish();
+        cookie.make_removal();
+        cookie
+    }
+
+    // Removes a cookie the request carries, whether it was written whole or in chunks
+    pub fn removals(&self, req: &HttpRequest, name: &str) -> Vec<Cookie<'static>> {
+        let host = req.headers().get("host");
+        let secure_request = self.is_secure_request(req);
+
+        let mut cookies = vec![];
+        if req.cookie(&self.name(name)).is_some() {
+            cookies.push(self.removal(name, host, secure_request));
+        }
+        let mut index = 0;
+        while req.cookie(&self.chunk_name(name, index)).is_some() {
+            cookies.push(self.removal(&format!("{}.{}", name, index), host, secure_request));
+            index += 1;
+        }
+
+        cookies
+    }
+}
+
+fn signature(key: &[u8], name: &str, value: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(name.as_bytes());
+    mac.update(&[0]);
+    mac.update(value.as_bytes());
+    general_purpose::URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+pub fn strip_port(host: &str) -> String {
+    // Bracketed IPv6 literals carry colons of their own
+    if let Some(end) = host.find(']') {
+        return host[..=end].into();
+    }
+
+    host.split(':').next().unwrap_or_default().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn policy(domain: CookieDomain, prefix: CookiePrefix) -> CookiePolicy {
+        CookiePolicy::new(
+            domain,
+            CookieSecure::Auto,
+            SameSite::Lax,
+            "/auth".into(),
+            prefix,
+            false,
+        )
+    }
+
+    #[test]
+    fn parses_domains() {
+        assert_eq!(CookieDomain::parse(None), CookieDomain::HostOnly);
+        assert_eq!(CookieDomain::parse(Some("")), CookieDomain::HostOnly);
+        assert_eq!(
+            CookieDomain::parse(Some("from-host")),
+            CookieDomain::FromHost
+        );
+        assert_eq!(
+            CookieDomain::parse(Some(".example.com")),
+            CookieDomain::Explicit(".example.com".into())
+        );
+    }
+
+    #[test]
+    fn prefixes_rename_cookies() {
+        let none = policy(CookieDomain::HostOnly, CookiePrefix::None);
+        let host = policy(CookieDomain::HostOnly, CookiePrefix::Host);
+        let secure = policy(CookieDomain::HostOnly, CookiePrefix::Secure);
+
+        assert_eq!(none.name("id_token"), "id_token");
+        assert_eq!(host.name("id_token"), "__Host-id_token");
+        assert_eq!(secure.chunk_name("id_token", 1), "__Secure-id_token.1");
+    }
+
+    #[test]
+    fn host_prefix_forces_its_attributes() {
+        let policy = policy(
+            CookieDomain::Explicit(".example.com".into()),
+            CookiePrefix::Host,
+        );
+        let cookie = policy
+            .build("access_token", "v".into(), None, false)
+            .finish();
+
+        assert_eq!(cookie.name(), "__Host-access_token");
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.path(), Some("/"));
+        assert_eq!(cookie.domain(), None);
+        assert_eq!(cookie.http_only(), Some(true));
+    }
+
+    #[test]
+    fn secure_prefix_keeps_domain_and_path() {
+        let policy = policy(
+            CookieDomain::Explicit(".example.com".into()),
+            CookiePrefix::Secure,
+        );
+        let cookie = policy
+            .build("access_token", "v".into(), None, false)
+            .finish();
+
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.path(), Some("/auth"));
+        assert_eq!(cookie.domain(), Some(".example.com"));
+    }
+
+    #[test]
+    fn same_site_none_forces_secure() {
+        let policy = CookiePolicy::new(
+            CookieDomain::HostOnly,
+            CookieSecure::Never,
+            SameSite::None,
+            "/".into(),
+            CookiePrefix::None,
+            false,
+        );
+        assert!(policy.secure(false));
+    }
+
+    #[test]
+    fn auto_follows_the_request() {
+        let policy = policy(CookieDomain::HostOnly, CookiePrefix::None);
+        assert!(policy.secure(true));
+        assert!(!policy.secure(false));
+    }
+
+    #[test]
+    fn domain_from_host_drops_the_port() {
+        let policy = policy(CookieDomain::FromHost, CookiePrefix::None);
+        let host = HeaderValue::from_static("app.example.com:8080");
+        assert_eq!(policy.domain(Some(&host)), Some("app.example.com".into()));
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+    }
+
+    #[test]
+    fn tenant_domain_does_not_override_host_prefix() {
+        let tenant = CookieDomain::Explicit("tenant.test".into());
+        let host = policy(CookieDomain::HostOnly, CookiePrefix::Host);
+        let plain = policy(CookieDomain::HostOnly, CookiePrefix::None);
+
+        assert_eq!(
+            host.with_domain(Some(&tenant)).domain,
+            CookieDomain::HostOnly
+        );
+        assert_eq!(plain.with_domain(Some(&tenant)).domain, tenant);
+    }
+
+    #[test]
+    fn small_values_are_not_split() {
+        let policy = policy(CookieDomain::HostOnly, CookiePrefix::None);
+        assert_eq!(policy.split("short"), None);
+    }
+
+    #[test]
+    fn chunks_reassemble_into_the_value() {
+        let mut policy = policy(CookieDomain::HostOnly, CookiePrefix::Secure);
+        policy.chunk_size = 4;
+        let chunks = policy.split("abcdefghij").unwrap();
+        assert_eq!(chunks, vec!["abcd", "efgh", "ij"]);
+
+        let req = chunks
+            .iter()
+            .enumerate()
+            .fold(TestRequest::default(), |req, (index, chunk)| {
+                req.cookie(Cookie::new(
+                    policy.chunk_name("id_token", index),
+                    chunk.clone(),
+                ))
+            })
+            .to_http_request();
+
+        assert!(policy.has_cookie(&req, "id_token"));
+        assert_eq!(policy.read(&req, "id_token").unwrap(), "abcdefghij");
+        assert_eq!(policy.removals(&req, "id_token").len(), 3);
+    }
+
+    #[test]
+    fn chunks_end_on_char_boundaries() {
+        let mut policy = policy(CookieDomain::HostOnly, CookiePrefix::None);
+        policy.chunk_size = 3;
+        let value = "aé€😀b";
+        let chunks = policy.split(value).unwrap();
+
+        assert!(chunks.iter().all(|chunk| !chunk.is_empty()));
+        assert_eq!(chunks.concat(), value);
+
+        policy.chunk_size = 1;
+        assert_eq!(policy.split("é€").unwrap(), vec!["é", "€"]);
+    }
+
+    #[test]
+    fn whole_cookies_win_over_chunks() {
+        let policy = policy(CookieDomain::HostOnly, CookiePrefix::None);
+        let req = TestRequest::default()
+            .cookie(Cookie::new("id_token", "whole"))
+            .cookie(Cookie::new("id_token.0", "chunk"))
+            .to_http_request();
+        assert_eq!(policy.read(&req, "id_token").unwrap(), "whole");
+    }
+
+    #[test]
+    fn signed_values_verify_under_any_listed_key() {
+        let mut policy = policy(CookieDomain::HostOnly, CookiePrefix::None);
+        assert_eq!(
+            policy.sign("oauth_state", "value"),
+            Err(CookieCryptoError::NoSigningKey)
+        );
+
+        policy.signing_keys = vec![b"old".to_vec()];
+        let signed = policy.sign("oauth_state", "value").unwrap();
+        assert_eq!(policy.verify("oauth_state", &signed).unwrap(), "value");
+
+        policy.signing_keys = vec![b"new".to_vec(), b"old".to_vec()];
+        assert_eq!(policy.verify("oauth_state", &signed).unwrap(), "value");
+
+        policy.signing_keys = vec![b"new".to_vec()];
+        assert_eq!(
+            policy.verify("oauth_state", &signed),
+            Err(CookieCryptoError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn signatures_cover_the_value_and_name() {
+        let mut policy = policy(CookieDomain::HostOnly, CookiePrefix::None);
+        policy.signing_keys = vec![b"key".to_vec()];
+        let signed = policy.sign("oauth_state", "value").unwrap();
+        let (_, tag) = signed.rsplit_once('.').unwrap();
+
+        assert_eq!(
+            policy.verify("oauth_state", &format!("other.{}", tag)),
+            Err(CookieCryptoError::BadSignature)
+        );
+        assert_eq!(
+            policy.verify("id_token", &signed),
+            Err(CookieCryptoError::BadSignature)
+        );
+        assert_eq!(
+            policy.verify("oauth_state", "unsigned"),
+            Err(CookieCryptoError::Malformed)
+        );
+    }
+
+    #[test]
+    fn forwarded_proto_is_only_trusted_when_configured() {
+        let req = TestRequest::default()
+            .insert_header(("x-forwarded-proto", "https"))
+            .to_http_request();
+        let mut policy = policy(CookieDomain::HostOnly, CookiePrefix::None);
+
+        assert!(!policy.is_secure_request(&req));
+        policy.trust_forwarded_proto = true;
+        assert!(policy.is_secure_request(&req));
+    }
+}
@@ -0,0 +1,166 @@
+use actix_cors::Cors;
+use std::env;
+
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age: Option<usize>,
+}
+
+impl CorsPolicy {
+    pub fn from_env() -> CorsPolicy {
+        CorsPolicy {
+            allowed_origins: list_var("CORS_ALLOWED_ORIGINS", &[])
+                .into_iter()
+                .map(|origin| origin.trim_end_matches('/').to_lowercase())
+                .collect(),
+            allowed_methods: list_var(
+                "CORS_ALLOWED_METHODS",
+                &["GET", "POST", "PUT", "PATCH", "DELETE"],
+            ),
+            allowed_headers: list_var(
+                "CORS_ALLOWED_HEADERS",
+                &["Accept", "Authorization", "Content-Type"],
+            ),
+            allow_credentials: env::var("CORS_ALLOW_CREDENTIALS")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(true),
+            max_age: env::var("CORS_MAX_AGE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .or(Some(3600)),
+        }
+    }
+
+    pub fn allow_header(&mut self, header: &str) {
+        if !self
+            .allowed_headers
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(header))
+        {
+            self.allowed_headers.push(header.into());
+        }
+    }
+
+    pub fn cors(&self) -> Cors {
+        let origins = self.allowed_origins.clone();
+
+        let mut cors = Cors::default()
+            .allowed_origin_fn(move |origin, _req_head| {
+                origin
+                    .to_str()
+                    .map(|origin| is_allowed_origin(&origins, origin))
+                    .unwrap_or(false)
+            })
+            .allowed_methods(self.allowed_methods.iter().map(|m| m.as_str()))
+            .allowed_headers(self.allowed_headers.iter().map(|h| h.as_str()))
+            .max_age(self.max_age);
+
+        if self.allow_credentials {
+            cors = cors.supports_credentials();
+        }
+
+        cors
+    }
+}
+
+pub fn is_allowed_origin(allowed: &[String], origin: &str) -> bool {
+    let origin = origin.trim_end_matches('/').to_lowercase();
+    allowed
+        .iter()
+        .any(|pattern| origin_matches(pattern, &origin))
+}
+
+// Patterns are either an exact origin or "scheme://*.domain[:port]", which matches any
+// subdomain of `domain` (but not `domain` itself) on the same scheme and port
+pub fn origin_matches(pattern: &str, origin: &str) -> bool {
+    let (pattern_scheme, pattern_host) = match pattern.split_once("://") {
+        Some(parts) => parts,
+        None => return false,
+    };
+
+    let suffix = match pattern_host.strip_prefix("*.") {
+        Some(suffix) => suffix,
+        None => return pattern == origin,
+    };
+
+    match origin.split_once("://") {
+        Some((scheme, host)) if scheme == pattern_scheme => host
+            .strip_suffix(suffix)
+            .and_then(|label| label.strip_suffix('.'))
+            .map(|label| !label.is_empty() && !label.contains(':') && !label.contains('/'))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn list_var(name: &str, default: &[&str]) -> Vec<String> {
+    match env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect(),
+        Err(_) => default.iter().map(|item| item.to_string()).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_origins_match_whole() {
+        let allowed = vec!["https://app.example.com".to_string()];
+        assert!(is_allowed_origin(&allowed, "https://app.example.com"));
+        assert!(is_allowed_origin(&allowed, "HTTPS://App.Example.com/"));
+        assert!(!is_allowed_origin(&allowed, "http://app.example.com"));
+        assert!(!is_allowed_origin(
+            &allowed,
+            "https://app.example.com.evil.test"
+        ));
+    }
+
+    #[test]
+    fn wildcards_match_subdomains_only() {
+        let pattern = "https://*.example.com";
+        assert!(origin_matches(pattern, "https://app.example.com"));
+        assert!(origin_matches(pattern, "https://a.b.example.com"));
+        assert!(!origin_matches(pattern, "https://example.com"));
+        assert!(!origin_matches(pattern, "http://app.example.com"));
+        assert!(!origin_matches(pattern, "https://evilexample.com"));
+        assert!(!origin_matches(pattern, "https://app.example.com:8443"));
+    }
+
+    #[test]
+    fn wildcards_keep_their_port() {
+        let pattern = "https://*.example.com:8443";
+        assert!(origin_matches(pattern, "https://app.example.com:8443"));
+        assert!(!origin_matches(pattern, "https://app.example.com"));
+    }
+
+    #[test]
+    fn patterns_without_a_scheme_never_match() {
+        assert!(!origin_matches("*.example.com", "https://app.example.com"));
+    }
+
+    #[test]
+    fn allowed_headers_are_added_once() {
+        let mut policy = CorsPolicy {
+            allowed_origins: vec![],
+            allowed_methods: vec![],
+            allowed_headers: vec!["Authorization".into()],
+            allow_credentials: true,
+            max_age: None,
+        };
+        policy.allow_header("authorization");
+        policy.allow_header("X-CSRF-Token");
+        assert_eq!(
+            policy.allowed_headers,
+            vec!["Authorization", "X-CSRF-Token"]
+        );
+    }
+}
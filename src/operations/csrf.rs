@@ -0,0 +1,410 @@
+use crate::errors::csrf::CsrfError;
+use crate::operations::authenticated_user::bearer_token;
+use crate::operations::cookie_policy::{CookiePolicy, TOKEN_COOKIES};
+use crate::operations::cors::is_allowed_origin;
+use actix_web::{
+    cookie::Cookie,
+    http::{header, Method},
+    HttpRequest,
+};
+use base64::{engine::general_purpose, Engine};
+use rand::RngCore;
+use reqwest::Url;
+use std::env;
+
+#[derive(Debug, Clone)]
+pub struct CsrfPolicy {
+    pub allowed_origins: Vec<String>,
+    pub cookie_name: String,
+    pub header_name: String,
+    // Paths whose cookie-authenticated requests only need an allowed Origin, not the token
+    pub origin_only_prefixes: Vec<String>,
+    // Paths that don't change anything whatever their method, like the forward auth check
+    pub exempt_paths: Vec<String>,
+}
+
+impl CsrfPolicy {
+    pub fn from_env() -> CsrfPolicy {
+        // Browser clients on other origins are usually the same ones CORS lets in
+        let allowed_origins = env::var("CSRF_ALLOWED_ORIGINS")
+            .or_else(|_| env::var("CORS_ALLOWED_ORIGINS"))
+            .unwrap_or_default()
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_lowercase())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+
+        CsrfPolicy {
+            allowed_origins,
+            cookie_name: env::var("CSRF_COOKIE_NAME").unwrap_or_else(|_| "csrf_token".into()),
+            header_name: env::var("CSRF_HEADER_NAME").unwrap_or_else(|_| "X-CSRF-Token".into()),
+            origin_only_prefixes: vec![],
+            exempt_paths: vec![],
+        }
+    }
+
+    pub fn check_origin_only(&mut self, prefix: &str) {
+        self.origin_only_prefixes.push(prefix.into());
+    }
+
+    pub fn exempt(&mut self, path: &str) {
+        self.exempt_paths.push(path.into());
+    }
+
+    pub fn generate_token() -> String {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    // Readable by scripts on purpose: the client echoes it back in the CSRF header. It shares
+    // the token cookies' domain and SameSite so it reaches the same places they do.
+    pub fn cookie(
+        &self,
+        token: String,
+        cookie_policy: &CookiePolicy,
+        req: &HttpRequest,
+    ) -> Cookie<'static> {
+        let mut cookie = cookie_policy
+            .build(
+                &self.cookie_name,
+                token,
+                req.headers().get("host"),
+                cookie_policy.is_secure_request(req),
+            )
+            .http_only(false)
+            .finish();
+        cookie.set_name(self.cookie_name.clone());
+        cookie
+    }
+
+    pub fn validate(
+        &self,
+        req: &HttpRequest,
+        cookie_policy: &CookiePolicy,
+    ) -> Result<(), CsrfError> {
+        // A WebSocket handshake is a GET, but the socket it opens acts with the user's cookies
+        let upgrade = is_websocket_upgrade(req);
+        if is_safe_method(req) && !upgrade || self.is_exempt(req.path()) {
+            return Ok(());
+        }
+
+        // A Bearer header only speaks for the request when no token cookie rides along with it
+        let cookie_authenticated = is_cookie_authenticated(req, cookie_policy);
+        if !cookie_authenticated && is_bearer_authenticated(req) {
+            return Ok(());
+        }
+
+        self.check_origin(req, cookie_authenticated)?;
+
+        // Browsers can't add headers to a handshake, so its Origin is all there is to check
+        if cookie_authenticated && !upgrade && !self.is_origin_only(req.path()) {
+            self.check_token(req)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn check_origin(&self, req: &HttpRequest, required: bool) -> Result<(), CsrfError> {
+        let origin = match request_origin(req) {
+            Some(origin) => origin,
+            // Browsers send one of them on unsafe requests, so only non-browser clients get here
+            None if required => return Err(CsrfError::MissingOrigin),
+            None => return Ok(()),
+        };
+
+        let connection = req.connection_info();
+        let own_origin = format!("{}://{}", connection.scheme(), connection.host()).to_lowercase();
+
+        if origin == own_origin || is_allowed_origin(&self.allowed_origins, &origin) {
+            Ok(())
+        } else {
+            Err(CsrfError::OriginNotAllowed)
+        }
+    }
+
+    fn is_exempt(&self, path: &str) -> bool {
+        self.exempt_paths.iter().any(|exempt| exempt == path)
+    }
+
+    fn is_origin_only(&self, path: &str) -> bool {
+        self.origin_only_prefixes.iter().any(|prefix| {
+            path.strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+
+    pub fn check_token(&self, req: &HttpRequest) -> Result<(), CsrfError> {
+        let cookie = req
+            .cookie(&self.cookie_name)
+            .ok_or(CsrfError::MissingToken)?;
+        let header = req
+            .headers()
+            .get(self.header_name.as_str())
+            .and_then(|v| v.to_str().ok())
+            .ok_or(CsrfError::MissingToken)?;
+
+        if constant_time_eq(cookie.value().as_bytes(), header.as_bytes()) {
+            Ok(())
+        } else {
+            Err(CsrfError::TokenMismatch)
+        }
+    }
+}
+
+fn is_safe_method(req: &HttpRequest) -> bool {
+    matches!(
+        *req.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+pub fn is_websocket_upgrade(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+pub fn is_bearer_authenticated(req: &HttpRequest) -> bool {
+    bearer_token(req).is_some()
+}
+
+// Token cookies authenticate a request on their own, and therefore need CSRF protection
+pub fn is_cookie_authenticated(req: &HttpRequest, cookie_policy: &CookiePolicy) -> bool {
+    TOKEN_COOKIES
+        .iter()
+        .any(|name| cookie_policy.has_cookie(req, name))
+}
+
+// Origin header first, falling back to the origin part of the Referer
+fn request_origin(req: &HttpRequest) -> Option<String> {
+    if let Some(origin) = req.headers().get(header::ORIGIN) {
+        // A "null" origin can't be matched against anything, so it never passes
+        return Some(
+            origin
+                .to_str()
+                .unwrap_or("null")
+                .trim_end_matches('/')
+                .to_lowercase(),
+        );
+    }
+
+    req.headers()
+        .get(header::REFERER)
+        .and_then(|v| v.to_str().ok())
+        .map(|referer| match Url::parse(referer) {
+            Ok(url) => url.origin().ascii_serialization().to_lowercase(),
+            Err(_) => "null".into(),
+        })
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::test_support::cookie_policy;
+    use actix_web::test::TestRequest;
+
+    fn policy() -> CsrfPolicy {
+        CsrfPolicy {
+            allowed_origins: vec!["https://*.example.com".into()],
+            cookie_name: "csrf_token".into(),
+            header_name: "X-CSRF-Token".into(),
+            origin_only_prefixes: vec!["/app".into()],
+            exempt_paths: vec!["/auth".into()],
+        }
+    }
+
+    fn post() -> TestRequest {
+        TestRequest::post()
+            .uri("/logout")
+            .insert_header(("host", "auth.test"))
+    }
+
+    #[test]
+    fn safe_methods_pass() {
+        let req = TestRequest::get()
+            .cookie(Cookie::new("access_token", "token"))
+            .to_http_request();
+        assert_eq!(policy().validate(&req, &cookie_policy()), Ok(()));
+    }
+
+    #[test]
+    fn bearer_without_token_cookies_passes() {
+        let req = post()
+            .insert_header(("authorization", "Bearer token"))
+            .to_http_request();
+        assert_eq!(policy().validate(&req, &cookie_policy()), Ok(()));
+    }
+
+    #[test]
+    fn bearer_does_not_exempt_token_cookies() {
+        let req = post()
+            .insert_header(("authorization", "Bearer token"))
+            .insert_header(("origin", "http://auth.test"))
+            .cookie(Cookie::new("access_token", "token"))
+            .to_http_request();
+        assert_eq!(
+            policy().validate(&req, &cookie_policy()),
+            Err(CsrfError::MissingToken)
+        );
+    }
+
+    #[test]
+    fn cookie_requests_need_an_origin() {
+        let req = post()
+            .cookie(Cookie::new("access_token", "token"))
+            .cookie(Cookie::new("csrf_token", "csrf"))
+            .insert_header(("x-csrf-token", "csrf"))
+            .to_http_request();
+        assert_eq!(
+            policy().validate(&req, &cookie_policy()),
+            Err(CsrfError::MissingOrigin)
+        );
+    }
+
+    #[test]
+    fn cookie_requests_pass_with_origin_and_token() {
+        let req = post()
+            .insert_header(("origin", "http://auth.test"))
+            .cookie(Cookie::new("access_token", "token"))
+            .cookie(Cookie::new("csrf_token", "csrf"))
+            .insert_header(("x-csrf-token", "csrf"))
+            .to_http_request();
+        assert_eq!(policy().validate(&req, &cookie_policy()), Ok(()));
+    }
+
+    #[test]
+    fn token_mismatch_is_rejected() {
+        let req = post()
+            .insert_header(("origin", "http://auth.test"))
+            .cookie(Cookie::new("access_token", "token"))
+            .cookie(Cookie::new("csrf_token", "csrf"))
+            .insert_header(("x-csrf-token", "other"))
+            .to_http_request();
+        assert_eq!(
+            policy().validate(&req, &cookie_policy()),
+            Err(CsrfError::TokenMismatch)
+        );
+    }
+
+    #[test]
+    fn origin_only_prefixes_skip_the_token_but_not_the_origin() {
+        let form_post = |uri: &str, origin: &str| {
+            TestRequest::post()
+                .uri(uri)
+                .insert_header(("host", "auth.test"))
+                .insert_header(("origin", origin))
+                .cookie(Cookie::new("access_token", "token"))
+                .to_http_request()
+        };
+
+        let req = form_post("/app/orders", "http://auth.test");
+        assert_eq!(policy().validate(&req, &cookie_policy()), Ok(()));
+        let req = form_post("/app/orders", "https://evil.test");
+        assert_eq!(
+            policy().validate(&req, &cookie_policy()),
+            Err(CsrfError::OriginNotAllowed)
+        );
+        let req = form_post("/application", "http://auth.test");
+        assert_eq!(
+            policy().validate(&req, &cookie_policy()),
+            Err(CsrfError::MissingToken)
+        );
+    }
+
+    #[test]
+    fn exempt_paths_pass_with_any_method() {
+        let req = TestRequest::post()
+            .uri("/auth")
+            .insert_header(("host", "auth.test"))
+            .insert_header(("origin", "https://evil.test"))
+            .cookie(Cookie::new("access_token", "token"))
+            .to_http_request();
+        assert_eq!(policy().validate(&req, &cookie_policy()), Ok(()));
+
+        let req = TestRequest::post()
+            .uri("/auth/other")
+            .insert_header(("host", "auth.test"))
+            .cookie(Cookie::new("access_token", "token"))
+            .to_http_request();
+        assert_eq!(
+            policy().validate(&req, &cookie_policy()),
+            Err(CsrfError::MissingOrigin)
+        );
+    }
+
+    #[test]
+    fn websocket_upgrades_need_an_allowed_origin() {
+        let upgrade = |origin: Option<&str>| {
+            let mut req = TestRequest::get()
+                .uri("/legacy/socket")
+                .insert_header(("host", "auth.test"))
+                .insert_header(("upgrade", "websocket"))
+                .cookie(Cookie::new("access_token", "token"));
+            if let Some(origin) = origin {
+                req = req.insert_header(("origin", origin));
+            }
+            policy().validate(&req.to_http_request(), &cookie_policy())
+        };
+
+        assert_eq!(upgrade(Some("http://auth.test")), Ok(()));
+        assert_eq!(upgrade(Some("https://app.example.com")), Ok(()));
+        assert_eq!(
+            upgrade(Some("https://evil.test")),
+            Err(CsrfError::OriginNotAllowed)
+        );
+        assert_eq!(upgrade(None), Err(CsrfError::MissingOrigin));
+    }
+
+    #[test]
+    fn origins_are_matched_against_own_and_allowed() {
+        let check = |origin: &str| {
+            let req = post().insert_header(("origin", origin)).to_http_request();
+            policy().check_origin(&req, true)
+        };
+
+        assert_eq!(check("http://auth.test"), Ok(()));
+        assert_eq!(check("https://app.example.com/"), Ok(()));
+        assert_eq!(
+            check("https://example.com"),
+            Err(CsrfError::OriginNotAllowed)
+        );
+        assert_eq!(check("https://evil.test"), Err(CsrfError::OriginNotAllowed));
+        assert_eq!(check("null"), Err(CsrfError::OriginNotAllowed));
+    }
+
+    #[test]
+    fn referer_stands_in_for_origin() {
+        let req = post()
+            .insert_header(("referer", "http://auth.test/account?tab=1"))
+            .to_http_request();
+        assert_eq!(policy().check_origin(&req, true), Ok(()));
+
+        let req = post()
+            .insert_header(("referer", "https://evil.test/"))
+            .to_http_request();
+        assert_eq!(
+            policy().check_origin(&req, true),
+            Err(CsrfError::OriginNotAllowed)
+        );
+    }
+
+    #[test]
+    fn compares_in_constant_time() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+}
@@ -0,0 +1,348 @@
+use crate::errors::{auth::AuthError, server::ServerError};
+use crate::operations::claims::{AccessTokenClaims, IdTokenClaims};
+use crate::operations::session_token::now;
+use aws_config::SdkConfig;
+use aws_sdk_dynamodb::{
+    types::{AttributeValue, KeysAndAttributes},
+    Client,
+};
+use std::{collections::HashMap, env, sync::Mutex};
+
+// Cognito's default refresh token validity: a revoked sign-in can keep minting access tokens
+// carrying its origin_jti and auth_time for this long
+const REVOCATION_TTL: i64 = 30 * 24 * 60 * 60;
+
+// How long another instance's revocations may take to be noticed here
+const DEFAULT_CACHE_SECS: i64 = 30;
+
+struct CachedEntry {
+    value: Option<i64>,
+    valid_until: i64,
+}
+
+// Access tokens stay valid until they expire, so revoked ones are remembered here: single
+// tokens by jti, whole sign-ins by origin_jti, and every sign-in a user made before some
+// moment by a watermark on their sub
+pub struct TokenDenylist {
+    table: Option<String>,
+    entries: Mutex<HashMap<String, CachedEntry>>,
+    cache_secs: i64,
+}
+
+impl TokenDenylist {
+    // DENYLIST_TABLE is keyed on "key", with "expires_at" as its TTL attribute; without it
+    // revocations only reach this instance
+    pub fn from_env() -> TokenDenylist {
+        TokenDenylist {
+            table: env::var("DENYLIST_TABLE").ok(),
+            entries: Mutex::new(HashMap::new()),
+            cache_secs: env::var("DENYLIST_CACHE_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_CACHE_SECS),
+        }
+    }
+
+    pub async fn check(
+        &self,
+        config: &SdkConfig,
+        tenant: &str,
+        claims: &AccessTokenClaims,
+    ) -> Result<(), ServerError> {
+        let token_ids = [claims.jti.as_deref(), claims.origin_jti.as_deref()];
+        // auth_time survives refreshes, so tokens refreshed from an older sign-in are caught too
+        let signed_in_at = claims.auth_time.unwrap_or(claims.iat);
+        self.check_session(config, tenant, &claims.sub, &token_ids, signed_in_at)
+            .await
+    }
+
+    // ID tokens share their sign-in's origin_jti, so they go when it is revoked; their own jti
+    // is never revoked, since logout names the access token
+    pub async fn check_id_token(
+        &self,
+        config: &SdkConfig,
+        tenant: &str,
+        claims: &IdTokenClaims,
+    ) -> Result<(), ServerError> {
+        let token_ids = [claims.origin_jti.as_deref()];
+        let signed_in_at = claims.auth_time.unwrap_or(claims.iat);
+        self.check_session(config, tenant, &claims.sub, &token_ids, signed_in_at)
+            .await
+    }
+
+    async fn check_session(
+        &self,
+        config: &SdkConfig,
+        tenant: &str,
+        sub: &str,
+        token_ids: &[Option<&str>],
+        signed_in_at: i64,
+    ) -> Result<(), ServerError> {
+        let user = user_key(tenant, sub);
+        let mut keys = vec![user.clone()];
+        keys.extend(token_ids.iter().flatten().copied().map(jti_key));
+
+        let values = self.lookup(config, &keys).await?;
+
+        if keys[1..]
+            .iter()
+            .any(|key| values.get(key).is_some_and(|v| v.is_some()))
+        {
+            return Err(AuthError::InvalidToken("token has been revoked".into()).into());
+        }
+
+        if let Some(Some(not_before)) = values.get(&user) {
+            if signed_in_at < *not_before {
+                return Err(AuthError::InvalidToken("session has been revoked".into()).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    // The token itself, and everything else refreshed from the same sign-in
+    pub async fn revoke_token(
+        &self,
+        config: &SdkConfig,
+        claims: &AccessTokenClaims,
+    ) -> Result<(), ServerError> {
+        if let Some(jti) = &claims.jti {
+            self.put(config, jti_key(jti), claims.exp, claims.exp)
+                .await?;
+        }
+        if let Some(origin_jti) = &claims.origin_jti {
+            let expires_at = now() + REVOCATION_TTL;
+            self.put(config, jti_key(origin_jti), expires_at, expires_at)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // Every sign-in the user made up to now, on every device
+    pub async fn revoke_user(
+        &self,
+        config: &SdkConfig,
+        tenant: &str,
+        sub: &str,
+    ) -> Result<(), ServerError> {
+        let now = now();
+        self.put(config, user_key(tenant, sub), now, now + REVOCATION_TTL)
+            .await
+    }
+
+    async fn put(
+        &self,
+        config: &SdkConfig,
+        key: String,
+        value: i64,
+        expires_at: i64,
+    ) -> Result<(), ServerError> {
+        if let Some(table) = &self.table {
+            Client::new(config)
+                .put_item()
+                .table_name(table)
+                .item("key", AttributeValue::S(key.clone()))
+                .item("value", AttributeValue::N(value.to_string()))
+                .item("expires_at", AttributeValue::N(expires_at.to_string()))
+                .send()
+                .await?;
+        }
+
+        // Our own revocations hold here for as long as they last at all
+        self.entries.lock().unwrap().insert(
+            key,
+            CachedEntry {
+                value: Some(value),
+                valid_until: expires_at,
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn lookup(
+        &self,
+        config: &SdkConfig,
+        keys: &[String],
+    ) -> Result<HashMap<String, Option<i64>>, ServerError> {
+        let now = now();
+        let mut values = HashMap::new();
+        let mut missing = vec![];
+        {
+            let entries = self.entries.lock().unwrap();
+            for key in keys {
+                match entries.get(key).filter(|entry| entry.valid_until > now) {
+                    Some(entry) => {
+                        values.insert(key.clone(), entry.value);
+                    }
+                    None => missing.push(key.clone()),
+                }
+            }
+        }
+
+        let table = match &self.table {
+            Some(table) if !missing.is_empty() => table,
+            _ => return Ok(values),
+        };
+
+        let output = Client::new(config)
+            .batch_get_item()
+            .request_items(
+                table,
+                KeysAndAttributes::builder()
+                    .set_keys(Some(
+                        missing
+                            .iter()
+                            .map(|key| {
+                                HashMap::from([("key".into(), AttributeValue::S(key.clone()))])
+                            })
+                            .collect(),
+                    ))
+                    .consistent_read(true)
+                    .build(),
+            )
+            .send()
+            .await?;
+
+        let mut found: HashMap<String, i64> = HashMap::new();
+        for item in output
+            .responses()
+            .and_then(|responses| responses.get(table))
+            .map(|items| items.as_slice())
+            .unwrap_or_default()
+        {
+            let key = item.get("key").and_then(|v| v.as_s().ok());
+            let value = item
+                .get("value")
+                .and_then(|v| v.as_n().ok())
+                .and_then(|v| v.parse().ok());
+            if let (Some(key), Some(value)) = (key, value) {
+                found.insert(key.clone(), value);
+            }
+        }
+
+        // Keys DynamoDB left unprocessed are looked up again next time rather than cached
+        let unprocessed = output
+            .unprocessed_keys()
+            .is_some_and(|keys| !keys.is_empty());
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.valid_until > now);
+        for key in missing {
+            let value = found.get(&key).copied();
+            if value.is_some() || !unprocessed {
+                entries.insert(
+                    key.clone(),
+                    CachedEntry {
+                        value,
+                        valid_until: now + self.cache_secs,
+                    },
+                );
+            }
+            values.insert(key, value);
+        }
+
+        Ok(values)
+    }
+}
+
+fn jti_key(jti: &str) -> String {
+    format!("jti#{}", jti)
+}
+
+fn user_key(tenant: &str, sub: &str) -> String {
+    format!("user#{}#{}", tenant, sub)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::test_support::sdk_config;
+    use serde_json::json;
+
+    fn denylist() -> TokenDenylist {
+        TokenDenylist {
+            table: None,
+            entries: Mutex::new(HashMap::new()),
+            cache_secs: DEFAULT_CACHE_SECS,
+        }
+    }
+
+    fn access_token(auth_time: i64, jti: &str) -> AccessTokenClaims {
+        serde_json::from_value(json!({
+            "sub": "sub",
+            "iss": "https://cognito-idp.eu-west-1.amazonaws.com/pool",
+            "client_id": "client",
+            "token_use": "access",
+            "auth_time": auth_time,
+            "iat": auth_time,
+            "exp": now() + 3600,
+            "jti": jti,
+            "origin_jti": format!("origin-{}", jti),
+        }))
+        .unwrap()
+    }
+
+    fn id_token(auth_time: i64, jti: &str) -> IdTokenClaims {
+        serde_json::from_value(json!({
+            "sub": "sub",
+            "iss": "https://cognito-idp.eu-west-1.amazonaws.com/pool",
+            "aud": "client",
+            "token_use": "id",
+            "auth_time": auth_time,
+            "iat": now(),
+            "exp": now() + 3600,
+            "jti": format!("id-{}", jti),
+            "origin_jti": format!("origin-{}", jti),
+        }))
+        .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn watermark_revokes_earlier_sign_ins_only() {
+        let denylist = denylist();
+        let config = sdk_config();
+        let before = access_token(now() - 60, "a");
+
+        denylist.revoke_user(&config, "acme", "sub").await.unwrap();
+
+        assert!(denylist.check(&config, "acme", &before).await.is_err());
+        assert!(denylist
+            .check_id_token(&config, "acme", &id_token(now() - 60, "a"))
+            .await
+            .is_err());
+        // A sign-in after the revocation, or the same sub in another tenant, is unaffected
+        let after = access_token(now() + 1, "b");
+        assert!(denylist.check(&config, "acme", &after).await.is_ok());
+        assert!(denylist.check(&config, "globex", &before).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn revoking_a_token_revokes_its_sign_in() {
+        let denylist = denylist();
+        let config = sdk_config();
+        let token = access_token(now(), "a");
+
+        denylist.revoke_token(&config, &token).await.unwrap();
+
+        assert!(denylist.check(&config, "acme", &token).await.is_err());
+        // Refreshed from the same sign-in, so sharing its origin_jti
+        let mut refreshed = access_token(now(), "a");
+        refreshed.jti = Some("refreshed".into());
+        assert!(denylist.check(&config, "acme", &refreshed).await.is_err());
+        assert!(denylist
+            .check_id_token(&config, "acme", &id_token(now(), "a"))
+            .await
+            .is_err());
+
+        assert!(denylist
+            .check(&config, "acme", &access_token(now(), "b"))
+            .await
+            .is_ok());
+        assert!(denylist
+            .check_id_token(&config, "acme", &id_token(now(), "b"))
+            .await
+            .is_ok());
+    }
+}
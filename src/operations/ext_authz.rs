@@ -0,0 +1,418 @@
+use crate::errors::policy::PolicyError;
+use crate::errors::server::ServerError;
+use crate::operations::cookie_policy::CookiePolicy;
+use crate::operations::denylist::TokenDenylist;
+use crate::operations::forward_auth::{ForwardAuth, OriginalRequest};
+use crate::operations::personal_access_token::PersonalAccessTokens;
+use crate::operations::tenant::TenantRegistry;
+use crate::operations::user_attributes::UserEmails;
+use actix_http::{HttpMessage, Request as HttpRequest};
+use actix_rt::Arbiter;
+use actix_service::IntoServiceFactory;
+use actix_web::{
+    body,
+    dev::{AppConfig, Service, ServiceFactory, ServiceResponse},
+    http::{
+        header::{HeaderName, HeaderValue},
+        Method, Uri,
+    },
+    web, App, Error, HttpResponse, ResponseError,
+};
+use aws_config::SdkConfig;
+use envoy_types::ext_authz::v3::pb::{
+    Authorization, AuthorizationServer, CheckRequest, CheckResponse, DeniedHttpResponse,
+    HeaderAppendAction, HttpStatus,
+};
+use envoy_types::ext_authz::v3::{
+    CheckResponseExt, DeniedHttpResponseBuilder, OkHttpResponseBuilder,
+};
+use std::{collections::HashMap, env, future::Future, net::SocketAddr, rc::Rc, str::FromStr};
+use tokio::sync::{mpsc, oneshot};
+use tonic::{transport::Server, Code, Request, Response, Status};
+
+// What the HTTP side registers as app data, for the checks to run against
+pub struct CheckContext {
+    pub forward_auth: web::Data<ForwardAuth>,
+    pub tenants: web::Data<TenantRegistry>,
+    pub cookie_policy: web::Data<CookiePolicy>,
+    pub personal_access_tokens: web::Data<PersonalAccessTokens>,
+    pub denylist: web::Data<TokenDenylist>,
+    pub config: web::Data<SdkConfig>,
+    pub user_emails: web::Data<UserEmails>,
+}
+
+// The request Envoy is asking about, as its attributes describe it
+struct Check {
+    method: String,
+    scheme: String,
+    path: String,
+    host: String,
+    headers: HashMap<String, String>,
+}
+
+// The outcome of /auth for the request Envoy is asking about
+struct Decision {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+// Answers envoy.service.auth.v3.Authorization/Check with the same checks as /auth. Those need
+// an HttpRequest, which can't leave its thread while tonic's futures must, so they run in an
+// app of their own on an arbiter of their own.
+pub struct ExtAuthz {
+    forward_auth: web::Data<ForwardAuth>,
+    checks: mpsc::UnboundedSender<(Check, oneshot::Sender<Decision>)>,
+}
+
+impl ExtAuthz {
+    pub fn new(context: CheckContext) -> ExtAuthz {
+        let forward_auth = context.forward_auth.clone();
+        let (checks, queue) = mpsc::unbounded_channel::<(Check, oneshot::Sender<Decision>)>();
+
+        Arbiter::new().spawn_fn(move || {
+            actix_rt::spawn(serve_checks(context, queue));
+        });
+
+        ExtAuthz {
+            forward_auth,
+            checks,
+        }
+    }
+
+    // EXT_AUTHZ_ADDR is where the gRPC listener binds, e.g. 0.0.0.0:9191; without it there is none
+    pub fn serve_from_env(
+        context: CheckContext,
+    ) -> Option<impl Future<Output = Result<(), tonic::transport::Error>>> {
+        let addr: SocketAddr = env::var("EXT_AUTHZ_ADDR").ok()?.parse().ok()?;
+        println!("Envoy ext_authz listening on {}", addr);
+
+        Some(
+            Server::builder()
+                .add_service(AuthorizationServer::new(ExtAuthz::new(context)))
+                .serve(addr),
+        )
+    }
+
+    fn respond(&self, decision: Decision) -> CheckResponse {
+        if (200..300).contains(&decision.status) {
+            let mut ok = OkHttpResponseBuilder::new();
+            // Whatever identity headers the client sent itself never reach the upstream
+            for name in self.forward_auth.identity_headers() {
+                match decision
+                    .headers
+                    .iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                {
+                    Some((key, value)) => {
+                        ok.add_header(
+                            key,
+                            value,
+                            Some(HeaderAppendAction::OverwriteIfExistsOrAdd),
+                            false,
+                        );
+                    }
+                    None => {
+                        ok.remove_header(name);
+                    }
+                }
+            }
+
+            let mut response = CheckResponse::with_status(Status::ok(""));
+            response.set_http_response(ok);
+            return response;
+        }
+
+        let code = match decision.status {
+            401 => Code::Unauthenticated,
+            _ => Code::PermissionDenied,
+        };
+
+        let mut denied = DeniedHttpResponseBuilder::new();
+        for (key, value) in &decision.headers {
+            denied.add_header(key, value, None, false);
+        }
+        denied.set_body(decision.body);
+        let mut denied = DeniedHttpResponse::from(denied);
+        // Any status, so a browser can still be sent to the login page with a 302
+        denied.status = Some(HttpStatus {
+            code: decision.status.into(),
+        });
+
+        let mut response = CheckResponse::with_status(Status::new(code, ""));
+        response.set_http_response(denied);
+        response
+    }
+}
+
+#[tonic::async_trait]
+impl Authorization for ExtAuthz {
+    async fn check(
+        &self,
+        request: Request<CheckRequest>,
+    ) -> Result<Response<CheckResponse>, Status> {
+        let http = request
+            .into_inner()
+            .attributes
+            .and_then(|attributes| attributes.request)
+            .and_then(|request| request.http)
+            .ok_or_else(|| Status::invalid_argument("no HTTP request attributes"))?;
+
+        let check = Check {
+            method: http.method,
+            scheme: http.scheme,
+            path: http.path,
+            host: http.host,
+            headers: http.headers,
+        };
+        let (tx, rx) = oneshot::channel();
+        self.checks
+            .send((check, tx))
+            .map_err(|_| Status::unavailable("authorization checks have stopped"))?;
+        let decision = rx
+            .await
+            .map_err(|_| Status::unavailable("authorization check was dropped"))?;
+
+        Ok(Response::new(self.respond(decision)))
+    }
+}
+
+// Runs on the arbiter, where the app and the requests it is handed can live
+async fn serve_checks(
+    context: CheckContext,
+    mut queue: mpsc::UnboundedReceiver<(Check, oneshot::Sender<Decision>)>,
+) {
+    let service = match check_app(context)
+        .into_factory()
+        .new_service(AppConfig::default())
+        .await
+    {
+        Ok(service) => Rc::new(service),
+        Err(_) => {
+            println!("Envoy ext_authz checks could not start");
+            return;
+        }
+    };
+    while let Some((check, reply)) = queue.recv().await {
+        let service = service.clone();
+        actix_rt::spawn(async move {
+            let _ = reply.send(decide(&*service, check).await);
+        });
+    }
+}
+
+fn check_app(
+    context: CheckContext,
+) -> App<
+    impl ServiceFactory<
+        actix_web::dev::ServiceRequest,
+        Config = (),
+        Response = ServiceResponse,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .app_data(context.forward_auth)
+        .app_data(context.tenants)
+        .app_data(context.cookie_policy)
+        .app_data(context.personal_access_tokens)
+        .app_data(context.denylist)
+        .app_data(context.config)
+        .app_data(context.user_emails)
+        .default_service(web::to(check_handler))
+}
+
+async fn check_handler(
+    req: actix_web::HttpRequest,
+    forward_auth: web::Data<ForwardAuth>,
+) -> Result<HttpResponse, ServerError> {
+    let original = req.extensions().get::<OriginalRequest>().cloned();
+    match original {
+        Some(original) => forward_auth.check_original(&req, &original).await,
+        None => Ok(HttpResponse::BadRequest().finish()),
+    }
+}
+
+// The request as Envoy saw it. The client's own forwarding headers are left out, as nothing
+// between it and Envoy vouches for them; the scheme is the one Envoy was reached over.
+fn request(check: Check) -> Result<HttpRequest, ServerError> {
+    let invalid = |what: &str| PolicyError::InvalidPath(format!("{} {}", what, check.path));
+    let method = Method::from_str(&check.method).map_err(|_| invalid("method"))?;
+    let original = OriginalRequest::new(method.clone(), &check.path)?;
+    let (host_name, host) = header("host", &check.host).ok_or_else(|| invalid("host"))?;
+
+    let mut req = HttpRequest::new();
+    req.head_mut().method = method;
+    req.head_mut().uri = Uri::from_str(&original.uri()).map_err(|_| invalid("uri"))?;
+    // Pseudo-headers like :path are not headers at all, and whatever else actix can't hold is
+    // dropped rather than taking the listener down
+    for (name, value) in check
+        .headers
+        .iter()
+        .filter_map(|(name, value)| header(name, value))
+    {
+        if !name.as_str().starts_with("x-forwarded-") && name != "forwarded" {
+            req.headers_mut().append(name, value);
+        }
+    }
+    req.headers_mut().insert(host_name, host);
+    if let Some((name, value)) =
+        header("x-forwarded-proto", &check.scheme).filter(|_| !check.scheme.is_empty())
+    {
+        req.headers_mut().insert(name, value);
+    }
+    req.extensions_mut().insert(original);
+
+    Ok(req)
+}
+
+async fn decide<S>(service: &S, check: Check) -> Decision
+where
+    S: Service<HttpRequest, Response = ServiceResponse, Error = Error>,
+{
+    let res = match request(check) {
+        Ok(req) => match service.call(req).await {
+            Ok(res) => res.into_parts().1,
+            Err(e) => e.error_response(),
+        },
+        Err(e) => e.error_response(),
+    };
+
+    let status = res.status().as_u16();
+    let headers = res
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let body = body::to_bytes(res.into_body())
+        .await
+        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        .unwrap_or_default();
+
+    Decision {
+        status,
+        headers,
+        body,
+    }
+}
+
+fn header(name: &str, value: &str) -> Option<(HeaderName, HeaderValue)> {
+    Some((
+        HeaderName::try_from(name).ok()?,
+        HeaderValue::try_from(value).ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::test_support::{cookie_policy, sdk_config};
+
+    #[test]
+    fn unrepresentable_headers_are_dropped() {
+        assert!(header("x-request-id", "abc").is_some());
+        assert!(header(":path", "/").is_none());
+        assert!(header("bad name", "abc").is_none());
+        assert!(header("x-request-id", "line\nbreak").is_none());
+    }
+
+    fn check(path: &str, headers: &[(&str, &str)]) -> Check {
+        Check {
+            method: "GET".into(),
+            scheme: "https".into(),
+            path: path.into(),
+            host: "login.acme.com".into(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    fn context() -> CheckContext {
+        let tenants = TenantRegistry::parse(
+            r#"
+            [[tenant]]
+            id = "acme"
+            region = "eu-west-1"
+            user_pool_id = "eu-west-1_acme"
+            client_id = "acme-client"
+            hosts = ["login.acme.com"]
+            "#,
+        )
+        .unwrap();
+
+        CheckContext {
+            forward_auth: web::Data::new(ForwardAuth::from_env().unwrap()),
+            tenants: web::Data::new(tenants),
+            cookie_policy: web::Data::new(cookie_policy()),
+            personal_access_tokens: web::Data::new(PersonalAccessTokens::from_env()),
+            denylist: web::Data::new(TokenDenylist::from_env()),
+            config: web::Data::new(sdk_config()),
+            user_emails: web::Data::new(UserEmails::from_env()),
+        }
+    }
+
+    #[test]
+    fn forwarding_headers_come_from_envoy_not_the_client() {
+        let req = request(check(
+            "/app?x=1",
+            &[
+                (":authority", "login.acme.com"),
+                ("host", "evil.example"),
+                ("x-forwarded-host", "evil.example"),
+                ("x-forwarded-proto", "http"),
+                ("forwarded", "host=evil.example"),
+                ("x-request-id", "abc"),
+            ],
+        ))
+        .unwrap();
+
+        let headers = req.headers();
+        assert_eq!(headers.get("host").unwrap(), "login.acme.com");
+        assert_eq!(headers.get("x-forwarded-proto").unwrap(), "https");
+        assert!(headers.get("x-forwarded-host").is_none());
+        assert!(headers.get("forwarded").is_none());
+        assert_eq!(headers.get("x-request-id").unwrap(), "abc");
+        assert_eq!(req.uri(), "/app?x=1");
+    }
+
+    #[test]
+    fn envoy_path_is_normalized() {
+        let req = request(check("/%61dmin?x=1", &[])).unwrap();
+        let original = req.extensions().get::<OriginalRequest>().cloned().unwrap();
+        assert_eq!(original.path, "/admin");
+        assert_eq!(original.query, "x=1");
+
+        for path in ["/public/../admin", "/public/%2e%2e/admin", "admin"] {
+            assert!(request(check(path, &[])).is_err(), "{}", path);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn decides_through_the_check_app() {
+        let service = check_app(context())
+            .into_factory()
+            .new_service(AppConfig::default())
+            .await
+            .unwrap();
+
+        let decision = decide(&service, check("/public/%2e%2e/admin", &[])).await;
+        assert_eq!(decision.status, 400);
+
+        let decision = decide(&service, check("/app", &[])).await;
+        assert_eq!(decision.status, 401);
+
+        // A browser is sent to log in, and comes back to the normalized path
+        let decision = decide(&service, check("/%61pp", &[("accept", "text/html")])).await;
+        assert_eq!(decision.status, 302);
+        let location = decision
+            .headers
+            .iter()
+            .find(|(name, _)| name == "location")
+            .map(|(_, value)| value.as_str())
+            .unwrap();
+        assert!(location.ends_with("?return_to=%2Fapp"), "{}", location);
+    }
+}
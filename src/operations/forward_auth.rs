@@ -0,0 +1,406 @@
+use crate::errors::{policy::PolicyError, server::ServerError};
+use crate::operations::authenticated_user::{authenticate, AuthenticatedUser};
+use crate::operations::oauth::is_local_path;
+use crate::operations::policy::{DefaultAction, PolicyEngine, PolicyMode};
+use crate::operations::proxy::has_dot_segment;
+use crate::operations::user_attributes::verified_email;
+use actix_web::{
+    http::{header, Method, Uri},
+    HttpRequest, HttpResponse,
+};
+use reqwest::Url;
+use std::{env, str::FromStr};
+
+const DEFAULT_LOGIN_PATH: &str = "/oauth/authorize";
+const DEFAULT_RULE: &str = "default";
+
+// Which gateway asks, and so which headers describe the original request. Only that pair is
+// read: a client's own headers reach /auth too, and must not stand in for the gateway's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gateway {
+    // X-Forwarded-Method and X-Forwarded-Uri
+    Traefik,
+    // X-Original-Method and X-Original-URL, which the nginx config must set itself
+    Nginx,
+}
+
+// The request the gateway is asking about, rather than its subrequest to /auth
+#[derive(Debug, Clone)]
+pub struct OriginalRequest {
+    pub method: Method,
+    pub path: String,
+    pub query: String,
+}
+
+impl OriginalRequest {
+    pub fn from_request(
+        req: &HttpRequest,
+        gateway: Gateway,
+    ) -> Result<OriginalRequest, PolicyError> {
+        let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+
+        let (method, uri) = match gateway {
+            Gateway::Traefik => (
+                header("X-Forwarded-Method"),
+                header("X-Forwarded-Uri").map(|uri| uri.to_string()),
+            ),
+            Gateway::Nginx => (
+                header("X-Original-Method"),
+                header("X-Original-URL").map(path_of),
+            ),
+        };
+        let method = method
+            .and_then(|method| Method::from_str(method).ok())
+            .unwrap_or(Method::GET);
+        let uri = uri.ok_or_else(|| PolicyError::InvalidPath("no original URI".into()))?;
+
+        OriginalRequest::new(method, &uri)
+    }
+
+    // Matched against rules the way the router sees paths, so /%61dmin is /admin, and never
+    // with dot segments, which the upstream would resolve to somewhere else
+    pub fn new(method: Method, uri: &str) -> Result<OriginalRequest, PolicyError> {
+        let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+        let invalid = || PolicyError::InvalidPath(path.into());
+
+        if !path.starts_with('/') || has_dot_segment(path) {
+            return Err(invalid());
+        }
+        let path = actix_web::dev::Url::new(Uri::from_str(path).map_err(|_| invalid())?)
+            .path()
+            .to_string();
+
+        Ok(OriginalRequest {
+            method,
+            path,
+            query: query.into(),
+        })
+    }
+
+    pub fn uri(&self) -> String {
+        match self.query.is_empty() {
+            true => self.path.clone(),
+            false => format!("{}?{}", self.path, self.query),
+        }
+    }
+
+    // A page load a login page can answer, rather than an API call or an XHR
+    pub fn is_navigation(&self, req: &HttpRequest) -> bool {
+        if self.method != Method::GET {
+            return false;
+        }
+
+        let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+        match header("Sec-Fetch-Mode") {
+            Some(mode) => mode == "navigate",
+            None => header("Accept").is_some_and(|accept| accept.contains("text/html")),
+        }
+    }
+}
+
+// X-Original-URL is usually the absolute $scheme://$host$request_uri
+fn path_of(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(url) => match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        },
+        Err(_) => url.to_string(),
+    }
+}
+
+// Answers nginx auth_request and Traefik ForwardAuth subrequests
+pub struct ForwardAuth {
+    engine: PolicyEngine,
+    gateway: Gateway,
+    login_path: String,
+    user_header: String,
+    email_header: String,
+    groups_header: String,
+}
+
+impl ForwardAuth {
+    // FORWARD_AUTH_POLICY_FILE holds rules in the POLICY_FILE format, matched against the
+    // original request instead of the one to /auth. FORWARD_AUTH_GATEWAY is traefik (the
+    // default) or nginx.
+    pub fn from_env() -> Result<ForwardAuth, PolicyError> {
+        let header = |name: &str, default: &str| env::var(name).unwrap_or_else(|_| default.into());
+        let gateway = match env::var("FORWARD_AUTH_GATEWAY").as_deref() {
+            Err(_) | Ok("traefik") => Gateway::Traefik,
+            Ok("nginx") => Gateway::Nginx,
+            Ok(other) => {
+                return Err(PolicyError::Invalid(format!(
+                    "FORWARD_AUTH_GATEWAY {} is neither traefik nor nginx",
+                    other
+                )))
+            }
+        };
+
+        Ok(ForwardAuth {
+            engine: PolicyEngine::from_file(env::var("FORWARD_AUTH_POLICY_FILE").ok())?,
+            gateway,
+            login_path: header("FORWARD_AUTH_LOGIN_URL", DEFAULT_LOGIN_PATH),
+            user_header: header("FORWARD_AUTH_USER_HEADER", "X-User-Id"),
+            email_header: header("FORWARD_AUTH_EMAIL_HEADER", "X-User-Email"),
+            groups_header: header("FORWARD_AUTH_GROUPS_HEADER", "X-User-Groups"),
+        })
+    }
+
+    // Everything asked about needs a signed-in user; rules only add requirements on top, and
+    // paths that should stay public are left out of the gateway's auth_request
+    pub async fn check(&self, req: &HttpRequest) -> Result<HttpResponse, ServerError> {
+        let original = OriginalRequest::from_request(req, self.gateway)?;
+        self.check_original(req, &original).await
+    }
+
+    // For callers that know the original request from elsewhere than the gateway's headers
+    pub async fn check_original(
+        &self,
+        req: &HttpRequest,
+        original: &OriginalRequest,
+    ) -> Result<HttpResponse, ServerError> {
+        let user = match authenticate(req).await {
+            Ok(user) => user,
+            Err(e) if e.status_code == 401 && original.is_navigation(req) => {
+                return Ok(self.login_redirect(original));
+            }
+            Err(e) => return Err(e),
+        };
+
+        let policy = self.engine.current();
+        let decision = match policy.find(&original.method, &original.path) {
+            Some((rule, captures)) => rule.check(&user, &captures, &original.query, req.headers()),
+            None if policy.default == DefaultAction::Deny => Err(PolicyError::Denied {
+                rule: DEFAULT_RULE.into(),
+                reason: "no rule matches".into(),
+            }),
+            None => Ok(()),
+        };
+
+        if let Err(e) = decision {
+            if policy.mode == PolicyMode::Enforce {
+                return Err(e.into());
+            }
+            println!(
+                "Forward auth (report only) would deny {} {}: {}",
+                original.method, original.path, e
+            );
+        }
+
+        let email = verified_email(req, &user).await?;
+        Ok(self.identity_response(&user, email))
+    }
+
+    #[cfg(feature = "ext-authz")]
+    pub fn identity_headers(&self) -> [&str; 3] {
+        [&self.user_header, &self.email_header, &self.groups_header]
+    }
+
+    fn identity_response(&self, user: &AuthenticatedUser, email: Option<String>) -> HttpResponse {
+        let mut res = HttpResponse::Ok();
+        res.insert_header((self.user_header.as_str(), user.sub.as_str()));
+        if let Some(email) = email {
+            res.insert_header((self.email_header.as_str(), email));
+        }
+        if !user.groups.is_empty() {
+            res.insert_header((self.groups_header.as_str(), user.groups.join(",")));
+        }
+
+        res.finish()
+    }
+
+    // The login flow only returns to local paths, so this works where the gateway and this
+    // server share a host
+    fn login_redirect(&self, original: &OriginalRequest) -> HttpResponse {
+        let return_to = original.uri();
+        let location = match is_local_path(&return_to) {
+            true => {
+                let query =
+                    serde_urlencoded::to_string([("return_to", &return_to)]).unwrap_or_default();
+                let separator = if self.login_path.contains('?') {
+                    '&'
+                } else {
+                    '?'
+                };
+                format!("{}{}{}", self.login_path, separator, query)
+            }
+            false => self.login_path.clone(),
+        };
+
+        HttpResponse::Found()
+            .insert_header((header::LOCATION, location))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn forward_auth(gateway: Gateway, login_path: &str) -> ForwardAuth {
+        ForwardAuth {
+            engine: PolicyEngine::from_file(None).unwrap(),
+            gateway,
+            login_path: login_path.into(),
+            user_header: "X-User-Id".into(),
+            email_header: "X-User-Email".into(),
+            groups_header: "X-User-Groups".into(),
+        }
+    }
+
+    fn location(res: &HttpResponse) -> &str {
+        res.headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+    }
+
+    #[test]
+    fn reads_only_the_configured_gateways_headers() {
+        let req = TestRequest::default()
+            .insert_header(("X-Forwarded-Method", "POST"))
+            .insert_header(("X-Forwarded-Uri", "/public?a=1"))
+            .insert_header(("X-Original-Method", "DELETE"))
+            .insert_header(("X-Original-URL", "https://app.acme.com/admin?b=2"))
+            .to_http_request();
+
+        let traefik = OriginalRequest::from_request(&req, Gateway::Traefik).unwrap();
+        assert_eq!(traefik.method, Method::POST);
+        assert_eq!(traefik.path, "/public");
+        assert_eq!(traefik.query, "a=1");
+
+        let nginx = OriginalRequest::from_request(&req, Gateway::Nginx).unwrap();
+        assert_eq!(nginx.method, Method::DELETE);
+        assert_eq!(nginx.path, "/admin");
+        assert_eq!(nginx.query, "b=2");
+    }
+
+    // A client talking to /auth through nginx can send Traefik's headers, and the other way
+    // round; neither is taken in place of the gateway's own
+    #[test]
+    fn spoofed_headers_are_not_a_fallback() {
+        let req = TestRequest::default()
+            .insert_header(("X-Forwarded-Uri", "/public"))
+            .to_http_request();
+        assert!(matches!(
+            OriginalRequest::from_request(&req, Gateway::Nginx),
+            Err(PolicyError::InvalidPath(_))
+        ));
+
+        let req = TestRequest::default()
+            .insert_header(("X-Original-URL", "/public"))
+            .to_http_request();
+        assert!(OriginalRequest::from_request(&req, Gateway::Traefik).is_err());
+    }
+
+    #[test]
+    fn method_defaults_to_get() {
+        let req = TestRequest::default()
+            .insert_header(("X-Forwarded-Uri", "/"))
+            .to_http_request();
+        let original = OriginalRequest::from_request(&req, Gateway::Traefik).unwrap();
+        assert_eq!(original.method, Method::GET);
+    }
+
+    #[test]
+    fn normalizes_encoded_paths() {
+        let original = OriginalRequest::new(Method::GET, "/%61dmin/users?x=%61").unwrap();
+        assert_eq!(original.path, "/admin/users");
+        assert_eq!(original.query, "x=%61");
+    }
+
+    #[test]
+    fn rejects_dot_segments_and_relative_paths() {
+        for uri in [
+            "/public/../admin",
+            "/public/%2e%2e/admin",
+            "/public/%2E%2E%2Fadmin",
+            "/./admin",
+            "admin",
+            "",
+        ] {
+            assert!(
+                matches!(
+                    OriginalRequest::new(Method::GET, uri),
+                    Err(PolicyError::InvalidPath(_))
+                ),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn check_refuses_dot_segments_before_authenticating() {
+        let req = TestRequest::default()
+            .insert_header(("X-Forwarded-Uri", "/public/%2e%2e/admin"))
+            .to_http_request();
+        let err = forward_auth(Gateway::Traefik, DEFAULT_LOGIN_PATH)
+            .check(&req)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status_code, 400);
+    }
+
+    #[test]
+    fn path_of_absolute_and_relative_urls() {
+        assert_eq!(path_of("https://app.acme.com/a/b?c=d"), "/a/b?c=d");
+        assert_eq!(path_of("https://app.acme.com"), "/");
+        assert_eq!(path_of("/a/b?c=d"), "/a/b?c=d");
+    }
+
+    #[test]
+    fn navigation_is_a_get_for_a_page() {
+        let get = OriginalRequest::new(Method::GET, "/").unwrap();
+        let post = OriginalRequest::new(Method::POST, "/").unwrap();
+
+        let navigate = TestRequest::default()
+            .insert_header(("Sec-Fetch-Mode", "navigate"))
+            .insert_header(("Accept", "application/json"))
+            .to_http_request();
+        assert!(get.is_navigation(&navigate));
+        assert!(!post.is_navigation(&navigate));
+
+        let fetch = TestRequest::default()
+            .insert_header(("Sec-Fetch-Mode", "cors"))
+            .insert_header(("Accept", "text/html"))
+            .to_http_request();
+        assert!(!get.is_navigation(&fetch));
+
+        let html = TestRequest::default()
+            .insert_header(("Accept", "text/html,application/xhtml+xml"))
+            .to_http_request();
+        assert!(get.is_navigation(&html));
+        assert!(!get.is_navigation(&TestRequest::default().to_http_request()));
+    }
+
+    #[test]
+    fn login_redirect_returns_to_the_original_request() {
+        let original = OriginalRequest::new(Method::GET, "/docs/page?x=1&y=2").unwrap();
+        let res = forward_auth(Gateway::Traefik, "/login").login_redirect(&original);
+        assert_eq!(res.status(), 302);
+        assert_eq!(
+            location(&res),
+            "/login?return_to=%2Fdocs%2Fpage%3Fx%3D1%26y%3D2"
+        );
+
+        let res = forward_auth(Gateway::Traefik, "/login?tenant=acme").login_redirect(&original);
+        assert_eq!(
+            location(&res),
+            "/login?tenant=acme&return_to=%2Fdocs%2Fpage%3Fx%3D1%26y%3D2"
+        );
+    }
+
+    #[test]
+    fn login_redirect_drops_non_local_return_to() {
+        let original = OriginalRequest {
+            method: Method::GET,
+            path: "//evil.example/".into(),
+            query: String::new(),
+        };
+        let res = forward_auth(Gateway::Traefik, "/login").login_redirect(&original);
+        assert_eq!(location(&res), "/login");
+    }
+}
@@ -0,0 +1,226 @@
+use crate::errors::server::ServerError;
+use crate::operations::tenant::Tenant;
+use aws_config::SdkConfig;
+use aws_sdk_dynamodb::{types::AttributeValue, Client};
+use std::{collections::HashMap, env};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentityProviderMapping {
+    pub identity_provider: String,
+    // Enforced domains may only sign in through their IdP, never with a password
+    pub enforced: bool,
+    // Whether eng.acme.com goes wherever acme.com goes; only when the mapping says so, since a
+    // subdomain may well belong to someone else
+    pub subdomains: bool,
+}
+
+// "domain:Provider[:enforced][:subdomains],...", as SSO_DOMAINS and a tenant's sso_domains
+pub fn parse_domains(spec: &str) -> HashMap<String, IdentityProviderMapping> {
+    let mut domains = HashMap::new();
+
+    for entry in spec.split(',') {
+        let mut parts = entry.trim().split(':');
+        if let (Some(domain), Some(identity_provider)) = (parts.next(), parts.next()) {
+            if domain.is_empty() || identity_provider.is_empty() {
+                continue;
+            }
+            let flags: Vec<&str> = parts.collect();
+            domains.insert(
+                domain.to_lowercase(),
+                IdentityProviderMapping {
+                    identity_provider: identity_provider.into(),
+                    enforced: flags.contains(&"enforced"),
+                    subdomains: flags.contains(&"subdomains"),
+                },
+            );
+        }
+    }
+
+    domains
+}
+
+// IdP names belong to a user pool, so mappings are looked up in the tenant's own: its
+// sso_domains, then SSO_DOMAIN_TABLE
+#[derive(Debug, Clone)]
+pub struct HomeRealmDirectory {
+    // The table and the client that reads it, made once at startup
+    table: Option<(String, Client)>,
+}
+
+impl HomeRealmDirectory {
+    // SSO_DOMAIN_TABLE names a DynamoDB table keyed on "tenant" and "domain" with
+    // "identity_provider", "enforced" and "subdomains" attributes
+    pub fn from_env(config: &SdkConfig) -> HomeRealmDirectory {
+        HomeRealmDirectory {
+            table: env::var("SSO_DOMAIN_TABLE")
+                .ok()
+                .map(|table| (table, Client::new(config))),
+        }
+    }
+
+    pub async fn lookup(
+        &self,
+        tenant: &Tenant,
+        email: &str,
+    ) -> Result<Option<IdentityProviderMapping>, ServerError> {
+        let domain = match email.rsplit_once('@') {
+            Some((_, domain)) if !domain.is_empty() => domain.trim().to_lowercase(),
+            _ => return Ok(None),
+        };
+
+        for (candidate, exact) in candidates(&domain) {
+            let mapping = match tenant.sso_domains.get(candidate) {
+                Some(mapping) => Some(mapping.clone()),
+                None => self.get(&tenant.id, candidate).await?,
+            };
+
+            if let Some(mapping) = mapping.filter(|mapping| exact || mapping.subdomains) {
+                return Ok(Some(mapping));
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn get(
+        &self,
+        tenant: &str,
+        domain: &str,
+    ) -> Result<Option<IdentityProviderMapping>, ServerError> {
+        let (table, client) = match &self.table {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+
+        let output = client
+            .get_item()
+            .table_name(table)
+            .key("tenant", AttributeValue::S(tenant.into()))
+            .key("domain", AttributeValue::S(domain.into()))
+            .send()
+            .await?;
+
+        Ok(output.item().and_then(to_mapping))
+    }
+}
+
+// The domain itself, then each parent that is still more than a TLD, each with whether it is
+// the exact domain
+fn candidates(domain: &str) -> impl Iterator<Item = (&str, bool)> {
+    let parents = domain
+        .match_indices('.')
+        .map(move |(i, _)| &domain[i + 1..])
+        .filter(|parent| parent.contains('.'))
+        .map(|parent| (parent, false));
+
+    std::iter::once((domain, true)).chain(parents)
+}
+
+fn to_mapping(item: &HashMap<String, AttributeValue>) -> Option<IdentityProviderMapping> {
+    let identity_provider = item.get("identity_provider")?.as_s().ok()?;
+    let flag = |name: &str| {
+        item.get(name)
+            .and_then(|v| v.as_bool().ok())
+            .copied()
+            .unwrap_or(false)
+    };
+
+    Some(IdentityProviderMapping {
+        identity_provider: identity_provider.clone(),
+        enforced: flag("enforced"),
+        subdomains: flag("subdomains"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::tenant::TenantRegistry;
+    use crate::operations::test_support::tenant;
+
+    fn directory() -> HomeRealmDirectory {
+        HomeRealmDirectory { table: None }
+    }
+
+    #[test]
+    fn candidates_stop_short_of_the_tld() {
+        let all = |domain| candidates(domain).collect::<Vec<_>>();
+        assert_eq!(
+            all("eng.uk.acme.com"),
+            vec![
+                ("eng.uk.acme.com", true),
+                ("uk.acme.com", false),
+                ("acme.com", false),
+            ]
+        );
+        assert_eq!(all("acme.com"), vec![("acme.com", true)]);
+        assert_eq!(all("localhost"), vec![("localhost", true)]);
+    }
+
+    #[test]
+    fn parses_domains_and_flags() {
+        let domains = parse_domains(
+            "Acme.com:AcmeOkta:enforced, globex.com:GlobexAD:subdomains:enforced,bad, :x,y:",
+        );
+        assert_eq!(domains.len(), 2);
+        assert_eq!(
+            domains["acme.com"],
+            IdentityProviderMapping {
+                identity_provider: "AcmeOkta".into(),
+                enforced: true,
+                subdomains: false,
+            }
+        );
+        assert!(domains["globex.com"].enforced);
+        assert!(domains["globex.com"].subdomains);
+    }
+
+    #[actix_rt::test]
+    async fn subdomains_only_inherit_when_the_mapping_says_so() {
+        let tenant = TenantRegistry::parse(
+            r#"
+            [[tenant]]
+            id = "acme"
+            region = "eu-west-1"
+            user_pool_id = "eu-west-1_acme"
+            client_id = "acme-client"
+            sso_domains = "acme.com:AcmeOkta,globex.com:GlobexAD:subdomains"
+            "#,
+        )
+        .unwrap()
+        .get("acme")
+        .unwrap();
+        let lookup = |email: &'static str| {
+            let directory = directory();
+            let tenant = &tenant;
+            async move { directory.lookup(tenant, email).await.unwrap() }
+        };
+
+        assert_eq!(
+            lookup("alice@ACME.com").await.unwrap().identity_provider,
+            "AcmeOkta"
+        );
+        assert_eq!(lookup("alice@eng.acme.com").await, None);
+        assert_eq!(
+            lookup("bob@eng.globex.com")
+                .await
+                .unwrap()
+                .identity_provider,
+            "GlobexAD"
+        );
+        assert_eq!(lookup("carol@example.com").await, None);
+        assert_eq!(lookup("no-domain").await, None);
+        assert_eq!(lookup("trailing@").await, None);
+    }
+
+    // Another tenant's pool has no such IdP, so its mapping is not this tenant's
+    #[actix_rt::test]
+    async fn mappings_belong_to_their_tenant() {
+        let tenant = tenant(None);
+        assert!(tenant.sso_domains.is_empty());
+        assert_eq!(
+            directory().lookup(&tenant, "alice@acme.com").await.unwrap(),
+            None
+        );
+    }
+}
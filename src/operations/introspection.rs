@@ -0,0 +1,337 @@
+use crate::errors::server::ServerError;
+use crate::operations::authenticated_user::{verify_token, AuthenticatedUser, Credential};
+use crate::operations::claims::AccessTokenClaims;
+use crate::operations::denylist::TokenDenylist;
+use crate::operations::session_token::now;
+use crate::operations::tenant::{shared_config, Tenant};
+use actix_web::{web, HttpRequest};
+use base64::{engine::general_purpose, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, env, sync::Mutex};
+
+pub const MAX_BATCH_SIZE: usize = 100;
+
+// Personal access tokens live for months and can be revoked at any time, so they are only
+// trusted from the cache this long
+const DEFAULT_PAT_CACHE_SECS: i64 = 60;
+
+const DEFAULT_MAX_CACHE_ENTRIES: usize = 10_000;
+
+// One token as RFC 7662 has it, or a batch of them as "tokens", which only JSON can carry.
+// token_type_hint is accepted and ignored, since only access tokens are ever active.
+#[derive(Debug, Deserialize)]
+pub struct IntrospectionRequest {
+    pub token: Option<String>,
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Introspection {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    // Only in batches, for a token that couldn't be checked and is worth asking about again
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Introspection {
+    // One token in a batch failing to be checked, say for a JWKS fetch, doesn't fail the rest
+    fn from_batch(result: Result<Introspection, ServerError>) -> Introspection {
+        result.unwrap_or_else(|e| {
+            println!("Introspection failed: {}", e.cause);
+            Introspection {
+                error: Some("temporarily_unavailable".into()),
+                ..Introspection::default()
+            }
+        })
+    }
+}
+
+impl From<&AuthenticatedUser> for Introspection {
+    fn from(user: &AuthenticatedUser) -> Self {
+        let client_id = match (&user.credential, &user.claims) {
+            (Credential::Service { client_id }, _) => Some(client_id.clone()),
+            (_, Some(claims)) => Some(claims.client_id.clone()),
+            _ => None,
+        };
+
+        Introspection {
+            active: true,
+            sub: Some(user.sub.clone()),
+            scope: Some(user.scopes.join(" ")).filter(|scope| !scope.is_empty()),
+            client_id,
+            exp: Some(user.expires_at),
+            username: user.username.clone(),
+            error: None,
+        }
+    }
+}
+
+struct CachedIntrospection {
+    introspection: Introspection,
+    // Kept to look the token up in the denylist again, since it may be revoked at any time
+    claims: Option<AccessTokenClaims>,
+    expires_at: i64,
+}
+
+// Active results are kept until their token expires; inactive ones are never cached, so a
+// token that fails for a passing reason isn't reported dead for good
+pub struct IntrospectionCache {
+    results: Mutex<HashMap<String, CachedIntrospection>>,
+    pat_cache_secs: i64,
+    max_entries: usize,
+}
+
+impl IntrospectionCache {
+    // INTROSPECTION_CACHE_SIZE bounds how many results are kept; the ones nearest expiry go
+    // first when it is full
+    pub fn from_env() -> IntrospectionCache {
+        IntrospectionCache {
+            results: Mutex::new(HashMap::new()),
+            pat_cache_secs: env::var("INTROSPECTION_PAT_CACHE_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_PAT_CACHE_SECS),
+            max_entries: env::var("INTROSPECTION_CACHE_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_CACHE_ENTRIES),
+        }
+    }
+
+    pub async fn introspect_all(
+        &self,
+        req: &HttpRequest,
+        tenant: &Tenant,
+        tokens: &[String],
+    ) -> Vec<Introspection> {
+        let mut results = vec![];
+        for token in tokens {
+            results.push(Introspection::from_batch(
+                self.introspect(req, tenant, token).await,
+            ));
+        }
+        results
+    }
+
+    // A token we turn away is simply inactive; only failing to check it at all is an error
+    pub async fn introspect(
+        &self,
+        req: &HttpRequest,
+        tenant: &Tenant,
+        token: &str,
+    ) -> Result<Introspection, ServerError> {
+        let key = cache_key(&tenant.id, token);
+        if let Some((introspection, claims)) = self.cached(&key) {
+            let denylist = req.app_data::<web::Data<TokenDenylist>>();
+            if let (Some(denylist), Some(claims)) = (denylist, &claims) {
+                let config = shared_config(req).await;
+                match denylist.check(&config, &tenant.id, claims).await {
+                    Ok(()) => {}
+                    Err(e) if e.status_code < 500 => return Ok(Introspection::default()),
+                    Err(e) => return Err(e),
+                }
+            }
+            return Ok(introspection);
+        }
+
+        let user = match verify_token(req, token).await {
+            Ok(user) => user,
+            Err(e) if e.status_code < 500 => return Ok(Introspection::default()),
+            Err(e) => return Err(e),
+        };
+
+        let introspection = Introspection::from(&user);
+        let expires_at = match user.is_personal_access_token() {
+            true => user.expires_at.min(now() + self.pat_cache_secs),
+            false => user.expires_at,
+        };
+
+        self.store(
+            key,
+            CachedIntrospection {
+                introspection: introspection.clone(),
+                claims: user.claims,
+                expires_at,
+            },
+        );
+
+        Ok(introspection)
+    }
+
+    fn store(&self, key: String, cached: CachedIntrospection) {
+        let mut results = self.results.lock().unwrap();
+        let now = now();
+        results.retain(|_, cached| cached.expires_at > now);
+
+        while results.len() >= self.max_entries.max(1) {
+            let soonest = results
+                .iter()
+                .min_by_key(|(_, cached)| cached.expires_at)
+                .map(|(key, _)| key.clone());
+            match soonest {
+                Some(key) => results.remove(&key),
+                None => break,
+            };
+        }
+        results.insert(key, cached);
+    }
+
+    fn cached(&self, key: &str) -> Option<(Introspection, Option<AccessTokenClaims>)> {
+        let results = self.results.lock().unwrap();
+        results
+            .get(key)
+            .filter(|cached| cached.expires_at > now())
+            .map(|cached| (cached.introspection.clone(), cached.claims.clone()))
+    }
+}
+
+// Tokens are bearer credentials, so only their digest is kept as a key
+fn cache_key(tenant: &str, token: &str) -> String {
+    let mut hash = Sha256::new();
+    hash.update(tenant.as_bytes());
+    hash.update([0u8]);
+    hash.update(token.as_bytes());
+    general_purpose::STANDARD.encode(hash.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::test_support::{service, tenant, tenants, user};
+    use actix_web::test::TestRequest;
+
+    fn cache(max_entries: usize) -> IntrospectionCache {
+        IntrospectionCache {
+            results: Mutex::new(HashMap::new()),
+            pat_cache_secs: DEFAULT_PAT_CACHE_SECS,
+            max_entries,
+        }
+    }
+
+    fn request() -> HttpRequest {
+        TestRequest::default()
+            .insert_header(("host", "login.acme.com"))
+            .app_data(web::Data::new(tenants(None)))
+            .to_http_request()
+    }
+
+    fn cached(sub: &str, expires_at: i64) -> CachedIntrospection {
+        CachedIntrospection {
+            introspection: Introspection {
+                active: true,
+                sub: Some(sub.into()),
+                ..Introspection::default()
+            },
+            claims: None,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn cache_keys_are_per_tenant_digests() {
+        let key = cache_key("acme", "token");
+        assert_eq!(key, cache_key("acme", "token"));
+        assert_ne!(key, cache_key("globex", "token"));
+        assert_ne!(key, cache_key("acme", "other"));
+        assert!(!key.contains("token"));
+    }
+
+    #[test]
+    fn describes_users_and_services() {
+        let introspection = Introspection::from(&user(&[], &["orgs", "tokens"]));
+        assert!(introspection.active);
+        assert_eq!(introspection.sub.as_deref(), Some("sub-1"));
+        assert_eq!(introspection.scope.as_deref(), Some("orgs tokens"));
+        assert_eq!(introspection.username.as_deref(), Some("alice"));
+
+        let introspection = Introspection::from(&service("reporting", &[]));
+        assert_eq!(introspection.client_id.as_deref(), Some("reporting"));
+        assert_eq!(introspection.scope, None);
+    }
+
+    #[test]
+    fn inactive_says_nothing_else() {
+        assert_eq!(
+            serde_json::to_value(Introspection::default()).unwrap(),
+            serde_json::json!({"active": false})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn rejected_tokens_are_inactive() {
+        let req = request();
+        let introspection = cache(10)
+            .introspect(&req, &tenant(None), "not-a-token")
+            .await
+            .unwrap();
+        assert!(!introspection.active);
+        assert_eq!(introspection.error, None);
+    }
+
+    // Without a PAT table the token can't be checked at all, which the batch reports for that
+    // token alone
+    #[actix_rt::test]
+    async fn a_failed_check_only_fails_its_own_token() {
+        let req = request();
+        let cache = cache(10);
+        let tenant = tenant(None);
+
+        assert_eq!(
+            cache
+                .introspect(&req, &tenant, "hfp_token")
+                .await
+                .unwrap_err()
+                .status_code,
+            500
+        );
+
+        let results = cache
+            .introspect_all(&req, &tenant, &["not-a-token".into(), "hfp_token".into()])
+            .await;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].error, None);
+        assert!(!results[1].active);
+        assert_eq!(results[1].error.as_deref(), Some("temporarily_unavailable"));
+    }
+
+    #[actix_rt::test]
+    async fn active_results_come_from_the_cache() {
+        let req = request();
+        let cache = cache(10);
+        let tenant = tenant(None);
+        cache.store(cache_key(&tenant.id, "token"), cached("sub-1", now() + 60));
+
+        let introspection = cache.introspect(&req, &tenant, "token").await.unwrap();
+        assert!(introspection.active);
+        assert_eq!(introspection.sub.as_deref(), Some("sub-1"));
+    }
+
+    #[test]
+    fn cache_is_bounded_and_drops_the_soonest_to_expire() {
+        let cache = cache(2);
+        let now = now();
+        cache.store("a".into(), cached("a", now + 30));
+        cache.store("b".into(), cached("b", now + 10));
+        cache.store("expired".into(), cached("expired", now - 1));
+        cache.store("c".into(), cached("c", now + 20));
+
+        let results = cache.results.lock().unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.contains_key("a"));
+        assert!(results.contains_key("c"));
+    }
+}
@@ -1,4 +1,32 @@
+pub mod audit;
 pub mod auth;
 pub mod auth_key;
+pub mod authenticated_user;
+pub mod claims;
+pub mod client_credentials;
+pub mod cognito_verifier;
+pub mod cookie_crypto;
+pub mod cookie_policy;
+pub mod cors;
+pub mod csrf;
+pub mod denylist;
+#[cfg(feature = "ext-authz")]
+pub mod ext_authz;
+pub mod forward_auth;
+pub mod home_realm;
+pub mod introspection;
+pub mod oauth;
+pub mod organization;
+pub mod personal_access_token;
+pub mod policy;
+pub mod proxy;
+pub mod session_token;
+pub mod tenant;
+#[cfg(test)]
+pub mod test_support;
+pub mod token_validity;
 pub mod user;
+pub mod user_attributes;
+pub mod user_directory;
 pub mod user_token;
+pub mod ws_ticket;
@@ -0,0 +1,443 @@
+use crate::errors::{oauth::OAuthError, server::ServerError};
+use crate::operations::cookie_policy::CookiePolicy;
+use crate::operations::csrf::constant_time_eq;
+use crate::operations::tenant::Tenant;
+use crate::operations::user::is_checked;
+use actix_web::{
+    cookie::{time::Duration, Cookie, SameSite},
+    HttpRequest,
+};
+use aws_sdk_cognitoidentityprovider::types::AuthenticationResultType;
+use base64::{engine::general_purpose, Engine};
+use rand::RngCore;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env;
+
+pub const STATE_COOKIE: &str = "oauth_state";
+
+// Long enough to get through the hosted UI, including a federated IdP's login page
+const STATE_COOKIE_LIFETIME: i64 = 10 * 60;
+
+// Stands in for the request's own origin when checking a return path can't leave it
+const LOCAL_ORIGIN: &str = "https://local.invalid/";
+
+#[derive(Debug, Clone)]
+pub struct HostedUi {
+    pub domain: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub callback_path: String,
+    pub scopes: String,
+}
+
+impl HostedUi {
+    pub fn for_tenant(tenant: &Tenant) -> Result<HostedUi, OAuthError> {
+        let domain = tenant
+            .cognito_domain
+            .clone()
+            .ok_or(OAuthError::NotConfigured("COGNITO_DOMAIN"))?;
+        let domain = match domain.starts_with("https://") || domain.starts_with("http://") {
+            true => domain.trim_end_matches('/').to_string(),
+            false => format!("https://{}", domain.trim_end_matches('/')),
+        };
+
+        Ok(HostedUi {
+            domain,
+            client_id: Some(tenant.client_id.clone())
+                .filter(|id| !id.is_empty())
+                .ok_or(OAuthError::NotConfigured("APP_CLIENT_ID"))?,
+            client_secret: tenant.client_secret.clone(),
+            redirect_uri: tenant.redirect_uri.clone(),
+            callback_path: format!(
+                "{}/oauth/callback",
+                tenant.path_prefix.as_deref().unwrap_or_default()
+            ),
+            scopes: env::var("OAUTH_SCOPES").unwrap_or_else(|_| "openid email profile".into()),
+        })
+    }
+
+    // Falls back to this backend's own callback route when no redirect URI is configured
+    pub fn redirect_uri(&self, req: &HttpRequest, cookie_policy: &CookiePolicy) -> String {
+        if let Some(redirect_uri) = &self.redirect_uri {
+            return redirect_uri.clone();
+        }
+
+        let scheme = match cookie_policy.is_secure_request(req) {
+            true => "https",
+            false => "http",
+        };
+        format!(
+            "{}://{}{}",
+            scheme,
+            req.connection_info().host(),
+            self.callback_path
+        )
+    }
+
+    pub fn authorize_url(
+        &self,
+        pending: &PendingAuthorization,
+        redirect_uri: &str,
+        identity_provider: Option<&str>,
+        login_hint: Option<&str>,
+    ) -> String {
+        let mut params = vec![
+            ("response_type", "code".to_string()),
+            ("client_id", self.client_id.clone()),
+            ("redirect_uri", redirect_uri.into()),
+            ("scope", self.scopes.clone()),
+            ("state", pending.state.clone()),
+            ("nonce", pending.nonce.clone()),
+            ("code_challenge", pending.code_challenge()),
+            ("code_challenge_method", "S256".into()),
+        ];
+        if let Some(identity_provider) = identity_provider {
+            params.push(("identity_provider", identity_provider.into()));
+        }
+        if let Some(login_hint) = login_hint {
+            params.push(("login_hint", login_hint.into()));
+        }
+
+        Url::parse_with_params(&format!("{}/oauth2/authorize", self.domain), &params)
+            .map(|url| url.to_string())
+            .unwrap_or_else(|_| format!("{}/oauth2/authorize", self.domain))
+    }
+
+    pub async fn exchange_code(
+        &self,
+        client: &Client,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+    ) -> Result<OAuthTokenResponse, OAuthError> {
+        let params = [
+            ("grant_type", "authorization_code"),
+            ("client_id", self.client_id.as_str()),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("code_verifier", code_verifier),
+        ];
+
+        let mut request = client
+            .post(format!("{}/oauth2/token", self.domain))
+            .form(&params);
+        if let Some(secret) = &self.client_secret {
+            request = request.basic_auth(&self.client_id, Some(secret));
+        }
+
+        let res = request
+            .send()
+            .await
+            .map_err(|e| OAuthError::TokenExchange(e.to_string()))?;
+
+        if !res.status().is_success() {
+            let status = res.status();
+            let body = res.text().await.unwrap_or_default();
+            return Err(OAuthError::TokenExchange(format!("{} {}", status, body)));
+        }
+
+        res.json::<OAuthTokenResponse>()
+            .await
+            .map_err(|e| OAuthError::TokenExchange(e.to_string()))
+    }
+
+    // The caller's own app client credentials are passed straight through to Cognito
+    pub async fn client_credentials(
+        &self,
+        client: &Client,
+        client_id: &str,
+        client_secret: &str,
+        scope: Option<&str>,
+    ) -> Result<OAuthTokenResponse, OAuthError> {
+        let mut params = vec![("grant_type", "client_credentials")];
+        if let Some(scope) = scope {
+            params.push(("scope", scope));
+        }
+
+        let res = client
+            .post(format!("{}/oauth2/token", self.domain))
+            .basic_auth(client_id, Some(client_secret))
+            .form(&params)
+            .send()
+            .await
+            .map_err(|e| OAuthError::TokenExchange(e.to_string()))?;
+
+        if !res.status().is_success() {
+            let status = res.status();
+            let body = res.text().await.unwrap_or_default();
+            let error = serde_json::from_str::<OAuthErrorResponse>(&body)
+                .map(|e| e.error)
+                .unwrap_or_default();
+
+            return Err(match error.as_str() {
+                "invalid_client" | "unauthorized_client" => OAuthError::InvalidClient,
+                "invalid_scope" => OAuthError::InvalidScope(scope.unwrap_or_default().into()),
+                _ => OAuthError::TokenExchange(format!("{} {}", status, body)),
+            });
+        }
+
+        res.json::<OAuthTokenResponse>()
+            .await
+            .map_err(|e| OAuthError::TokenExchange(e.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingAuthorization {
+    pub state: String,
+    // Sent to the hosted UI and echoed back inside the ID token, tying that token to this browser
+    pub nonce: String,
+    pub code_verifier: String,
+    pub redirect_uri: String,
+    pub return_to: Option<String>,
+    #[serde(default)]
+    pub remember_me: bool,
+}
+
+impl PendingAuthorization {
+    pub fn new(
+        redirect_uri: String,
+        return_to: Option<String>,
+        remember_me: bool,
+    ) -> PendingAuthorization {
+        PendingAuthorization {
+            state: random_string(32),
+            nonce: random_string(32),
+            code_verifier: random_string(64),
+            redirect_uri,
+            return_to: return_to.filter(|path| is_local_path(path)),
+            remember_me,
+        }
+    }
+
+    pub fn code_challenge(&self) -> String {
+        general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(self.code_verifier.as_bytes()))
+    }
+
+    // Kept in a short-lived cookie so the callback can be matched to the browser that started it.
+    // It is always signed, since the callback trusts the nonce and return path it carries.
+    pub fn cookie(
+        &self,
+        cookie_policy: &CookiePolicy,
+        req: &HttpRequest,
+    ) -> Result<Cookie<'static>, ServerError> {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        let signed =
+            cookie_policy.sign(STATE_COOKIE, &general_purpose::URL_SAFE_NO_PAD.encode(json))?;
+        let value = cookie_policy.seal(STATE_COOKIE, &signed);
+
+        // The callback is a cross-site navigation from the hosted UI, which Strict would drop
+        let same_site = match cookie_policy.same_site {
+            SameSite::Strict => SameSite::Lax,
+            same_site => same_site,
+        };
+
+        Ok(cookie_policy
+            .build(
+                STATE_COOKIE,
+                value,
+                req.headers().get("host"),
+                cookie_policy.is_secure_request(req),
+            )
+            .same_site(same_site)
+            .max_age(Duration::seconds(STATE_COOKIE_LIFETIME))
+            .finish())
+    }
+
+    pub fn from_request(
+        req: &HttpRequest,
+        cookie_policy: &CookiePolicy,
+    ) -> Result<PendingAuthorization, ServerError> {
+        let value = cookie_policy
+            .read(req, STATE_COOKIE)
+            .ok_or(OAuthError::MissingState)?;
+        let value = cookie_policy.open(STATE_COOKIE, &value)?;
+        let value = cookie_policy.verify(STATE_COOKIE, &value)?;
+        let json = general_purpose::URL_SAFE_NO_PAD
+            .decode(value)
+            .map_err(|_| OAuthError::MissingState)?;
+
+        Ok(serde_json::from_slice(&json).map_err(|_| OAuthError::MissingState)?)
+    }
+
+    pub fn check_state(&self, state: &str) -> Result<(), OAuthError> {
+        match constant_time_eq(self.state.as_bytes(), state.as_bytes()) {
+            true => Ok(()),
+            false => Err(OAuthError::StateMismatch),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OAuthErrorResponse {
+    error: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OAuthTokenResponse {
+    pub access_token: String,
+    pub id_token: Option<String>,
+    pub refresh_token: Option<String>,
+    pub expires_in: i32,
+    pub token_type: String,
+}
+
+impl From<OAuthTokenResponse> for AuthenticationResultType {
+    fn from(tokens: OAuthTokenResponse) -> Self {
+        AuthenticationResultType::builder()
+            .access_token(tokens.access_token)
+            .set_id_token(tokens.id_token)
+            .set_refresh_token(tokens.refresh_token)
+            .expires_in(tokens.expires_in)
+            .token_type(tokens.token_type)
+            .build()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OAuthAuthorizeQuery {
+    pub return_to: Option<String>,
+    pub identity_provider: Option<String>,
+    pub login_hint: Option<String>,
+    pub remember_me: Option<String>,
+}
+
+impl OAuthAuthorizeQuery {
+    pub fn remember_me(&self) -> bool {
+        is_checked(self.remember_me.as_deref())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OAuthCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+// Only same-origin paths, so the callback can't be turned into an open redirect. Browsers drop
+// tabs and newlines from URLs and read a backslash as "/", so those are refused outright; the
+// rest is resolved the way a browser would and must not have left the origin.
+pub fn is_local_path(path: &str) -> bool {
+    if !path.starts_with('/') || path.contains('\\') || path.chars().any(|c| c.is_control()) {
+        return false;
+    }
+
+    let base = Url::parse(LOCAL_ORIGIN).expect("LOCAL_ORIGIN is a valid URL");
+    match base.join(path) {
+        Ok(url) => url.origin() == base.origin(),
+        Err(_) => false,
+    }
+}
+
+fn random_string(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::test_support;
+    use actix_web::test::TestRequest;
+
+    fn cookie_policy() -> CookiePolicy {
+        let mut policy = test_support::cookie_policy();
+        policy.signing_keys = vec![b"state key".to_vec()];
+        policy
+    }
+
+    #[test]
+    fn local_paths_are_accepted() {
+        assert!(is_local_path("/"));
+        assert!(is_local_path("/account?tab=1#top"));
+        assert!(is_local_path("/a/../b"));
+        assert!(is_local_path("/%2F%2Fevil.test"));
+    }
+
+    #[test]
+    fn paths_that_leave_the_origin_are_rejected() {
+        for path in [
+            "",
+            "account",
+            "https://evil.test/",
+            "//evil.test",
+            "/\\evil.test",
+            "/\t/evil.test",
+            "/\n/evil.test",
+            "\t//evil.test",
+            "/.//evil.test\u{0}",
+        ] {
+            assert!(!is_local_path(path), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn code_challenge_is_s256_of_the_verifier() {
+        let mut pending = PendingAuthorization::new("https://app/cb".into(), None, false);
+        // The example from RFC 7636 appendix B
+        pending.code_verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".into();
+        assert_eq!(
+            pending.code_challenge(),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn foreign_return_paths_are_dropped() {
+        let pending =
+            PendingAuthorization::new("https://app/cb".into(), Some("//evil.test".into()), false);
+        assert_eq!(pending.return_to, None);
+        assert_ne!(pending.state, pending.nonce);
+        assert!(pending.code_verifier.len() >= 43);
+    }
+
+    #[test]
+    fn state_cookie_round_trips() {
+        let policy = cookie_policy();
+        let pending =
+            PendingAuthorization::new("https://app/cb".into(), Some("/home".into()), true);
+        let cookie = pending
+            .cookie(&policy, &TestRequest::default().to_http_request())
+            .unwrap();
+
+        let req = TestRequest::default().cookie(cookie).to_http_request();
+        let read = PendingAuthorization::from_request(&req, &policy).unwrap();
+
+        assert_eq!(read.return_to.as_deref(), Some("/home"));
+        assert!(read.remember_me);
+        assert!(read.check_state(&pending.state).is_ok());
+        assert!(read.check_state("other").is_err());
+    }
+
+    #[test]
+    fn unsigned_state_cookies_are_rejected() {
+        let policy = cookie_policy();
+        let planted = PendingAuthorization {
+            return_to: Some("//evil.test".into()),
+            ..PendingAuthorization::new("https://app/cb".into(), None, false)
+        };
+        let json = serde_json::to_string(&planted).unwrap();
+        let value = general_purpose::URL_SAFE_NO_PAD.encode(json);
+
+        let req = TestRequest::default()
+            .cookie(Cookie::new(STATE_COOKIE, value))
+            .to_http_request();
+        assert!(PendingAuthorization::from_request(&req, &policy).is_err());
+    }
+
+    #[test]
+    fn state_cookie_needs_a_signing_key() {
+        let mut policy = cookie_policy();
+        policy.signing_keys.clear();
+        let pending = PendingAuthorization::new("https://app/cb".into(), None, false);
+        assert!(pending
+            .cookie(&policy, &TestRequest::default().to_http_request())
+            .is_err());
+    }
+}
@@ -0,0 +1,1061 @@
+use crate::errors::{
+    admin::AdminError, auth::AuthError, organization::OrgError, server::ServerError,
+};
+use crate::operations::authenticated_user::{authenticate, AuthenticatedUser};
+use crate::operations::session_token::{now, random_id};
+use crate::operations::tenant::{shared_config, CurrentTenant, Tenant};
+use crate::operations::user_directory::{CreateUserRequest, UserDirectory, UserSummary};
+use actix_web::{dev::Payload, web, FromRequest, HttpMessage, HttpRequest};
+use aws_config::SdkConfig;
+use aws_sdk_dynamodb::{
+    error::SdkError,
+    types::{AttributeValue, Delete, Put, TransactWriteItem},
+    Client,
+};
+use futures_util::future::LocalBoxFuture;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::HashMap, env};
+
+// The Cognito attribute naming a user's home organization, used when a request names none
+pub const ORG_ID_ATTRIBUTE: &str = "custom:org_id";
+
+// Lets personal access tokens and services act within their owner's organizations
+pub const ORGS_SCOPE: &str = "orgs";
+
+const DEFAULT_USER_INDEX: &str = "user_sub-index";
+const ORG_SORT_KEY: &str = "org";
+const MEMBER_SORT_KEY_PREFIX: &str = "member#";
+const INVITATION_SORT_KEY_PREFIX: &str = "invitation#";
+const INVITATION_TTL: i64 = 7 * 24 * 60 * 60;
+const MAX_NAME_LEN: usize = 100;
+
+// Ordered, so a role includes everything the ones before it may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrgRole {
+    Member,
+    Admin,
+    Owner,
+}
+
+impl OrgRole {
+    fn as_str(&self) -> &'static str {
+        match self {
+            OrgRole::Member => "member",
+            OrgRole::Admin => "admin",
+            OrgRole::Owner => "owner",
+        }
+    }
+
+    fn parse(role: &str) -> Option<OrgRole> {
+        match role {
+            "member" => Some(OrgRole::Member),
+            "admin" => Some(OrgRole::Admin),
+            "owner" => Some(OrgRole::Owner),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Organization {
+    pub id: String,
+    pub name: String,
+    #[serde(skip)]
+    pub tenant: String,
+    pub created_at: i64,
+    pub created_by: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Membership {
+    pub org_id: String,
+    pub user_sub: String,
+    pub username: Option<String>,
+    pub role: OrgRole,
+    #[serde(skip)]
+    pub tenant: String,
+    pub joined_at: i64,
+    pub invited_by: Option<String>,
+}
+
+// Membership on offer until the invitee accepts it, keyed on their sub so an invitation can only
+// be taken up by the account it was made for
+#[derive(Debug, Clone, Serialize)]
+pub struct Invitation {
+    pub org_id: String,
+    pub user_sub: String,
+    pub username: String,
+    pub email: String,
+    pub role: OrgRole,
+    #[serde(skip)]
+    pub tenant: String,
+    pub invited_by: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateOrgRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InviteMemberRequest {
+    pub org_id: Option<String>,
+    pub email: String,
+    #[serde(default = "member_role")]
+    pub role: OrgRole,
+}
+
+fn member_role() -> OrgRole {
+    OrgRole::Member
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateMemberRequest {
+    pub org_id: Option<String>,
+    pub role: OrgRole,
+}
+
+// Bodies that name an organization, which must be the one the request is authorized for
+pub trait OrgScoped {
+    fn org_id(&self) -> Option<&str>;
+}
+
+impl OrgScoped for InviteMemberRequest {
+    fn org_id(&self) -> Option<&str> {
+        self.org_id.as_deref()
+    }
+}
+
+impl OrgScoped for UpdateMemberRequest {
+    fn org_id(&self) -> Option<&str> {
+        self.org_id.as_deref()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Organizations {
+    table: Option<String>,
+    user_index: String,
+    account_orgs: Vec<String>,
+}
+
+impl Organizations {
+    // ORG_TABLE is keyed on "org_id" and "sk", holding each organization, its memberships and
+    // its pending invitations (expiring by TTL on "expires_at"), with an ORG_USER_INDEX on
+    // "user_sub" to find a user's memberships and invitations. ORG_ACCOUNT_ORGS lists, comma
+    // separated, the organizations whose admins may invite people without an account yet.
+    pub fn from_env() -> Organizations {
+        Organizations {
+            table: env::var("ORG_TABLE").ok(),
+            user_index: env::var("ORG_USER_INDEX").unwrap_or_else(|_| DEFAULT_USER_INDEX.into()),
+            account_orgs: env::var("ORG_ACCOUNT_ORGS")
+                .map(|orgs| {
+                    orgs.split(',')
+                        .map(|org| org.trim().to_string())
+                        .filter(|org| !org.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    // The organization and its first owner are written together, so there is never one
+    // without an owner
+    pub async fn create(
+        &self,
+        config: &SdkConfig,
+        tenant: &str,
+        owner: &AuthenticatedUser,
+        name: &str,
+    ) -> Result<(Organization, Membership), ServerError> {
+        let table = self.table()?;
+
+        let name = name.trim();
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            return Err(AuthError::InvalidRequest(format!(
+                "name must be 1 to {} characters",
+                MAX_NAME_LEN
+            ))
+            .into());
+        }
+
+        let created_at = now();
+        let org = Organization {
+            id: random_id(),
+            name: name.into(),
+            tenant: tenant.into(),
+            created_at,
+            created_by: owner.sub.clone(),
+        };
+        let membership = Membership {
+            org_id: org.id.clone(),
+            user_sub: owner.sub.clone(),
+            username: owner.username.clone(),
+            role: OrgRole::Owner,
+            tenant: tenant.into(),
+            joined_at: created_at,
+            invited_by: None,
+        };
+
+        let put = |item| {
+            TransactWriteItem::builder()
+                .put(
+                    Put::builder()
+                        .table_name(table)
+                        .set_item(Some(item))
+                        .condition_expression("attribute_not_exists(sk)")
+                        .build(),
+                )
+                .build()
+        };
+
+        Client::new(config)
+            .transact_write_items()
+            .transact_items(put(org_to_item(&org)))
+            .transact_items(put(membership_to_item(&membership)))
+            .send()
+            .await?;
+
+        Ok((org, membership))
+    }
+
+    pub async fn get(
+        &self,
+        config: &SdkConfig,
+        org_id: &str,
+    ) -> Result<Option<Organization>, ServerError> {
+        let output = Client::new(config)
+            .get_item()
+            .table_name(self.table()?)
+            .key("org_id", AttributeValue::S(org_id.into()))
+            .key("sk", AttributeValue::S(ORG_SORT_KEY.into()))
+            .send()
+            .await?;
+
+        Ok(output.item().and_then(to_org))
+    }
+
+    // Consistent, so a removed member is locked out straight away
+    pub async fn membership(
+        &self,
+        config: &SdkConfig,
+        org_id: &str,
+        user_sub: &str,
+    ) -> Result<Option<Membership>, ServerError> {
+        let output = Client::new(config)
+            .get_item()
+            .table_name(self.table()?)
+            .key("org_id", AttributeValue::S(org_id.into()))
+            .key("sk", AttributeValue::S(member_sort_key(user_sub)))
+            .consistent_read(true)
+            .send()
+            .await?;
+
+        Ok(output.item().and_then(to_membership))
+    }
+
+    pub async fn members(
+        &self,
+        config: &SdkConfig,
+        org_id: &str,
+    ) -> Result<Vec<Membership>, ServerError> {
+        self.query(
+            config,
+            None,
+            "org_id = :key AND begins_with(sk, :prefix)",
+            HashMap::from([
+                (":key".into(), AttributeValue::S(org_id.into())),
+                (
+                    ":prefix".into(),
+                    AttributeValue::S(MEMBER_SORT_KEY_PREFIX.into()),
+                ),
+            ]),
+        )
+        .await
+    }
+
+    pub async fn memberships_for_user(
+        &self,
+        config: &SdkConfig,
+        tenant: &str,
+        user_sub: &str,
+    ) -> Result<Vec<Membership>, ServerError> {
+        let mut memberships = self
+            .query(
+                config,
+                Some(&self.user_index),
+                "user_sub = :key",
+                HashMap::from([(":key".into(), AttributeValue::S(user_sub.into()))]),
+            )
+            .await?;
+
+        memberships.retain(|membership| membership.tenant == tenant);
+        Ok(memberships)
+    }
+
+    pub async fn set_role(
+        &self,
+        config: &SdkConfig,
+        org_id: &str,
+        user_sub: &str,
+        role: OrgRole,
+    ) -> Result<(), ServerError> {
+        let result = Client::new(config)
+            .update_item()
+            .table_name(self.table()?)
+            .key("org_id", AttributeValue::S(org_id.into()))
+            .key("sk", AttributeValue::S(member_sort_key(user_sub)))
+            .update_expression("SET #role = :role")
+            .condition_expression("attribute_exists(sk)")
+            .expression_attribute_names("#role", "role")
+            .expression_attribute_values(":role", AttributeValue::S(role.as_str().into()))
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(SdkError::ServiceError(e)) if e.err().is_conditional_check_failed_exception() => {
+                Err(OrgError::MemberNotFound.into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn remove_member(
+        &self,
+        config: &SdkConfig,
+        org_id: &str,
+        user_sub: &str,
+    ) -> Result<(), ServerError> {
+        let result = Client::new(config)
+            .delete_item()
+            .table_name(self.table()?)
+            .key("org_id", AttributeValue::S(org_id.into()))
+            .key("sk", AttributeValue::S(member_sort_key(user_sub)))
+            .condition_expression("attribute_exists(sk)")
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(SdkError::ServiceError(e)) if e.err().is_conditional_check_failed_exception() => {
+                Err(OrgError::MemberNotFound.into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Creating an account sends Cognito's invite email to any address, so it is for the
+    // tenant's admins and allow-listed organizations; everyone else only invites existing users
+    pub fn may_create_accounts(&self, tenant: &Tenant, inviter: &OrgMember) -> bool {
+        inviter.user.require_admin(tenant).is_ok()
+            || self.account_orgs.contains(&inviter.membership.org_id)
+    }
+
+    // Anyone not yet in the tenant's pool gets a Cognito account and its invite email, where
+    // the inviter may create one. Nobody joins until they accept, so an invitation can't put a
+    // user in an organization they never agreed to, nor change their home one.
+    pub async fn invite(
+        &self,
+        config: &SdkConfig,
+        directory: &UserDirectory,
+        tenant: &Tenant,
+        inviter: &OrgMember,
+        request: InviteMemberRequest,
+    ) -> Result<Invitation, ServerError> {
+        inviter.require_role(OrgRole::Admin)?;
+        // Only owners make owners
+        if request.role == OrgRole::Owner {
+            inviter.require_role(OrgRole::Owner)?;
+        }
+
+        let org_id = &inviter.membership.org_id;
+        let account = match directory.find_by_email(&request.email).await? {
+            Some(account) => account,
+            None if !self.may_create_accounts(tenant, inviter) => {
+                return Err(OrgError::InviteeNotFound.into())
+            }
+            None => {
+                directory
+                    .create(CreateUserRequest {
+                        email: request.email.clone(),
+                        attributes: HashMap::new(),
+                        temporary_password: None,
+                        send_invite: true,
+                    })
+                    .await?
+            }
+        };
+        let user_sub = match account.attributes.get("sub") {
+            Some(sub) => sub.clone(),
+            None => directory
+                .get(&account.username)
+                .await?
+                .attributes
+                .remove("sub")
+                .ok_or(AdminError::UserNotFound)?,
+        };
+
+        if self.membership(config, org_id, &user_sub).await?.is_some() {
+            return Err(OrgError::MemberExists.into());
+        }
+
+        let created_at = now();
+        let invitation = Invitation {
+            org_id: org_id.clone(),
+            user_sub,
+            username: account.username,
+            email: request.email,
+            role: request.role,
+            tenant: inviter.membership.tenant.clone(),
+            invited_by: inviter.user.sub.clone(),
+            created_at,
+            expires_at: created_at + INVITATION_TTL,
+        };
+        // Inviting again replaces the earlier invitation and starts its clock over
+        Client::new(config)
+            .put_item()
+            .table_name(self.table()?)
+            .set_item(Some(invitation_to_item(&invitation)))
+            .send()
+            .await?;
+
+        Ok(invitation)
+    }
+
+    pub async fn invitations_for_user(
+        &self,
+        config: &SdkConfig,
+        tenant: &str,
+        user_sub: &str,
+    ) -> Result<Vec<Invitation>, ServerError> {
+        let now = now();
+        let mut invitations: Vec<Invitation> = self
+            .query_items(
+                config,
+                Some(&self.user_index),
+                "user_sub = :key",
+                HashMap::from([(":key".into(), AttributeValue::S(user_sub.into()))]),
+            )
+            .await?
+            .iter()
+            .filter_map(to_invitation)
+            .filter(|invitation| invitation.tenant == tenant && invitation.expires_at > now)
+            .collect();
+
+        invitations.sort_by_key(|invitation| invitation.created_at);
+        Ok(invitations)
+    }
+
+    // The invitation goes and the membership arrives in one transaction, so accepting twice
+    // or after the invitation was replaced does nothing
+    pub async fn accept(
+        &self,
+        config: &SdkConfig,
+        directory: &UserDirectory,
+        tenant: &str,
+        user: &AuthenticatedUser,
+        org_id: &str,
+    ) -> Result<Membership, ServerError> {
+        let table = self.table()?;
+
+        let output = Client::new(config)
+            .get_item()
+            .table_name(table)
+            .key("org_id", AttributeValue::S(org_id.into()))
+            .key("sk", AttributeValue::S(invitation_sort_key(&user.sub)))
+            .consistent_read(true)
+            .send()
+            .await?;
+        let invitation = output
+            .item()
+            .and_then(to_invitation)
+            .filter(|invitation| invitation.tenant == tenant && invitation.expires_at > now())
+            .ok_or(OrgError::InvitationNotFound)?;
+
+        let membership = Membership {
+            org_id: invitation.org_id.clone(),
+            user_sub: invitation.user_sub.clone(),
+            username: Some(invitation.username.clone()),
+            role: invitation.role,
+            tenant: invitation.tenant.clone(),
+            joined_at: now(),
+            invited_by: Some(invitation.invited_by.clone()),
+        };
+
+        let result = Client::new(config)
+            .transact_write_items()
+            .transact_items(
+                TransactWriteItem::builder()
+                    .delete(
+                        Delete::builder()
+                            .table_name(table)
+                            .key("org_id", AttributeValue::S(org_id.into()))
+                            .key("sk", AttributeValue::S(invitation_sort_key(&user.sub)))
+                            .condition_expression("created_at = :created_at")
+                            .expression_attribute_values(
+                                ":created_at",
+                                AttributeValue::N(invitation.created_at.to_string()),
+                            )
+                            .build(),
+                    )
+                    .build(),
+            )
+            .transact_items(
+                TransactWriteItem::builder()
+                    .put(
+                        Put::builder()
+                            .table_name(table)
+                            .set_item(Some(membership_to_item(&membership)))
+                            .condition_expression("attribute_not_exists(sk)")
+                            .build(),
+                    )
+                    .build(),
+            )
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => {}
+            Err(SdkError::ServiceError(e)) if e.err().is_transaction_canceled_exception() => {
+                return Err(OrgError::InvitationNotFound.into())
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let account = directory.get(&invitation.username).await?;
+        set_home_org(directory, &account, org_id).await?;
+
+        Ok(membership)
+    }
+
+    pub async fn update_member(
+        &self,
+        config: &SdkConfig,
+        updater: &OrgMember,
+        user_sub: &str,
+        role: OrgRole,
+    ) -> Result<(), ServerError> {
+        updater.require_role(OrgRole::Admin)?;
+
+        let org_id = &updater.membership.org_id;
+        let target = self
+            .membership(config, org_id, user_sub)
+            .await?
+            .ok_or(OrgError::MemberNotFound)?;
+        if target.role == OrgRole::Owner || role == OrgRole::Owner {
+            updater.require_role(OrgRole::Owner)?;
+        }
+        if target.role == OrgRole::Owner && role != OrgRole::Owner {
+            self.ensure_other_owner(config, org_id, user_sub).await?;
+        }
+
+        self.set_role(config, org_id, user_sub, role).await
+    }
+
+    // Members may always leave; removing anyone else takes an admin, and an owner an owner
+    pub async fn remove(
+        &self,
+        config: &SdkConfig,
+        directory: &UserDirectory,
+        remover: &OrgMember,
+        user_sub: &str,
+    ) -> Result<(), ServerError> {
+        if user_sub != remover.user.sub {
+            remover.require_role(OrgRole::Admin)?;
+        }
+
+        let org_id = &remover.membership.org_id;
+        let target = self
+            .membership(config, org_id, user_sub)
+            .await?
+            .ok_or(OrgError::MemberNotFound)?;
+        if target.role == OrgRole::Owner {
+            remover.require_role(OrgRole::Owner)?;
+            self.ensure_other_owner(config, org_id, user_sub).await?;
+        }
+
+        // Looked up first, so a Cognito failure leaves the membership in place to retry. A
+        // user deleted from the pool has no home organization left to clear.
+        let account = match directory
+            .get(target.username.as_deref().unwrap_or(user_sub))
+            .await
+        {
+            Ok(account) => Some(account),
+            Err(e) if e.status_code == 404 => None,
+            Err(e) => return Err(e),
+        };
+
+        self.remove_member(config, org_id, user_sub).await?;
+
+        // Tokens issued from now on stop pointing the user at an organization they left
+        if let Some(account) =
+            account.filter(|account| account.attributes.get(ORG_ID_ATTRIBUTE) == Some(org_id))
+        {
+            directory
+                .delete_attribute(&account.username, ORG_ID_ATTRIBUTE)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn ensure_other_owner(
+        &self,
+        config: &SdkConfig,
+        org_id: &str,
+        user_sub: &str,
+    ) -> Result<(), ServerError> {
+        let members = self.members(config, org_id).await?;
+        match members
+            .iter()
+            .any(|m| m.role == OrgRole::Owner && m.user_sub != user_sub)
+        {
+            true => Ok(()),
+            false => Err(OrgError::LastOwner.into()),
+        }
+    }
+
+    async fn query(
+        &self,
+        config: &SdkConfig,
+        index: Option<&str>,
+        key_condition: &str,
+        values: HashMap<String, AttributeValue>,
+    ) -> Result<Vec<Membership>, ServerError> {
+        let mut memberships: Vec<Membership> = self
+            .query_items(config, index, key_condition, values)
+            .await?
+            .iter()
+            .filter_map(to_membership)
+            .collect();
+
+        memberships.sort_by_key(|membership| membership.joined_at);
+        Ok(memberships)
+    }
+
+    async fn query_items(
+        &self,
+        config: &SdkConfig,
+        index: Option<&str>,
+        key_condition: &str,
+        values: HashMap<String, AttributeValue>,
+    ) -> Result<Vec<HashMap<String, AttributeValue>>, ServerError> {
+        let table = self.table()?;
+        let client = Client::new(config);
+
+        let mut items = vec![];
+        let mut start_key = None;
+        loop {
+            let output = client
+                .query()
+                .table_name(table)
+                .set_index_name(index.map(|index| index.into()))
+                .key_condition_expression(key_condition)
+                .set_expression_attribute_values(Some(values.clone()))
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await?;
+
+            items.extend(output.items().unwrap_or_default().iter().cloned());
+
+            start_key = output.last_evaluated_key().cloned();
+            if start_key.is_none() {
+                break;
+            }
+        }
+
+        Ok(items)
+    }
+
+    fn table(&self) -> Result<&str, AuthError> {
+        self.table
+            .as_deref()
+            .ok_or(AuthError::NotConfigured("ORG_TABLE"))
+    }
+}
+
+// The caller's membership of the organization in the path, or of their home organization
+// when the route names none. Anyone else is turned away before the handler runs.
+#[derive(Debug, Clone)]
+pub struct OrgMember {
+    pub user: AuthenticatedUser,
+    pub membership: Membership,
+}
+
+impl OrgMember {
+    pub fn require_role(&self, role: OrgRole) -> Result<(), OrgError> {
+        match self.membership.role >= role {
+            true => Ok(()),
+            false => Err(OrgError::RoleRequired(role.as_str().into())),
+        }
+    }
+
+    async fn extract(req: &HttpRequest) -> Result<OrgMember, ServerError> {
+        let cached = req.extensions().get::<OrgMember>().cloned();
+        if let Some(member) = cached {
+            return Ok(member);
+        }
+
+        let user = authenticate(req).await?;
+        user.require_scope(ORGS_SCOPE)?;
+
+        let org_id = match req.match_info().get("org_id") {
+            Some(org_id) => org_id.to_string(),
+            None => user
+                .claim(ORG_ID_ATTRIBUTE)
+                .ok_or_else(|| AuthError::InvalidRequest("no organization".into()))?,
+        };
+
+        let tenant = CurrentTenant::get(req)?;
+        let organizations = req
+            .app_data::<web::Data<Organizations>>()
+            .ok_or(AuthError::NotConfigured("ORG_TABLE"))?;
+        let config = shared_config(req).await;
+
+        // Memberships are per tenant too, so the same sub in another pool gets nowhere
+        let membership = organizations
+            .membership(&config, &org_id, &user.sub)
+            .await?
+            .filter(|membership| membership.tenant == tenant.id)
+            .ok_or(OrgError::NotAMember)?;
+
+        let member = OrgMember { user, membership };
+        req.extensions_mut().insert(member.clone());
+        Ok(member)
+    }
+}
+
+impl FromRequest for OrgMember {
+    type Error = ServerError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { OrgMember::extract(&req).await })
+    }
+}
+
+// A JSON body whose org_id, when it has one, must be the organization the caller was let into
+#[derive(Debug)]
+pub struct OrgJson<T>(pub T);
+
+impl<T: DeserializeOwned + OrgScoped + 'static> FromRequest for OrgJson<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let json = web::Json::<T>::from_request(&req, payload);
+
+        Box::pin(async move {
+            let member = OrgMember::extract(&req).await?;
+            let body = json.await?.into_inner();
+
+            if body
+                .org_id()
+                .is_some_and(|org_id| org_id != member.membership.org_id)
+            {
+                return Err(ServerError::from(OrgError::Mismatch).into());
+            }
+
+            Ok(OrgJson(body))
+        })
+    }
+}
+
+// A user's first organization becomes their home one, carried in their tokens as custom:org_id
+pub async fn set_home_org(
+    directory: &UserDirectory,
+    account: &UserSummary,
+    org_id: &str,
+) -> Result<(), ServerError> {
+    if account.attributes.contains_key(ORG_ID_ATTRIBUTE) {
+        return Ok(());
+    }
+
+    directory
+        .set_attribute(&account.username, ORG_ID_ATTRIBUTE, org_id)
+        .await
+}
+
+fn member_sort_key(user_sub: &str) -> String {
+    format!("{}{}", MEMBER_SORT_KEY_PREFIX, user_sub)
+}
+
+fn invitation_sort_key(user_sub: &str) -> String {
+    format!("{}{}", INVITATION_SORT_KEY_PREFIX, user_sub)
+}
+
+fn org_to_item(org: &Organization) -> HashMap<String, AttributeValue> {
+    HashMap::from([
+        ("org_id".into(), AttributeValue::S(org.id.clone())),
+        ("sk".into(), AttributeValue::S(ORG_SORT_KEY.into())),
+        ("name".into(), AttributeValue::S(org.name.clone())),
+        ("tenant".into(), AttributeValue::S(org.tenant.clone())),
+        (
+            "created_at".into(),
+            AttributeValue::N(org.created_at.to_string()),
+        ),
+        (
+            "created_by".into(),
+            AttributeValue::S(org.created_by.clone()),
+        ),
+    ])
+}
+
+fn to_org(item: &HashMap<String, AttributeValue>) -> Option<Organization> {
+    Some(Organization {
+        id: item.get("org_id")?.as_s().ok()?.clone(),
+        name: item.get("name")?.as_s().ok()?.clone(),
+        tenant: item.get("tenant")?.as_s().ok()?.clone(),
+        created_at: item.get("created_at")?.as_n().ok()?.parse().ok()?,
+        created_by: item.get("created_by")?.as_s().ok()?.clone(),
+    })
+}
+
+fn membership_to_item(membership: &Membership) -> HashMap<String, AttributeValue> {
+    let mut item = HashMap::from([
+        (
+            "org_id".into(),
+            AttributeValue::S(membership.org_id.clone()),
+        ),
+        (
+            "sk".into(),
+            AttributeValue::S(member_sort_key(&membership.user_sub)),
+        ),
+        (
+            "user_sub".into(),
+            AttributeValue::S(membership.user_sub.clone()),
+        ),
+        (
+            "role".into(),
+            AttributeValue::S(membership.role.as_str().into()),
+        ),
+        (
+            "tenant".into(),
+            AttributeValue::S(membership.tenant.clone()),
+        ),
+        (
+            "joined_at".into(),
+            AttributeValue::N(membership.joined_at.to_string()),
+        ),
+    ]);
+    if let Some(username) = &membership.username {
+        item.insert("username".into(), AttributeValue::S(username.clone()));
+    }
+    if let Some(invited_by) = &membership.invited_by {
+        item.insert("invited_by".into(), AttributeValue::S(invited_by.clone()));
+    }
+    item
+}
+
+fn to_membership(item: &HashMap<String, AttributeValue>) -> Option<Membership> {
+    let string = |name: &str| item.get(name).and_then(|v| v.as_s().ok()).cloned();
+    // The user index finds a user's invitations too
+    if !string("sk")?.starts_with(MEMBER_SORT_KEY_PREFIX) {
+        return None;
+    }
+
+    Some(Membership {
+        org_id: string("org_id")?,
+        user_sub: string("user_sub")?,
+        username: string("username"),
+        role: OrgRole::parse(&string("role")?)?,
+        tenant: string("tenant")?,
+        joined_at: item.get("joined_at")?.as_n().ok()?.parse().ok()?,
+        invited_by: string("invited_by"),
+    })
+}
+
+fn invitation_to_item(invitation: &Invitation) -> HashMap<String, AttributeValue> {
+    HashMap::from([
+        (
+            "org_id".into(),
+            AttributeValue::S(invitation.org_id.clone()),
+        ),
+        (
+            "sk".into(),
+            AttributeValue::S(invitation_sort_key(&invitation.user_sub)),
+        ),
+        (
+            "user_sub".into(),
+            AttributeValue::S(invitation.user_sub.clone()),
+        ),
+        (
+            "username".into(),
+            AttributeValue::S(invitation.username.clone()),
+        ),
+        ("email".into(), AttributeValue::S(invitation.email.clone())),
+        (
+            "role".into(),
+            AttributeValue::S(invitation.role.as_str().into()),
+        ),
+        (
+            "tenant".into(),
+            AttributeValue::S(invitation.tenant.clone()),
+        ),
+        (
+            "invited_by".into(),
+            AttributeValue::S(invitation.invited_by.clone()),
+        ),
+        (
+            "created_at".into(),
+            AttributeValue::N(invitation.created_at.to_string()),
+        ),
+        (
+            "expires_at".into(),
+            AttributeValue::N(invitation.expires_at.to_string()),
+        ),
+    ])
+}
+
+fn to_invitation(item: &HashMap<String, AttributeValue>) -> Option<Invitation> {
+    let string = |name: &str| item.get(name).and_then(|v| v.as_s().ok()).cloned();
+    let number = |name: &str| item.get(name)?.as_n().ok()?.parse().ok();
+    if !string("sk")?.starts_with(INVITATION_SORT_KEY_PREFIX) {
+        return None;
+    }
+
+    Some(Invitation {
+        org_id: string("org_id")?,
+        user_sub: string("user_sub")?,
+        username: string("username")?,
+        email: string("email")?,
+        role: OrgRole::parse(&string("role")?)?,
+        tenant: string("tenant")?,
+        invited_by: string("invited_by")?,
+        created_at: number("created_at")?,
+        expires_at: number("expires_at")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::test_support::{tenant, user};
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    fn invitation() -> Invitation {
+        Invitation {
+            org_id: "org".into(),
+            user_sub: "sub".into(),
+            username: "user".into(),
+            email: "user@example.com".into(),
+            role: OrgRole::Admin,
+            tenant: "acme".into(),
+            invited_by: "owner".into(),
+            created_at: 100,
+            expires_at: 100 + INVITATION_TTL,
+        }
+    }
+
+    #[test]
+    fn invitation_round_trips() {
+        let item = invitation_to_item(&invitation());
+        let parsed = to_invitation(&item).unwrap();
+        assert_eq!(parsed.org_id, "org");
+        assert_eq!(parsed.user_sub, "sub");
+        assert_eq!(parsed.role, OrgRole::Admin);
+        assert_eq!(parsed.tenant, "acme");
+        assert_eq!(parsed.expires_at, 100 + INVITATION_TTL);
+    }
+
+    // Both live under the user index, and an invitation must never read as a membership
+    #[test]
+    fn invitations_and_memberships_are_told_apart() {
+        let invitation = invitation_to_item(&invitation());
+        assert!(to_membership(&invitation).is_none());
+
+        let membership = membership_to_item(&Membership {
+            org_id: "org".into(),
+            user_sub: "sub".into(),
+            username: None,
+            role: OrgRole::Member,
+            tenant: "acme".into(),
+            joined_at: 100,
+            invited_by: None,
+        });
+        assert!(to_invitation(&membership).is_none());
+        assert!(to_membership(&membership).is_some());
+    }
+
+    fn member(role: OrgRole, groups: &[&str]) -> OrgMember {
+        OrgMember {
+            user: user(groups, &[ORGS_SCOPE]),
+            membership: Membership {
+                org_id: "org".into(),
+                user_sub: "sub-1".into(),
+                username: Some("alice".into()),
+                role,
+                tenant: "acme".into(),
+                joined_at: 100,
+                invited_by: None,
+            },
+        }
+    }
+
+    #[test]
+    fn roles_include_the_ones_below() {
+        let admin = member(OrgRole::Admin, &[]);
+        assert!(admin.require_role(OrgRole::Member).is_ok());
+        assert!(admin.require_role(OrgRole::Admin).is_ok());
+        assert_eq!(
+            admin.require_role(OrgRole::Owner),
+            Err(OrgError::RoleRequired("owner".into()))
+        );
+
+        assert!(member(OrgRole::Owner, &[])
+            .require_role(OrgRole::Admin)
+            .is_ok());
+        assert!(member(OrgRole::Member, &[])
+            .require_role(OrgRole::Admin)
+            .is_err());
+    }
+
+    #[test]
+    fn only_tenant_admins_and_listed_orgs_create_accounts() {
+        let mut organizations = Organizations {
+            table: None,
+            user_index: DEFAULT_USER_INDEX.into(),
+            account_orgs: vec![],
+        };
+        let acme = tenant(Some("admins"));
+
+        assert!(!organizations.may_create_accounts(&acme, &member(OrgRole::Owner, &[])));
+        assert!(organizations.may_create_accounts(&acme, &member(OrgRole::Admin, &["admins"])));
+        // Without an admin group nobody is a tenant admin
+        assert!(
+            !organizations.may_create_accounts(&tenant(None), &member(OrgRole::Owner, &["admins"]))
+        );
+
+        organizations.account_orgs = vec!["org".into()];
+        assert!(organizations.may_create_accounts(&acme, &member(OrgRole::Admin, &[])));
+    }
+
+    async fn org_json(
+        body: serde_json::Value,
+    ) -> Result<OrgJson<InviteMemberRequest>, actix_web::Error> {
+        let (req, mut payload) = TestRequest::post().set_json(body).to_http_parts();
+        req.extensions_mut().insert(member(OrgRole::Admin, &[]));
+        OrgJson::<InviteMemberRequest>::from_request(&req, &mut payload).await
+    }
+
+    #[actix_rt::test]
+    async fn body_must_name_the_callers_organization() {
+        let OrgJson(body) = org_json(json!({"email": "bob@acme.com"})).await.unwrap();
+        assert_eq!(body.role, OrgRole::Member);
+        assert!(org_json(json!({"org_id": "org", "email": "bob@acme.com"}))
+            .await
+            .is_ok());
+
+        let err = org_json(json!({"org_id": "other", "email": "bob@acme.com"}))
+            .await
+            .unwrap_err();
+        assert_eq!(err.error_response().status(), 403);
+    }
+}
@@ -0,0 +1,549 @@
+use crate::errors::{auth::AuthError, server::ServerError};
+use crate::operations::auth::HmacSha256;
+use crate::operations::csrf::constant_time_eq;
+use crate::operations::session_token::{now, random_id};
+use crate::operations::tenant::Tenant;
+use aws_config::SdkConfig;
+use aws_sdk_dynamodb::{error::SdkError, types::AttributeValue, Client};
+use base64::{engine::general_purpose, Engine};
+use hmac::Mac;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, collections::HashMap, env};
+
+// Lets secret scanners recognise a leaked token by its shape alone
+pub const TOKEN_PREFIX: &str = "hfp_";
+
+// Lets a token list and revoke its owner's tokens, e.g. for a script that rotates itself
+pub const TOKENS_SCOPE: &str = "tokens";
+
+const DEFAULT_USER_INDEX: &str = "user_sub-index";
+const DEFAULT_LIFETIME_DAYS: i64 = 90;
+const DEFAULT_MAX_LIFETIME_DAYS: i64 = 365;
+const MAX_NAME_LEN: usize = 100;
+
+// Writing last_used_at on every request would make a busy script a hot partition
+const LAST_USED_GRANULARITY: i64 = 60;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PersonalAccessToken {
+    pub id: String,
+    #[serde(skip)]
+    pub user_sub: String,
+    // Tokens from before tenants existed have none and are accepted by the default tenant
+    #[serde(skip)]
+    pub tenant: Option<String>,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateTokenRequest {
+    pub name: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    pub expires_in_days: Option<i64>,
+}
+
+// The only time the token itself is ever returned
+#[derive(Debug, Serialize)]
+pub struct CreatedToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub details: PersonalAccessToken,
+}
+
+#[derive(Debug, Clone)]
+pub struct PersonalAccessTokens {
+    table: Option<String>,
+    user_index: String,
+    hash_key: Option<Vec<u8>>,
+    allowed_scopes: Vec<String>,
+    max_lifetime_days: i64,
+}
+
+impl PersonalAccessTokens {
+    // PAT_TABLE is keyed on "id" with a PAT_USER_INDEX on "user_sub"; only an HMAC of each
+    // token keyed with PAT_HASH_KEY is stored. PAT_ALLOWED_SCOPES limits what can be requested.
+    pub fn from_env() -> PersonalAccessTokens {
+        PersonalAccessTokens {
+            table: env::var("PAT_TABLE").ok(),
+            user_index: env::var("PAT_USER_INDEX").unwrap_or_else(|_| DEFAULT_USER_INDEX.into()),
+            hash_key: env::var("PAT_HASH_KEY").ok().map(|key| key.into_bytes()),
+            allowed_scopes: env::var("PAT_ALLOWED_SCOPES")
+                .unwrap_or_default()
+                .split(',')
+                .map(|scope| scope.trim().to_string())
+                .filter(|scope| !scope.is_empty())
+                .collect(),
+            max_lifetime_days: env::var("PAT_MAX_LIFETIME_DAYS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_LIFETIME_DAYS),
+        }
+    }
+
+    pub fn is_token(token: &str) -> bool {
+        token.starts_with(TOKEN_PREFIX)
+    }
+
+    pub async fn create(
+        &self,
+        config: &SdkConfig,
+        tenant: &str,
+        user_sub: &str,
+        request: CreateTokenRequest,
+    ) -> Result<CreatedToken, ServerError> {
+        let table = self.table()?;
+        let (name, lifetime_days) = self.check_request(&request)?;
+
+        let id = random_id();
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let token = format!(
+            "{}{}_{}",
+            TOKEN_PREFIX,
+            id,
+            general_purpose::URL_SAFE_NO_PAD.encode(secret)
+        );
+
+        let created_at = now();
+        let details = PersonalAccessToken {
+            id,
+            user_sub: user_sub.into(),
+            tenant: Some(tenant.into()),
+            name,
+            scopes: request.scopes,
+            created_at,
+            expires_at: created_at + lifetime_days * 24 * 60 * 60,
+            last_used_at: None,
+        };
+
+        Client::new(config)
+            .put_item()
+            .table_name(table)
+            .set_item(Some(to_item(&details, self.digest(&token)?)))
+            .condition_expression("attribute_not_exists(id)")
+            .send()
+            .await?;
+
+        Ok(CreatedToken { token, details })
+    }
+
+    // Only the tokens issued in the tenant; the same user may hold others elsewhere
+    pub async fn list(
+        &self,
+        config: &SdkConfig,
+        tenant: &Tenant,
+        user_sub: &str,
+    ) -> Result<Vec<PersonalAccessToken>, ServerError> {
+        let table = self.table()?;
+        let client = Client::new(config);
+
+        let mut tokens = vec![];
+        let mut start_key = None;
+        loop {
+            let output = client
+                .query()
+                .table_name(table)
+                .index_name(&self.user_index)
+                .key_condition_expression("user_sub = :sub")
+                .expression_attribute_values(":sub", AttributeValue::S(user_sub.into()))
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await?;
+
+            tokens.extend(
+                output
+                    .items()
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(to_token)
+                    .filter(|token| issued_in(token, &tenant.id, tenant.is_default)),
+            );
+
+            start_key = output.last_evaluated_key().cloned();
+            if start_key.is_none() {
+                break;
+            }
+        }
+
+        tokens.sort_by_key(|token| Reverse(token.created_at));
+        Ok(tokens)
+    }
+
+    // The conditions stop anyone revoking a token that isn't theirs, or one from another tenant
+    pub async fn revoke(
+        &self,
+        config: &SdkConfig,
+        tenant: &Tenant,
+        user_sub: &str,
+        id: &str,
+    ) -> Result<(), ServerError> {
+        let table = self.table()?;
+
+        let condition = match tenant.is_default {
+            true => "user_sub = :sub AND (tenant = :tenant OR attribute_not_exists(tenant))",
+            false => "user_sub = :sub AND tenant = :tenant",
+        };
+        let result = Client::new(config)
+            .delete_item()
+            .table_name(table)
+            .key("id", AttributeValue::S(id.into()))
+            .condition_expression(condition)
+            .expression_attribute_values(":sub", AttributeValue::S(user_sub.into()))
+            .expression_attribute_values(":tenant", AttributeValue::S(tenant.id.clone()))
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(SdkError::ServiceError(e)) if e.err().is_conditional_check_failed_exception() => {
+                Err(AuthError::TokenNotFound.into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Every token the user holds in the tenant, for when their account is disabled or deleted
+    pub async fn revoke_all(
+        &self,
+        config: &SdkConfig,
+        tenant: &Tenant,
+        user_sub: &str,
+    ) -> Result<(), ServerError> {
+        if self.table.is_none() {
+            return Ok(());
+        }
+
+        for token in self.list(config, tenant, user_sub).await? {
+            match self.revoke(config, tenant, user_sub, &token.id).await {
+                // Revoked by its owner in the meantime
+                Err(e) if e.status_code == 404 => {}
+                result => result?,
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn authenticate(
+        &self,
+        config: &SdkConfig,
+        tenant: &Tenant,
+        token: &str,
+    ) -> Result<PersonalAccessToken, ServerError> {
+        let table = self.table()?;
+        let invalid = || AuthError::InvalidToken("unknown personal access token".into());
+
+        let id = token_id(token).ok_or_else(invalid)?;
+
+        let client = Client::new(config);
+        let output = client
+            .get_item()
+            .table_name(table)
+            .key("id", AttributeValue::S(id.into()))
+            .consistent_read(true)
+            .send()
+            .await?;
+
+        let item = output.item().ok_or_else(invalid)?;
+        let stored = item
+            .get("digest")
+            .and_then(|v| v.as_s().ok())
+            .ok_or_else(invalid)?;
+        if !constant_time_eq(stored.as_bytes(), self.digest(token)?.as_bytes()) {
+            return Err(invalid().into());
+        }
+
+        let details = to_token(item).ok_or_else(invalid)?;
+        let now = now();
+        check_usable(&details, &tenant.id, tenant.is_default, now)?;
+
+        if details
+            .last_used_at
+            .is_none_or(|last_used_at| now - last_used_at >= LAST_USED_GRANULARITY)
+        {
+            let touched = client
+                .update_item()
+                .table_name(table)
+                .key("id", AttributeValue::S(details.id.clone()))
+                .update_expression("SET last_used_at = :now")
+                .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+                .send()
+                .await;
+            if let Err(e) = touched {
+                println!("Failed to record personal access token use: {}", e);
+            }
+        }
+
+        Ok(details)
+    }
+
+    // Returns the trimmed name and the lifetime in days
+    fn check_request(&self, request: &CreateTokenRequest) -> Result<(String, i64), AuthError> {
+        let name = request.name.trim().to_string();
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            return Err(AuthError::InvalidRequest(format!(
+                "name must be 1 to {} characters",
+                MAX_NAME_LEN
+            )));
+        }
+
+        if let Some(scope) = request
+            .scopes
+            .iter()
+            .find(|scope| *scope != TOKENS_SCOPE && !self.allowed_scopes.contains(scope))
+        {
+            return Err(AuthError::InvalidRequest(format!(
+                "unknown scope {}",
+                scope
+            )));
+        }
+
+        let lifetime_days = request.expires_in_days.unwrap_or(DEFAULT_LIFETIME_DAYS);
+        if lifetime_days < 1 || lifetime_days > self.max_lifetime_days {
+            return Err(AuthError::InvalidRequest(format!(
+                "expires_in_days must be between 1 and {}",
+                self.max_lifetime_days
+            )));
+        }
+
+        Ok((name, lifetime_days))
+    }
+
+    fn table(&self) -> Result<&str, AuthError> {
+        self.table
+            .as_deref()
+            .ok_or(AuthError::NotConfigured("PAT_TABLE"))
+    }
+
+    fn digest(&self, token: &str) -> Result<String, ServerError> {
+        let key = self
+            .hash_key
+            .as_ref()
+            .ok_or(AuthError::NotConfigured("PAT_HASH_KEY"))?;
+        let mut hash = HmacSha256::new_from_slice(key)?;
+        hash.update(token.as_bytes());
+
+        Ok(general_purpose::STANDARD.encode(hash.finalize().into_bytes()))
+    }
+}
+
+// Tokens are "hfp_<id>_<secret>"; the id is only a lookup key, the digest is what's checked
+fn token_id(token: &str) -> Option<&str> {
+    token
+        .strip_prefix(TOKEN_PREFIX)
+        .and_then(|rest| rest.split_once('_'))
+        .map(|(id, _)| id)
+        .filter(|id| !id.is_empty())
+}
+
+// Tokens from before tenants existed belong to the default one
+fn issued_in(details: &PersonalAccessToken, tenant: &str, is_default_tenant: bool) -> bool {
+    match &details.tenant {
+        Some(issued_to) => issued_to == tenant,
+        None => is_default_tenant,
+    }
+}
+
+fn check_usable(
+    details: &PersonalAccessToken,
+    tenant: &str,
+    is_default_tenant: bool,
+    now: i64,
+) -> Result<(), AuthError> {
+    if !issued_in(details, tenant, is_default_tenant) {
+        return Err(AuthError::InvalidToken(
+            "unknown personal access token".into(),
+        ));
+    }
+
+    if details.expires_at <= now {
+        return Err(AuthError::TokenExpired);
+    }
+
+    Ok(())
+}
+
+fn to_item(token: &PersonalAccessToken, digest: String) -> HashMap<String, AttributeValue> {
+    let mut item = HashMap::from([
+        ("id".into(), AttributeValue::S(token.id.clone())),
+        ("user_sub".into(), AttributeValue::S(token.user_sub.clone())),
+        ("name".into(), AttributeValue::S(token.name.clone())),
+        (
+            "scopes".into(),
+            AttributeValue::L(
+                token
+                    .scopes
+                    .iter()
+                    .map(|scope| AttributeValue::S(scope.clone()))
+                    .collect(),
+            ),
+        ),
+        ("digest".into(), AttributeValue::S(digest)),
+        (
+            "created_at".into(),
+            AttributeValue::N(token.created_at.to_string()),
+        ),
+        // Doubles as the table's TTL attribute so expired tokens clean themselves up
+        (
+            "expires_at".into(),
+            AttributeValue::N(token.expires_at.to_string()),
+        ),
+    ]);
+    if let Some(tenant) = &token.tenant {
+        item.insert("tenant".into(), AttributeValue::S(tenant.clone()));
+    }
+    item
+}
+
+fn to_token(item: &HashMap<String, AttributeValue>) -> Option<PersonalAccessToken> {
+    let number = |name: &str| item.get(name)?.as_n().ok()?.parse::<i64>().ok();
+
+    Some(PersonalAccessToken {
+        id: item.get("id")?.as_s().ok()?.clone(),
+        user_sub: item.get("user_sub")?.as_s().ok()?.clone(),
+        tenant: item.get("tenant").and_then(|v| v.as_s().ok()).cloned(),
+        name: item.get("name")?.as_s().ok()?.clone(),
+        scopes: item
+            .get("scopes")
+            .and_then(|v| v.as_l().ok())
+            .map(|scopes| {
+                scopes
+                    .iter()
+                    .filter_map(|scope| scope.as_s().ok().cloned())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        created_at: number("created_at")?,
+        expires_at: number("expires_at")?,
+        last_used_at: number("last_used_at"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens() -> PersonalAccessTokens {
+        PersonalAccessTokens {
+            table: Some("tokens".into()),
+            user_index: DEFAULT_USER_INDEX.into(),
+            hash_key: Some(b"hash key".to_vec()),
+            allowed_scopes: vec!["read".into()],
+            max_lifetime_days: 30,
+        }
+    }
+
+    fn request(name: &str, scopes: &[&str], expires_in_days: Option<i64>) -> CreateTokenRequest {
+        CreateTokenRequest {
+            name: name.into(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            expires_in_days,
+        }
+    }
+
+    fn token(tenant: Option<&str>, expires_at: i64) -> PersonalAccessToken {
+        PersonalAccessToken {
+            id: "abc".into(),
+            user_sub: "sub".into(),
+            tenant: tenant.map(Into::into),
+            name: "ci".into(),
+            scopes: vec!["read".into()],
+            created_at: 0,
+            expires_at,
+            last_used_at: None,
+        }
+    }
+
+    #[test]
+    fn digests_depend_on_token_and_key() {
+        let tokens = tokens();
+        let digest = tokens.digest("hfp_abc_secret").unwrap();
+
+        assert_eq!(digest, tokens.digest("hfp_abc_secret").unwrap());
+        assert_ne!(digest, tokens.digest("hfp_abc_other").unwrap());
+
+        let mut rekeyed = tokens.clone();
+        rekeyed.hash_key = Some(b"other key".to_vec());
+        assert_ne!(digest, rekeyed.digest("hfp_abc_secret").unwrap());
+
+        rekeyed.hash_key = None;
+        assert!(rekeyed.digest("hfp_abc_secret").is_err());
+    }
+
+    #[test]
+    fn ids_come_from_the_token() {
+        assert_eq!(token_id("hfp_abc_secret"), Some("abc"));
+        assert_eq!(token_id("hfp__secret"), None);
+        assert_eq!(token_id("hfp_abc"), None);
+        assert_eq!(token_id("eyJhbGciOi"), None);
+        assert!(PersonalAccessTokens::is_token("hfp_abc_secret"));
+    }
+
+    #[test]
+    fn scopes_must_be_allowed() {
+        let tokens = tokens();
+        assert!(tokens
+            .check_request(&request("ci", &["read"], Some(7)))
+            .is_ok());
+        assert!(tokens
+            .check_request(&request("ci", &[TOKENS_SCOPE], Some(7)))
+            .is_ok());
+        assert_eq!(
+            tokens.check_request(&request("ci", &["admin"], Some(7))),
+            Err(AuthError::InvalidRequest("unknown scope admin".into()))
+        );
+    }
+
+    #[test]
+    fn names_and_lifetimes_are_bounded() {
+        let tokens = tokens();
+        assert_eq!(
+            tokens.check_request(&request("  ci  ", &[], Some(30))),
+            Ok(("ci".into(), 30))
+        );
+        assert!(tokens.check_request(&request("  ", &[], Some(7))).is_err());
+        assert!(tokens
+            .check_request(&request(&"x".repeat(MAX_NAME_LEN + 1), &[], Some(7)))
+            .is_err());
+        assert!(tokens.check_request(&request("ci", &[], Some(0))).is_err());
+        assert!(tokens.check_request(&request("ci", &[], Some(31))).is_err());
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        assert_eq!(
+            check_usable(&token(Some("acme"), 100), "acme", false, 99),
+            Ok(())
+        );
+        assert_eq!(
+            check_usable(&token(Some("acme"), 100), "acme", false, 100),
+            Err(AuthError::TokenExpired)
+        );
+    }
+
+    #[test]
+    fn tokens_only_work_in_their_tenant() {
+        assert!(check_usable(&token(Some("acme"), 100), "globex", false, 0).is_err());
+        assert!(check_usable(&token(None, 100), "acme", true, 0).is_ok());
+        assert!(check_usable(&token(None, 100), "acme", false, 0).is_err());
+    }
+
+    #[test]
+    fn items_round_trip() {
+        let token = token(Some("acme"), 100);
+        let item = to_item(&token, "digest".into());
+        let read = to_token(&item).unwrap();
+
+        assert_eq!(item["digest"].as_s().unwrap(), "digest");
+        assert_eq!(read.id, token.id);
+        assert_eq!(read.tenant, token.tenant);
+        assert_eq!(read.scopes, token.scopes);
+        assert_eq!(read.expires_at, token.expires_at);
+    }
+}
@@ -0,0 +1,403 @@
+use crate::errors::policy::PolicyError;
+use crate::operations::authenticated_user::AuthenticatedUser;
+use actix_web::{
+    http::{header::HeaderMap, Method},
+    web,
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+const DEFAULT_RELOAD_INTERVAL: u64 = 5;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyMode {
+    #[default]
+    Enforce,
+    // Violations are logged and the request let through, to try a policy out on live traffic
+    Report,
+}
+
+// What happens to requests no rule matches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DefaultAction {
+    #[default]
+    Allow,
+    Authenticated,
+    Deny,
+}
+
+// Paths are "/"-separated patterns where {name} captures one segment, * matches any one
+// segment and a trailing ** matches whatever is left, including nothing. Claim values are
+// "path.<name>", "query.<name>", "header.<name>" or else compared as literals.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rule {
+    pub id: String,
+    pub path: String,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub authenticated: bool,
+    #[serde(default)]
+    pub groups_any: Vec<String>,
+    #[serde(default)]
+    pub scopes_all: Vec<String>,
+    #[serde(default)]
+    pub claims: BTreeMap<String, String>,
+}
+
+impl Rule {
+    pub fn requires_authentication(&self) -> bool {
+        self.authenticated
+            || !self.groups_any.is_empty()
+            || !self.scopes_all.is_empty()
+            || !self.claims.is_empty()
+    }
+
+    pub fn check(
+        &self,
+        user: &AuthenticatedUser,
+        captures: &HashMap<String, String>,
+        query: &str,
+        headers: &HeaderMap,
+    ) -> Result<(), PolicyError> {
+        let deny = |reason: String| PolicyError::Denied {
+            rule: self.id.clone(),
+            reason,
+        };
+
+        if !self.groups_any.is_empty() && !self.groups_any.iter().any(|g| user.in_group(g)) {
+            return Err(deny(format!("not in any of {:?}", self.groups_any)));
+        }
+
+        if let Some(scope) = self
+            .scopes_all
+            .iter()
+            .find(|scope| !user.scopes.contains(scope))
+        {
+            return Err(deny(format!("missing scope {}", scope)));
+        }
+
+        for (claim, expected) in &self.claims {
+            let expected = resolve(expected, captures, query, headers);
+
+            if !expected.is_some_and(|expected| user.claim_matches(claim, &expected)) {
+                return Err(deny(format!("claim {} does not match", claim)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Policy {
+    #[serde(default)]
+    pub mode: PolicyMode,
+    #[serde(default)]
+    pub default: DefaultAction,
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn parse(toml: &str) -> Result<Policy, PolicyError> {
+        let policy: Policy =
+            toml::from_str(toml).map_err(|e| PolicyError::Invalid(e.to_string()))?;
+
+        for rule in &policy.rules {
+            let segments: Vec<&str> = rule.path.split('/').collect();
+            if !rule.path.starts_with('/') || segments[..segments.len() - 1].contains(&"**") {
+                return Err(PolicyError::Invalid(format!(
+                    "rule {} has an invalid path {}",
+                    rule.id, rule.path
+                )));
+            }
+        }
+
+        Ok(policy)
+    }
+
+    // Rules are tried in order and the first match decides
+    pub fn find(&self, method: &Method, path: &str) -> Option<(&Rule, HashMap<String, String>)> {
+        self.rules
+            .iter()
+            .filter(|rule| {
+                rule.methods.is_empty()
+                    || rule
+                        .methods
+                        .iter()
+                        .any(|m| m.eq_ignore_ascii_case(method.as_str()))
+            })
+            .find_map(|rule| matches(&rule.path, path).map(|captures| (rule, captures)))
+    }
+}
+
+// Re-reads the file when its modification time changes, checked at most every
+// POLICY_RELOAD_INTERVAL seconds; a file that no longer parses leaves the last good policy in place
+#[derive(Debug)]
+pub struct PolicyEngine {
+    path: Option<String>,
+    policy: RwLock<Arc<Policy>>,
+    modified: RwLock<Option<SystemTime>>,
+    checked: RwLock<Instant>,
+    reload_interval: Duration,
+}
+
+impl PolicyEngine {
+    // POLICY_FILE names a TOML policy; without it every request is let through as before
+    pub fn from_env() -> Result<PolicyEngine, PolicyError> {
+        PolicyEngine::from_file(env::var("POLICY_FILE").ok())
+    }
+
+    pub fn from_file(path: Option<String>) -> Result<PolicyEngine, PolicyError> {
+        let engine = PolicyEngine {
+            path,
+            policy: RwLock::new(Arc::new(Policy::default())),
+            modified: RwLock::new(None),
+            checked: RwLock::new(Instant::now()),
+            reload_interval: Duration::from_secs(
+                env::var("POLICY_RELOAD_INTERVAL")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(DEFAULT_RELOAD_INTERVAL),
+            ),
+        };
+
+        if let Some(path) = &engine.path {
+            let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+            *engine.policy.write().unwrap() = Arc::new(load(path)?);
+            *engine.modified.write().unwrap() = modified;
+        }
+
+        Ok(engine)
+    }
+
+    pub fn current(&self) -> Arc<Policy> {
+        if let Some(path) = &self.path {
+            if self.checked.read().unwrap().elapsed() >= self.reload_interval {
+                *self.checked.write().unwrap() = Instant::now();
+                self.reload_if_changed(path);
+            }
+        }
+
+        self.policy.read().unwrap().clone()
+    }
+
+    fn reload_if_changed(&self, path: &str) {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified == *self.modified.read().unwrap() {
+            return;
+        }
+        *self.modified.write().unwrap() = modified;
+
+        match load(path) {
+            Ok(policy) => {
+                println!("Reloaded policy from {}", path);
+                *self.policy.write().unwrap() = Arc::new(policy);
+            }
+            Err(e) => println!("Keeping the previous policy, {}", e),
+        }
+    }
+}
+
+fn load(path: &str) -> Result<Policy, PolicyError> {
+    let toml = fs::read_to_string(path)
+        .map_err(|e| PolicyError::Invalid(format!("can't read {}: {}", path, e)))?;
+    Policy::parse(&toml)
+}
+
+fn matches(pattern: &str, path: &str) -> Option<HashMap<String, String>> {
+    let mut captures = HashMap::new();
+    let mut segments = path.trim_end_matches('/').split('/');
+
+    for part in pattern.trim_end_matches('/').split('/') {
+        if part == "**" {
+            return Some(captures);
+        }
+
+        let segment = segments.next()?;
+        match part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+            Some(name) if !segment.is_empty() => {
+                captures.insert(name.to_string(), segment.to_string());
+            }
+            Some(_) => return None,
+            None if part == "*" && !segment.is_empty() => {}
+            None if part == segment => {}
+            None => return None,
+        }
+    }
+
+    match segments.next() {
+        Some(_) => None,
+        None => Some(captures),
+    }
+}
+
+fn resolve(
+    expected: &str,
+    captures: &HashMap<String, String>,
+    query: &str,
+    headers: &HeaderMap,
+) -> Option<String> {
+    if let Some(name) = expected.strip_prefix("path.") {
+        return captures.get(name).cloned();
+    }
+    if let Some(name) = expected.strip_prefix("query.") {
+        return web::Query::<HashMap<String, String>>::from_query(query)
+            .ok()
+            .and_then(|query| query.get(name).cloned());
+    }
+    if let Some(name) = expected.strip_prefix("header.") {
+        return headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+    }
+
+    Some(expected.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::test_support::user;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    const POLICY: &str = r#"
+        default = "deny"
+
+        [[rule]]
+        id = "health"
+        path = "/health"
+
+        [[rule]]
+        id = "admin"
+        path = "/admin/**"
+        groups_any = ["admins"]
+
+        [[rule]]
+        id = "org"
+        path = "/orgs/{org_id}/members/*"
+        methods = ["GET"]
+        claims = { org_id = "path.org_id" }
+
+        [[rule]]
+        id = "orders"
+        path = "/orders"
+        scopes_all = ["orders/read"]
+    "#;
+
+    fn rule_for<'a>(policy: &'a Policy, method: Method, path: &str) -> Option<&'a str> {
+        policy.find(&method, path).map(|(rule, _)| rule.id.as_str())
+    }
+
+    #[test]
+    fn parses_rules_and_defaults() {
+        let policy = Policy::parse(POLICY).unwrap();
+        assert_eq!(policy.default, DefaultAction::Deny);
+        assert_eq!(policy.mode, PolicyMode::Enforce);
+        assert_eq!(policy.rules.len(), 4);
+        assert!(!policy.rules[0].requires_authentication());
+        assert!(policy.rules[1].requires_authentication());
+    }
+
+    #[test]
+    fn rejects_invalid_paths() {
+        assert!(Policy::parse("[[rule]]\nid = \"a\"\npath = \"admin\"").is_err());
+        assert!(Policy::parse("[[rule]]\nid = \"a\"\npath = \"/**/x\"").is_err());
+        assert!(Policy::parse("mode = \"sometimes\"").is_err());
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let policy = Policy::parse(POLICY).unwrap();
+        assert_eq!(rule_for(&policy, Method::GET, "/health"), Some("health"));
+        assert_eq!(rule_for(&policy, Method::GET, "/health/"), Some("health"));
+        assert_eq!(rule_for(&policy, Method::GET, "/healthz"), None);
+        assert_eq!(rule_for(&policy, Method::DELETE, "/admin"), Some("admin"));
+        assert_eq!(
+            rule_for(&policy, Method::GET, "/admin/users/1"),
+            Some("admin")
+        );
+    }
+
+    #[test]
+    fn methods_and_segments_must_match() {
+        let policy = Policy::parse(POLICY).unwrap();
+        assert_eq!(
+            rule_for(&policy, Method::GET, "/orgs/acme/members/42"),
+            Some("org")
+        );
+        assert_eq!(
+            rule_for(&policy, Method::POST, "/orgs/acme/members/42"),
+            None
+        );
+        assert_eq!(rule_for(&policy, Method::GET, "/orgs/acme/members"), None);
+        assert_eq!(rule_for(&policy, Method::GET, "/orgs//members/42"), None);
+        assert_eq!(
+            rule_for(&policy, Method::GET, "/orgs/acme/members/42/x"),
+            None
+        );
+    }
+
+    #[test]
+    fn captures_named_segments() {
+        let policy = Policy::parse(POLICY).unwrap();
+        let (_, captures) = policy.find(&Method::GET, "/orgs/acme/members/42").unwrap();
+        assert_eq!(captures.get("org_id").map(|v| v.as_str()), Some("acme"));
+    }
+
+    #[test]
+    fn checks_groups_scopes_and_claims() {
+        let policy = Policy::parse(POLICY).unwrap();
+        let headers = HeaderMap::new();
+        let check = |path: &str, user: &AuthenticatedUser| {
+            let (rule, captures) = policy.find(&Method::GET, path).unwrap();
+            rule.check(user, &captures, "", &headers)
+        };
+
+        assert!(check("/admin/users", &user(&["admins"], &[])).is_ok());
+        assert!(check("/admin/users", &user(&["staff"], &[])).is_err());
+        assert!(check("/orders", &user(&[], &["orders/read"])).is_ok());
+        assert!(check("/orders", &user(&[], &[])).is_err());
+        assert!(check("/orgs/acme/members/1", &user(&[], &[])).is_ok());
+        assert_eq!(
+            check("/orgs/globex/members/1", &user(&[], &[])),
+            Err(PolicyError::Denied {
+                rule: "org".into(),
+                reason: "claim org_id does not match".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn resolves_query_header_and_literal_values() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-org"),
+            HeaderValue::from_static("acme"),
+        );
+        let captures = HashMap::new();
+
+        assert_eq!(
+            resolve("query.org", &captures, "org=acme&x=1", &headers),
+            Some("acme".into())
+        );
+        assert_eq!(
+            resolve("header.x-org", &captures, "", &headers),
+            Some("acme".into())
+        );
+        assert_eq!(resolve("path.org", &captures, "", &headers), None);
+        assert_eq!(
+            resolve("acme", &captures, "", &headers),
+            Some("acme".into())
+        );
+    }
+}
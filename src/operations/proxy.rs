@@ -0,0 +1,770 @@
+use crate::errors::{proxy::ProxyError, server::ServerError};
+use crate::operations::auth::HmacSha256;
+use crate::operations::authenticated_user::AuthenticatedUser;
+use crate::operations::cookie_policy::{CookiePolicy, TOKEN_COOKIES};
+use crate::operations::csrf::is_websocket_upgrade;
+use crate::operations::session_token::{now, SessionKeys};
+use crate::operations::tenant::Tenant;
+use crate::operations::user_attributes::verified_email;
+use actix_web::{
+    http::{header, StatusCode},
+    web::{self, Bytes},
+    HttpRequest, HttpResponse,
+};
+use actix_ws::{AggregatedMessage, CloseCode, CloseReason};
+use base64::{engine::general_purpose, Engine};
+use futures_util::{SinkExt, Stream, StreamExt};
+use hmac::Mac;
+use reqwest::{Body, Client};
+use serde::Deserialize;
+use std::{
+    env, fs, io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{net::TcpStream, sync::mpsc};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::{HeaderName as UpstreamHeaderName, HeaderValue as UpstreamHeaderValue},
+        protocol::{frame::coding::CloseCode as UpstreamCloseCode, CloseFrame},
+        Message as UpstreamMessage,
+    },
+    MaybeTlsStream, WebSocketStream,
+};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+pub const USER_HEADER: &str = "X-User-Id";
+pub const EMAIL_HEADER: &str = "X-User-Email";
+pub const GROUPS_HEADER: &str = "X-User-Groups";
+pub const TIMESTAMP_HEADER: &str = "X-Identity-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Identity-Signature";
+
+// Only this server may set these, whatever the client sent
+const IDENTITY_HEADERS: [&str; 5] = [
+    USER_HEADER,
+    EMAIL_HEADER,
+    GROUPS_HEADER,
+    TIMESTAMP_HEADER,
+    SIGNATURE_HEADER,
+];
+
+// Headers about one connection, never passed on to the next
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityMode {
+    // X-User-* headers, with an HMAC-SHA256 signature over them and the request's method and
+    // path, keyed by PROXY_IDENTITY_SECRET
+    #[default]
+    Headers,
+    // A session JWT as a Bearer token, verifiable against /.well-known/jwks.json
+    SessionToken,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Upstream {
+    pub prefix: String,
+    pub url: String,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default)]
+    pub identity: IdentityMode,
+    // Whether the upstream sees /legacy/orders or just /orders
+    #[serde(default)]
+    pub strip_prefix: bool,
+    // Whether cookie-authenticated form posts must carry our CSRF header as well; the apps'
+    // own pages don't know to send it, so by default only their Origin is checked
+    #[serde(default)]
+    pub csrf_token: bool,
+}
+
+fn default_timeout_secs() -> u64 {
+    DEFAULT_TIMEOUT_SECS
+}
+
+impl Upstream {
+    fn target(&self, path: &str, query: &str) -> String {
+        format!(
+            "{}{}",
+            self.url.trim_end_matches('/'),
+            self.path(path, query)
+        )
+    }
+
+    // The path and query as the upstream sees them
+    fn path(&self, path: &str, query: &str) -> String {
+        let path = match self.strip_prefix {
+            true => match &path[self.prefix.len()..] {
+                "" => "/",
+                rest => rest,
+            },
+            false => path,
+        };
+
+        match query.is_empty() {
+            true => path.to_string(),
+            false => format!("{}?{}", path, query),
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ProxyConfig {
+    #[serde(default, rename = "upstream")]
+    upstreams: Vec<Upstream>,
+}
+
+// Forwards configured path prefixes to internal apps once the caller is signed in, so they
+// get a verified identity without knowing anything about Cognito
+#[derive(Debug)]
+pub struct ReverseProxy {
+    upstreams: Vec<Upstream>,
+    identity_secret: Option<String>,
+    client: Client,
+}
+
+impl ReverseProxy {
+    // PROXY_FILE names a TOML file of [[upstream]] tables; without it nothing is proxied
+    pub fn from_env(client: Client) -> Result<ReverseProxy, ProxyError> {
+        let identity_secret = env::var("PROXY_IDENTITY_SECRET").ok();
+        let path = match env::var("PROXY_FILE") {
+            Ok(path) => path,
+            Err(_) => {
+                return Ok(ReverseProxy {
+                    upstreams: vec![],
+                    identity_secret,
+                    client,
+                })
+            }
+        };
+
+        let toml = fs::read_to_string(&path)
+            .map_err(|e| ProxyError::Invalid(format!("can't read {}: {}", path, e)))?;
+        ReverseProxy::parse(&toml, identity_secret, client)
+    }
+
+    pub fn parse(
+        toml: &str,
+        identity_secret: Option<String>,
+        client: Client,
+    ) -> Result<ReverseProxy, ProxyError> {
+        let config: ProxyConfig =
+            toml::from_str(toml).map_err(|e| ProxyError::Invalid(e.to_string()))?;
+
+        for upstream in &config.upstreams {
+            if !upstream.prefix.starts_with('/')
+                || upstream.prefix.ends_with('/')
+                || upstream.prefix.len() < 2
+            {
+                return Err(ProxyError::Invalid(format!(
+                    "upstream {} has an invalid prefix",
+                    upstream.prefix
+                )));
+            }
+            if !upstream.url.starts_with("http://") && !upstream.url.starts_with("https://") {
+                return Err(ProxyError::Invalid(format!(
+                    "upstream {} has an invalid url {}",
+                    upstream.prefix, upstream.url
+                )));
+            }
+            if upstream.identity == IdentityMode::Headers && identity_secret.is_none() {
+                return Err(ProxyError::Invalid(format!(
+                    "upstream {} signs identity headers, but PROXY_IDENTITY_SECRET is not set",
+                    upstream.prefix
+                )));
+            }
+        }
+
+        Ok(ReverseProxy {
+            upstreams: config.upstreams,
+            identity_secret,
+            client,
+        })
+    }
+
+    pub fn prefixes(&self) -> impl Iterator<Item = &str> {
+        self.upstreams
+            .iter()
+            .map(|upstream| upstream.prefix.as_str())
+    }
+
+    pub fn origin_checked_prefixes(&self) -> impl Iterator<Item = &str> {
+        self.upstreams
+            .iter()
+            .filter(|upstream| !upstream.csrf_token)
+            .map(|upstream| upstream.prefix.as_str())
+    }
+
+    // The longest prefix wins, so /legacy/admin can go elsewhere than /legacy. Paths with dot
+    // segments go nowhere, since the upstream would resolve /app/../admin out of its prefix.
+    fn route(&self, path: &str) -> Result<&Upstream, ProxyError> {
+        if has_dot_segment(path) {
+            return Err(ProxyError::InvalidPath(path.into()));
+        }
+
+        self.upstreams
+            .iter()
+            .filter(|upstream| {
+                path.strip_prefix(&upstream.prefix)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .max_by_key(|upstream| upstream.prefix.len())
+            .ok_or_else(|| ProxyError::NoUpstream(path.into()))
+    }
+
+    pub async fn forward(
+        &self,
+        req: &HttpRequest,
+        payload: web::Payload,
+        user: &AuthenticatedUser,
+        cookie_policy: &CookiePolicy,
+        session_keys: &SessionKeys,
+        tenant: &Tenant,
+    ) -> Result<HttpResponse, ServerError> {
+        let upstream = self.route(req.path())?;
+        let target = upstream.target(req.path(), req.query_string());
+
+        let mut headers = forwarded_headers(req, cookie_policy);
+        headers.extend(
+            self.identity(req, upstream, user, session_keys, tenant)
+                .await?,
+        );
+
+        if is_websocket_upgrade(req) {
+            return forward_websocket(req, payload, upstream, &target, headers).await;
+        }
+
+        let mut request = self
+            .client
+            .request(req.method().clone(), &target)
+            .timeout(upstream.timeout());
+        // Without either header there is no body, and some servers refuse a chunked empty one
+        if req.headers().contains_key(header::CONTENT_LENGTH)
+            || req.headers().contains_key(header::TRANSFER_ENCODING)
+        {
+            request = request.body(Body::wrap_stream(PayloadStream::spawn(payload)));
+        }
+        for (name, value) in &headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+
+        let res = request.send().await.map_err(upstream_error)?;
+
+        let status = StatusCode::from_u16(res.status().as_u16())
+            .map_err(|e| ProxyError::Unavailable(e.to_string()))?;
+        let mut builder = HttpResponse::build(status);
+        for (name, value) in res.headers() {
+            if !is_hop_by_hop(name.as_str()) && name.as_str() != "content-length" {
+                builder.append_header((name.as_str(), value.as_bytes()));
+            }
+        }
+        if let Some(length) = res.content_length() {
+            builder.no_chunking(length);
+        }
+
+        Ok(builder.streaming(
+            res.bytes_stream()
+                .map(|chunk| chunk.map_err(|e| io::Error::other(e.to_string()))),
+        ))
+    }
+
+    async fn identity(
+        &self,
+        req: &HttpRequest,
+        upstream: &Upstream,
+        user: &AuthenticatedUser,
+        session_keys: &SessionKeys,
+        tenant: &Tenant,
+    ) -> Result<Vec<(String, String)>, ServerError> {
+        if upstream.identity == IdentityMode::SessionToken {
+            let token = session_keys.mint(&user.sub, &tenant.id, user.groups.clone())?;
+            return Ok(vec![(
+                header::AUTHORIZATION.to_string(),
+                format!("Bearer {}", token),
+            )]);
+        }
+
+        let secret = self
+            .identity_secret
+            .as_deref()
+            .ok_or_else(|| ProxyError::Invalid("PROXY_IDENTITY_SECRET is not set".into()))?;
+        let email = verified_email(req, user).await?.unwrap_or_default();
+        let groups = user.groups.join(",");
+        let timestamp = now().to_string();
+        let path = upstream.path(req.path(), req.query_string());
+        let signature = identity_signature(
+            secret,
+            [
+                &user.sub,
+                &email,
+                &groups,
+                &timestamp,
+                req.method().as_str(),
+                &path,
+            ],
+        )?;
+
+        Ok(vec![
+            (USER_HEADER.into(), user.sub.clone()),
+            (EMAIL_HEADER.into(), email),
+            (GROUPS_HEADER.into(), groups),
+            (TIMESTAMP_HEADER.into(), timestamp),
+            (SIGNATURE_HEADER.into(), signature),
+        ])
+    }
+}
+
+// Over the identity, its timestamp, and the method and path the upstream sees, so headers
+// lifted from one request can't vouch for another while the timestamp is still fresh.
+// Newline separated, so the values can't be shifted from one field into the next.
+fn identity_signature(secret: &str, fields: [&str; 6]) -> Result<String, ServerError> {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())?;
+    mac.update(fields.join("\n").as_bytes());
+    Ok(general_purpose::STANDARD.encode(mac.finalize().into_bytes()))
+}
+
+fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP.iter().any(|h| name.eq_ignore_ascii_case(h))
+}
+
+// Any "." or ".." segment, percent-encoded or not; an encoded slash counts as a separator,
+// since upstreams differ on whether they decode it
+pub fn has_dot_segment(path: &str) -> bool {
+    percent_decode(path)
+        .split(['/', '\\'])
+        .any(|segment| segment == "." || segment == "..")
+}
+
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// The client's headers minus our own credentials, which the upstream has no business seeing,
+// and minus any forwarding headers it made up, which are ours to set
+fn forwarded_headers(req: &HttpRequest, cookie_policy: &CookiePolicy) -> Vec<(String, String)> {
+    // Connection names more headers that only concern the hop they arrived on
+    let connection_headers: Vec<String> = req
+        .headers()
+        .get_all(header::CONNECTION)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    let mut headers: Vec<(String, String)> = req
+        .headers()
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            !is_hop_by_hop(name)
+                && !connection_headers.iter().any(|h| h == name)
+                && !name.starts_with("sec-websocket-")
+                && !name.starts_with("x-forwarded-")
+                && !IDENTITY_HEADERS
+                    .iter()
+                    .any(|h| name.eq_ignore_ascii_case(h))
+                && !matches!(name, "host" | "authorization" | "cookie" | "forwarded")
+        })
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+
+    let auth_cookies: Vec<String> = TOKEN_COOKIES
+        .iter()
+        .chain(&["session_token"])
+        .map(|name| cookie_policy.name(name))
+        .collect();
+    let cookies: Vec<String> = req
+        .cookies()
+        .map(|cookies| {
+            cookies
+                .iter()
+                .filter(|cookie| {
+                    !auth_cookies.iter().any(|name| {
+                        cookie.name() == name
+                            || cookie
+                                .name()
+                                .strip_prefix(name.as_str())
+                                .is_some_and(|rest| rest.starts_with('.'))
+                    })
+                })
+                .map(|cookie| format!("{}={}", cookie.name(), cookie.value()))
+                .collect()
+        })
+        .unwrap_or_default();
+    if !cookies.is_empty() {
+        headers.push((header::COOKIE.to_string(), cookies.join("; ")));
+    }
+
+    // From the connection itself, since connection_info would take the client's word for them
+    if let Some(peer) = req.peer_addr() {
+        headers.push(("X-Forwarded-For".into(), peer.ip().to_string()));
+    }
+    if let Some(host) = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+    {
+        headers.push(("X-Forwarded-Host".into(), host.to_string()));
+    }
+    let scheme = match cookie_policy.is_secure_request(req) {
+        true => "https",
+        false => "http",
+    };
+    headers.push(("X-Forwarded-Proto".into(), scheme.into()));
+
+    headers
+}
+
+fn upstream_error(e: reqwest::Error) -> ProxyError {
+    match e.is_timeout() {
+        true => ProxyError::Timeout,
+        false => ProxyError::Unavailable(e.to_string()),
+    }
+}
+
+// The request body, handed over from the task that owns the payload, which can't leave its
+// thread while reqwest wants a body that can
+struct PayloadStream(mpsc::Receiver<Result<Bytes, io::Error>>);
+
+impl PayloadStream {
+    fn spawn(mut payload: web::Payload) -> PayloadStream {
+        let (tx, rx) = mpsc::channel(8);
+        actix_rt::spawn(async move {
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk.map_err(|e| io::Error::other(e.to_string()));
+                if tx.send(chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        PayloadStream(rx)
+    }
+}
+
+impl Stream for PayloadStream {
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+// The upstream is connected first, so a refused upgrade is refused to the client too. The
+// timeout covers connecting only; an open socket lives as long as both ends keep it.
+async fn forward_websocket(
+    req: &HttpRequest,
+    payload: web::Payload,
+    upstream: &Upstream,
+    target: &str,
+    headers: Vec<(String, String)>,
+) -> Result<HttpResponse, ServerError> {
+    let target = target.replacen("http", "ws", 1);
+    let mut request = target
+        .as_str()
+        .into_client_request()
+        .map_err(|e| ProxyError::Invalid(e.to_string()))?;
+    for (name, value) in headers {
+        if let (Ok(name), Ok(value)) = (
+            UpstreamHeaderName::from_bytes(name.as_bytes()),
+            UpstreamHeaderValue::from_str(&value),
+        ) {
+            request.headers_mut().append(name, value);
+        }
+    }
+    // The one sec-websocket-* header that is about the application rather than the handshake
+    if let Some(protocol) = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|v| UpstreamHeaderValue::from_bytes(v.as_bytes()).ok())
+    {
+        request
+            .headers_mut()
+            .insert("sec-websocket-protocol", protocol);
+    }
+
+    let (socket, response) = tokio::time::timeout(upstream.timeout(), connect_async(request))
+        .await
+        .map_err(|_| ProxyError::Timeout)?
+        .map_err(|e| ProxyError::Unavailable(e.to_string()))?;
+
+    let (mut res, session, messages) =
+        actix_ws::handle(req, payload).map_err(|e| ProxyError::BadUpgrade(e.to_string()))?;
+    if let Some(protocol) = response.headers().get("sec-websocket-protocol") {
+        if let Ok(protocol) = header::HeaderValue::from_bytes(protocol.as_bytes()) {
+            res.headers_mut()
+                .insert(header::SEC_WEBSOCKET_PROTOCOL, protocol);
+        }
+    }
+
+    actix_rt::spawn(relay(session, messages, socket));
+
+    Ok(res)
+}
+
+// Messages both ways until either end closes
+async fn relay(
+    mut session: actix_ws::Session,
+    messages: actix_ws::MessageStream,
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+) {
+    let mut messages = messages.aggregate_continuations();
+    let (mut upstream_tx, mut upstream_rx) = socket.split();
+
+    loop {
+        tokio::select! {
+            message = messages.next() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    _ => break,
+                };
+                let message = match message {
+                    AggregatedMessage::Text(text) => UpstreamMessage::Text(text.to_string()),
+                    AggregatedMessage::Binary(bytes) => UpstreamMessage::Binary(bytes.to_vec()),
+                    AggregatedMessage::Ping(bytes) => UpstreamMessage::Ping(bytes.to_vec()),
+                    AggregatedMessage::Pong(bytes) => UpstreamMessage::Pong(bytes.to_vec()),
+                    AggregatedMessage::Close(reason) => {
+                        let frame = reason.map(|reason| CloseFrame {
+                            code: UpstreamCloseCode::from(u16::from(reason.code)),
+                            reason: reason.description.unwrap_or_default().into(),
+                        });
+                        let _ = upstream_tx.send(UpstreamMessage::Close(frame)).await;
+                        return;
+                    }
+                };
+                if upstream_tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+            message = upstream_rx.next() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    _ => break,
+                };
+                let sent = match message {
+                    UpstreamMessage::Text(text) => session.text(text).await,
+                    UpstreamMessage::Binary(bytes) => session.binary(bytes).await,
+                    UpstreamMessage::Ping(bytes) => session.ping(&bytes).await,
+                    UpstreamMessage::Pong(bytes) => session.pong(&bytes).await,
+                    UpstreamMessage::Close(frame) => {
+                        let reason = frame.map(|frame| CloseReason {
+                            code: CloseCode::from(u16::from(frame.code)),
+                            description: Some(frame.reason.into_owned()),
+                        });
+                        let _ = session.close(reason).await;
+                        return;
+                    }
+                    UpstreamMessage::Frame(_) => Ok(()),
+                };
+                if sent.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    let _ = session.close(None).await;
+    let _ = upstream_tx.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::test_support::cookie_policy;
+    use actix_web::test::TestRequest;
+
+    const UPSTREAMS: &str = r#"
+        [[upstream]]
+        prefix = "/legacy"
+        url = "http://legacy.internal:8080/"
+
+        [[upstream]]
+        prefix = "/legacy/admin"
+        url = "http://admin.internal"
+        strip_prefix = true
+        csrf_token = true
+    "#;
+
+    fn proxy() -> ReverseProxy {
+        ReverseProxy::parse(UPSTREAMS, Some("secret".into()), Client::new()).unwrap()
+    }
+
+    #[test]
+    fn longest_prefix_wins_on_segment_boundaries() {
+        let proxy = proxy();
+        assert_eq!(proxy.route("/legacy/orders").unwrap().prefix, "/legacy");
+        assert_eq!(proxy.route("/legacy").unwrap().prefix, "/legacy");
+        assert_eq!(
+            proxy.route("/legacy/admin/users").unwrap().prefix,
+            "/legacy/admin"
+        );
+        assert_eq!(
+            proxy.route("/legacy/administrators").unwrap().prefix,
+            "/legacy"
+        );
+        assert_eq!(
+            proxy.route("/legacyish").unwrap_err(),
+            ProxyError::NoUpstream("/legacyish".into())
+        );
+    }
+
+    #[test]
+    fn dot_segments_are_rejected() {
+        let proxy = proxy();
+        for path in [
+            "/legacy/../admin",
+            "/legacy/./orders",
+            "/legacy/%2e%2e/admin",
+            "/legacy/%2E./admin",
+            "/legacy/..%2fadmin",
+            "/legacy/..%5cadmin",
+            "/legacy/orders/..",
+        ] {
+            assert_eq!(
+                proxy.route(path).unwrap_err(),
+                ProxyError::InvalidPath(path.into()),
+                "{}",
+                path
+            );
+        }
+        assert!(proxy.route("/legacy/v1.2/..orders").is_ok());
+    }
+
+    #[test]
+    fn targets_keep_or_strip_the_prefix() {
+        let proxy = proxy();
+        let legacy = proxy.route("/legacy/orders").unwrap();
+        assert_eq!(
+            legacy.target("/legacy/orders", ""),
+            "http://legacy.internal:8080/legacy/orders"
+        );
+        assert_eq!(
+            legacy.target("/legacy/orders", "page=2"),
+            "http://legacy.internal:8080/legacy/orders?page=2"
+        );
+
+        let admin = proxy.route("/legacy/admin").unwrap();
+        assert_eq!(
+            admin.target("/legacy/admin/users", ""),
+            "http://admin.internal/users"
+        );
+        assert_eq!(admin.target("/legacy/admin", ""), "http://admin.internal/");
+    }
+
+    #[test]
+    fn identity_signature_covers_the_request() {
+        let sign = |method: &str, path: &str| {
+            identity_signature(
+                "secret",
+                [
+                    "sub-1",
+                    "alice@acme.com",
+                    "staff",
+                    "1700000000",
+                    method,
+                    path,
+                ],
+            )
+            .unwrap()
+        };
+
+        let signature = sign("GET", "/orders?page=2");
+        let mut mac = HmacSha256::new_from_slice(b"secret").unwrap();
+        mac.update(b"sub-1\nalice@acme.com\nstaff\n1700000000\nGET\n/orders?page=2");
+        assert_eq!(
+            signature,
+            general_purpose::STANDARD.encode(mac.finalize().into_bytes())
+        );
+
+        assert_ne!(signature, sign("POST", "/orders?page=2"));
+        assert_ne!(signature, sign("GET", "/orders?page=3"));
+        assert_ne!(signature, sign("GET", "/admin"));
+    }
+
+    #[test]
+    fn signed_path_is_the_upstreams() {
+        let proxy = proxy();
+        let admin = proxy.route("/legacy/admin/users").unwrap();
+        assert_eq!(admin.path("/legacy/admin/users", "q=1"), "/users?q=1");
+        let legacy = proxy.route("/legacy/orders").unwrap();
+        assert_eq!(legacy.path("/legacy/orders", ""), "/legacy/orders");
+    }
+
+    #[test]
+    fn only_upstreams_without_csrf_token_are_origin_checked() {
+        let proxy = proxy();
+        let prefixes: Vec<&str> = proxy.origin_checked_prefixes().collect();
+        assert_eq!(prefixes, vec!["/legacy"]);
+    }
+
+    #[test]
+    fn client_forwarding_headers_are_replaced() {
+        let req = TestRequest::get()
+            .uri("/legacy/orders")
+            .insert_header(("host", "auth.test"))
+            .insert_header(("x-forwarded-for", "10.0.0.1"))
+            .insert_header(("x-forwarded-host", "evil.test"))
+            .insert_header(("forwarded", "for=10.0.0.1"))
+            .insert_header(("connection", "keep-alive, X-Internal"))
+            .insert_header(("x-internal", "1"))
+            .insert_header(("x-user-id", "someone"))
+            .insert_header(("accept", "text/html"))
+            .peer_addr("192.0.2.7:50000".parse().unwrap())
+            .to_http_request();
+
+        let headers = forwarded_headers(&req, &cookie_policy());
+        let values = |name: &str| -> Vec<&str> {
+            headers
+                .iter()
+                .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+                .collect()
+        };
+
+        assert_eq!(values("accept"), vec!["text/html"]);
+        assert_eq!(values("x-forwarded-host"), vec!["auth.test"]);
+        assert_eq!(values("x-forwarded-proto"), vec!["http"]);
+        assert_eq!(values("x-forwarded-for"), vec!["192.0.2.7"]);
+        assert!(values("forwarded").is_empty());
+        assert!(values("x-internal").is_empty());
+        assert!(values("x-user-id").is_empty());
+    }
+}
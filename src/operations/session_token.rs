@@ -0,0 +1,248 @@
+use crate::errors::server::ServerError;
+use crate::operations::auth_key::{AuthKey, AuthSet};
+use base64::{engine::general_purpose, Engine};
+use jsonwebtokens::{encode, Algorithm, AlgorithmID};
+use openssl::rsa::Rsa;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    env, fs,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const DEFAULT_SESSION_TOKEN_TTL: i64 = 15 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionClaims {
+    pub iss: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub roles: Vec<String>,
+    pub sid: String,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+pub struct SigningKey {
+    algorithm: Algorithm,
+    public: AuthKey,
+}
+
+// The first key signs; the rest stay published so tokens they signed still verify
+// until they expire
+pub struct SessionKeys {
+    keys: Vec<SigningKey>,
+    pub issuer: String,
+    pub audience: Option<String>,
+    pub ttl: i64,
+}
+
+impl SessionKeys {
+    // SESSION_SIGNING_KEYS is "kid:/path/to/key.pem,..." with PEM encoded RSA private keys.
+    // Without it startup fails, unless ALLOW_EPHEMERAL_SESSION_KEY is set for local development:
+    // a throwaway key changes on every restart and differs between instances.
+    pub fn from_env() -> SessionKeys {
+        let mut keys = vec![];
+
+        for entry in env::var("SESSION_SIGNING_KEYS")
+            .unwrap_or_default()
+            .split(',')
+        {
+            if let Some((kid, path)) = entry.trim().split_once(':') {
+                let pem = fs::read(path)
+                    .unwrap_or_else(|e| panic!("Can't read session signing key {}: {}", path, e));
+                keys.push(
+                    SigningKey::from_pem(kid, &pem)
+                        .unwrap_or_else(|_| panic!("Invalid session signing key {}", path)),
+                );
+            }
+        }
+
+        if keys.is_empty() {
+            let allow_ephemeral = env::var("ALLOW_EPHEMERAL_SESSION_KEY")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(false);
+            if !allow_ephemeral {
+                panic!("SESSION_SIGNING_KEYS is not set (set ALLOW_EPHEMERAL_SESSION_KEY=true for local development)");
+            }
+
+            println!("SESSION_SIGNING_KEYS not set, generating an ephemeral session signing key");
+            let rsa = Rsa::generate(2048).expect("RSA key generation");
+            let pem = rsa.private_key_to_pem().expect("RSA key encoding");
+            let kid = format!("ephemeral-{}", random_id());
+            keys.push(SigningKey::from_pem(&kid, &pem).expect("Generated RSA key"));
+        }
+
+        SessionKeys {
+            keys,
+            issuer: env::var("SESSION_TOKEN_ISSUER").unwrap_or_else(|_| "backend".into()),
+            audience: env::var("SESSION_TOKEN_AUDIENCE").ok(),
+            ttl: env::var("SESSION_TOKEN_TTL")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_SESSION_TOKEN_TTL),
+        }
+    }
+
+    pub fn jwks(&self) -> AuthSet {
+        let mut auth_set = AuthSet::new();
+        for key in &self.keys {
+            auth_set.insert(key.public.clone());
+        }
+        auth_set
+    }
+
+    pub fn sign(&self, claims: &SessionClaims) -> Result<String, ServerError> {
+        let key = &self.keys[0];
+        let header = json!({
+            "alg": key.public.alg,
+            "typ": "JWT",
+            "kid": key.public.kid,
+        });
+
+        Ok(encode(&header, claims, &key.algorithm)?)
+    }
+
+    pub fn mint(&self, sub: &str, tenant: &str, roles: Vec<String>) -> Result<String, ServerError> {
+        let iat = now();
+        let claims = SessionClaims {
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            sub: sub.into(),
+            tenant: Some(tenant.into()),
+            roles,
+            sid: random_id(),
+            iat,
+            exp: iat + self.ttl,
+        };
+
+        self.sign(&claims)
+    }
+}
+
+impl SigningKey {
+    pub fn from_pem(kid: &str, pem: &[u8]) -> Result<SigningKey, ServerError> {
+        let mut algorithm = Algorithm::new_rsa_pem_signer(AlgorithmID::RS256, pem)?;
+        algorithm.set_kid(kid);
+
+        let rsa = Rsa::private_key_from_pem(pem).map_err(|_| {
+            jsonwebtokens::error::Error::InvalidInput(jsonwebtokens::error::ErrorDetails::new(
+                "Invalid RSA private key",
+            ))
+        })?;
+
+        let public = AuthKey::build(
+            kid.into(),
+            "RS256".into(),
+            "RSA".into(),
+            general_purpose::URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+            general_purpose::URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+            "sig".into(),
+        );
+
+        Ok(SigningKey { algorithm, public })
+    }
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+pub fn random_id() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtokens::{raw::decode_only, Verifier};
+
+    fn pem() -> Vec<u8> {
+        Rsa::generate(2048).unwrap().private_key_to_pem().unwrap()
+    }
+
+    fn session_keys(kids: &[&str]) -> SessionKeys {
+        SessionKeys {
+            keys: kids
+                .iter()
+                .map(|kid| SigningKey::from_pem(kid, &pem()).unwrap())
+                .collect(),
+            issuer: "backend".into(),
+            audience: Some("api".into()),
+            ttl: 600,
+        }
+    }
+
+    #[test]
+    fn from_pem_publishes_the_public_half() {
+        let pem = pem();
+        let rsa = Rsa::private_key_from_pem(&pem).unwrap();
+        let key = SigningKey::from_pem("k1", &pem).unwrap();
+
+        assert_eq!(key.public.kid, "k1");
+        assert_eq!(key.public.alg, "RS256");
+        assert_eq!(key.public.kty, "RSA");
+        assert_eq!(key.public.intended_use, "sig");
+        assert_eq!(
+            key.public.n,
+            general_purpose::URL_SAFE_NO_PAD.encode(rsa.n().to_vec())
+        );
+        assert_eq!(key.public.e, "AQAB");
+    }
+
+    #[test]
+    fn from_pem_rejects_invalid_keys() {
+        assert!(SigningKey::from_pem("k1", b"not a key").is_err());
+    }
+
+    #[test]
+    fn mint_signs_with_the_first_key() {
+        let keys = session_keys(&["new", "old"]);
+        let token = keys.mint("sub-1", "acme", vec!["admin".into()]).unwrap();
+
+        let data = decode_only(&token).unwrap();
+        assert_eq!(data.header["kid"], "new");
+        assert_eq!(data.header["alg"], "RS256");
+
+        let claims: SessionClaims = serde_json::from_value(data.claims).unwrap();
+        assert_eq!(claims.iss, "backend");
+        assert_eq!(claims.aud.as_deref(), Some("api"));
+        assert_eq!(claims.sub, "sub-1");
+        assert_eq!(claims.tenant.as_deref(), Some("acme"));
+        assert_eq!(claims.roles, vec!["admin".to_string()]);
+        assert_eq!(claims.exp, claims.iat + 600);
+        assert_eq!(claims.sid.len(), 32);
+
+        let public = &keys.keys[0].public;
+        let verifier =
+            Algorithm::new_rsa_n_e_b64_verifier(AlgorithmID::RS256, &public.n, &public.e).unwrap();
+        assert!(Verifier::create()
+            .build()
+            .unwrap()
+            .verify(&token, &verifier)
+            .is_ok());
+    }
+
+    #[test]
+    fn jwks_publishes_every_key() {
+        let keys = session_keys(&["new", "old"]);
+        let jwks = serde_json::to_value(keys.jwks()).unwrap();
+
+        let kids: Vec<_> = jwks["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|k| k["kid"].as_str().unwrap())
+            .collect();
+        assert_eq!(kids, vec!["new", "old"]);
+    }
+}
@@ -0,0 +1,478 @@
+use crate::errors::{server::ServerError, tenant::TenantError};
+use crate::operations::auth::AuthCredentials;
+use crate::operations::cognito_verifier::CognitoVerifier;
+use crate::operations::cookie_policy::{strip_port, CookieDomain};
+use crate::operations::home_realm::{parse_domains, IdentityProviderMapping};
+use actix_web::{dev::Payload, http::header, web, FromRequest, HttpMessage, HttpRequest};
+use aws_config::SdkConfig;
+use aws_sdk_cognitoidentityprovider::config::Region;
+use futures_util::future::{ready, Ready};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    ops::Deref,
+    sync::Arc,
+};
+use tokio::sync::OnceCell;
+
+pub const TENANT_HEADER: &str = "X-Tenant";
+
+// The id of the one tenant configured from plain env vars, unless TENANT_ID names it
+const DEFAULT_TENANT_ID: &str = "default";
+
+#[derive(Debug, Deserialize)]
+struct TenantConfig {
+    id: String,
+    region: String,
+    user_pool_id: String,
+    client_id: String,
+    client_secret: Option<String>,
+    #[serde(default)]
+    service_client_ids: Vec<String>,
+    cognito_domain: Option<String>,
+    redirect_uri: Option<String>,
+    cookie_domain: Option<String>,
+    #[serde(default)]
+    hosts: Vec<String>,
+    path_prefix: Option<String>,
+    admin_group: Option<String>,
+    sso_domains: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RegistryConfig {
+    default: Option<String>,
+    #[serde(default, rename = "tenant")]
+    tenants: Vec<TenantConfig>,
+}
+
+// One brand's user pool and app client, with the JWKS cache for that pool
+#[derive(Debug)]
+pub struct Tenant {
+    pub id: String,
+    pub region: String,
+    pub user_pool_id: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub cognito_domain: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub cookie_domain: Option<CookieDomain>,
+    pub hosts: Vec<String>,
+    pub path_prefix: Option<String>,
+    // The Cognito group whose members may manage the pool's users
+    pub admin_group: Option<String>,
+    // Email domains that sign in through one of the pool's IdPs
+    pub sso_domains: HashMap<String, IdentityProviderMapping>,
+    pub is_default: bool,
+    pub verifier: CognitoVerifier,
+    sdk_config: OnceCell<SdkConfig>,
+}
+
+impl Tenant {
+    fn from_config(config: TenantConfig, is_default: bool) -> Result<Tenant, ServerError> {
+        if let Some(prefix) = &config.path_prefix {
+            if !prefix.starts_with('/') || prefix.ends_with('/') || prefix.len() < 2 {
+                return Err(TenantError::Invalid(format!(
+                    "tenant {} has an invalid path_prefix {}",
+                    config.id, prefix
+                ))
+                .into());
+            }
+        }
+
+        Ok(Tenant {
+            verifier: CognitoVerifier::new(
+                &config.region,
+                &config.user_pool_id,
+                vec![config.client_id.clone()],
+                config.service_client_ids,
+            )?,
+            id: config.id,
+            region: config.region,
+            user_pool_id: config.user_pool_id,
+            client_id: config.client_id,
+            client_secret: config.client_secret,
+            cognito_domain: config.cognito_domain,
+            redirect_uri: config.redirect_uri,
+            cookie_domain: config
+                .cookie_domain
+                .map(|domain| CookieDomain::parse(Some(&domain))),
+            hosts: config
+                .hosts
+                .iter()
+                .map(|host| host.to_ascii_lowercase())
+                .collect(),
+            path_prefix: config.path_prefix,
+            admin_group: config.admin_group,
+            sso_domains: parse_domains(config.sso_domains.as_deref().unwrap_or_default()),
+            is_default,
+            sdk_config: OnceCell::new(),
+        })
+    }
+
+    // The single tenant of a deployment without TENANTS_FILE, from the same env vars as before
+    fn from_env() -> Result<Tenant, ServerError> {
+        let client_id = env::var("APP_CLIENT_ID").unwrap_or_default();
+
+        Ok(Tenant {
+            id: env::var("TENANT_ID").unwrap_or_else(|_| DEFAULT_TENANT_ID.into()),
+            region: env::var("COGNITO_REGION").unwrap_or_default(),
+            user_pool_id: env::var("USER_POOL_ID").unwrap_or_default(),
+            client_id: client_id
+                .split(',')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .into(),
+            client_secret: env::var("COGNITO_SECRET").ok(),
+            cognito_domain: env::var("COGNITO_DOMAIN").ok(),
+            redirect_uri: env::var("OAUTH_REDIRECT_URI").ok(),
+            cookie_domain: None,
+            hosts: vec![],
+            path_prefix: None,
+            admin_group: env::var("ADMIN_GROUP").ok(),
+            sso_domains: parse_domains(&env::var("SSO_DOMAINS").unwrap_or_default()),
+            is_default: true,
+            verifier: CognitoVerifier::from_env()?,
+            sdk_config: OnceCell::new(),
+        })
+    }
+
+    // Cognito calls go to the tenant's own region, whatever the process default is. Loaded on
+    // first use and kept, since loading resolves credentials.
+    pub async fn sdk_config(&self) -> &SdkConfig {
+        self.sdk_config
+            .get_or_init(|| {
+                aws_config::from_env()
+                    .region(Region::new(self.region.clone()))
+                    .load()
+            })
+            .await
+    }
+
+    pub fn credentials(&self, auth_flow: &str) -> AuthCredentials {
+        AuthCredentials::prepare(
+            self.client_secret.clone(),
+            Some(self.client_id.clone()),
+            Some(self.user_pool_id.clone()),
+            auth_flow,
+        )
+    }
+
+    // The rest of the path when it is under this tenant's prefix
+    pub fn strip_prefix<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(self.path_prefix.as_deref()?)?;
+        match rest {
+            "" => Some("/"),
+            rest if rest.starts_with('/') => Some(rest),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TenantRegistry {
+    tenants: Vec<Arc<Tenant>>,
+    default: Option<Arc<Tenant>>,
+    trust_tenant_header: bool,
+}
+
+impl TenantRegistry {
+    // TENANTS_FILE names a TOML file of [[tenant]] tables and an optional default tenant id;
+    // without it there is one tenant, configured as before from COGNITO_REGION, USER_POOL_ID,
+    // APP_CLIENT_ID and friends. TRUST_TENANT_HEADER is for deployments behind a gateway that
+    // sets X-Tenant itself and strips it from clients.
+    pub fn from_env() -> Result<TenantRegistry, ServerError> {
+        let mut registry = match env::var("TENANTS_FILE") {
+            Ok(path) => {
+                let toml = fs::read_to_string(&path)
+                    .map_err(|e| TenantError::Invalid(format!("can't read {}: {}", path, e)))?;
+                TenantRegistry::parse(&toml)?
+            }
+            Err(_) => {
+                let tenant = Arc::new(Tenant::from_env()?);
+                TenantRegistry {
+                    tenants: vec![tenant.clone()],
+                    default: Some(tenant),
+                    trust_tenant_header: false,
+                }
+            }
+        };
+
+        registry.trust_tenant_header = env::var("TRUST_TENANT_HEADER")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        Ok(registry)
+    }
+
+    pub fn parse(toml: &str) -> Result<TenantRegistry, ServerError> {
+        let config: RegistryConfig =
+            toml::from_str(toml).map_err(|e| TenantError::Invalid(e.to_string()))?;
+
+        let mut ids = HashSet::new();
+        let mut tenants = vec![];
+        for tenant in config.tenants {
+            if !ids.insert(tenant.id.clone()) {
+                return Err(TenantError::Invalid(format!("duplicate tenant {}", tenant.id)).into());
+            }
+            let is_default = config.default.as_deref() == Some(tenant.id.as_str());
+            tenants.push(Arc::new(Tenant::from_config(tenant, is_default)?));
+        }
+
+        let default = match &config.default {
+            Some(id) => Some(
+                tenants
+                    .iter()
+                    .find(|tenant| tenant.id == *id)
+                    .cloned()
+                    .ok_or_else(|| {
+                        TenantError::Invalid(format!("default tenant {} is not defined", id))
+                    })?,
+            ),
+            None => None,
+        };
+
+        Ok(TenantRegistry {
+            tenants,
+            default,
+            trust_tenant_header: false,
+        })
+    }
+
+    // The Host decides, then a path prefix, then the default. An X-Tenant header from a
+    // trusted gateway overrides them; from anyone else it may only name the Host's own tenant,
+    // so a caller can't switch brands by sending it.
+    pub fn resolve(&self, req: &HttpRequest) -> Result<Arc<Tenant>, TenantError> {
+        let host = request_host(req);
+        let by_host = self.tenants.iter().find(|t| t.hosts.contains(&host));
+
+        if let Some(id) = req.headers().get(TENANT_HEADER) {
+            let id = id.to_str().unwrap_or_default();
+            let tenant = self
+                .get(id)
+                .ok_or_else(|| TenantError::Unknown(id.into()))?;
+
+            return match self.trust_tenant_header || by_host.is_some_and(|t| t.id == tenant.id) {
+                true => Ok(tenant),
+                false => Err(TenantError::HeaderNotTrusted(id.into())),
+            };
+        }
+
+        if let Some(tenant) = by_host {
+            return Ok(tenant.clone());
+        }
+
+        if let Some(tenant) = self
+            .tenants
+            .iter()
+            .find(|t| t.strip_prefix(req.path()).is_some())
+        {
+            return Ok(tenant.clone());
+        }
+
+        self.default.clone().ok_or(TenantError::Unknown(host))
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Tenant>> {
+        self.tenants.iter().find(|tenant| tenant.id == id).cloned()
+    }
+}
+
+// The Host the client connected to, without its port. connection_info would take a client's
+// X-Forwarded-Host or Forwarded header for it, and so let them pick the tenant.
+pub fn request_host(req: &HttpRequest) -> String {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().host())
+        .unwrap_or_default();
+    strip_port(host).to_ascii_lowercase()
+}
+
+// The tenant the request was resolved to, put in the request extensions by the tenant
+// middleware
+#[derive(Debug, Clone)]
+pub struct CurrentTenant(pub Arc<Tenant>);
+
+impl CurrentTenant {
+    pub fn get(req: &HttpRequest) -> Result<CurrentTenant, ServerError> {
+        if let Some(tenant) = req.extensions().get::<CurrentTenant>() {
+            return Ok(tenant.clone());
+        }
+
+        let registry = req
+            .app_data::<web::Data<TenantRegistry>>()
+            .ok_or_else(|| TenantError::Invalid("no tenant registry".into()))?;
+        Ok(CurrentTenant(registry.resolve(req)?))
+    }
+}
+
+impl Deref for CurrentTenant {
+    type Target = Tenant;
+
+    fn deref(&self) -> &Tenant {
+        &self.0
+    }
+}
+
+impl FromRequest for CurrentTenant {
+    type Error = ServerError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(CurrentTenant::get(req))
+    }
+}
+
+// The process default config main loads once, for the tables every tenant shares; loaded here
+// only when nothing registered it
+pub async fn shared_config(req: &HttpRequest) -> web::Data<SdkConfig> {
+    match req.app_data::<web::Data<SdkConfig>>() {
+        Some(config) => config.clone(),
+        None => web::Data::new(aws_config::load_from_env().await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    const TENANTS: &str = r#"
+        default = "acme"
+
+        [[tenant]]
+        id = "acme"
+        region = "eu-west-1"
+        user_pool_id = "eu-west-1_acme"
+        client_id = "acme-client"
+        hosts = ["Login.Acme.com"]
+
+        [[tenant]]
+        id = "globex"
+        region = "us-east-1"
+        user_pool_id = "us-east-1_globex"
+        client_id = "globex-client"
+        hosts = ["login.globex.com"]
+        path_prefix = "/globex"
+    "#;
+
+    fn registry() -> TenantRegistry {
+        TenantRegistry::parse(TENANTS).unwrap()
+    }
+
+    #[test]
+    fn parses_tenants_and_default() {
+        let registry = registry();
+        assert_eq!(registry.tenants.len(), 2);
+        assert_eq!(registry.default.as_ref().unwrap().id, "acme");
+        assert!(registry.get("acme").unwrap().is_default);
+        assert!(!registry.get("globex").unwrap().is_default);
+        assert_eq!(registry.get("acme").unwrap().hosts, vec!["login.acme.com"]);
+    }
+
+    #[test]
+    fn rejects_duplicate_and_undefined_tenants() {
+        let duplicate = format!(
+            "{}\n[[tenant]]\nid = \"acme\"\nregion = \"r\"\nuser_pool_id = \"p\"\nclient_id = \"c\"\n",
+            TENANTS
+        );
+        assert!(TenantRegistry::parse(&duplicate).is_err());
+        assert!(TenantRegistry::parse(
+            &TENANTS.replace("default = \"acme\"", "default = \"initech\"")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_path_prefix() {
+        assert!(TenantRegistry::parse(&TENANTS.replace("\"/globex\"", "\"/globex/\"")).is_err());
+        assert!(TenantRegistry::parse(&TENANTS.replace("\"/globex\"", "\"globex\"")).is_err());
+    }
+
+    #[test]
+    fn resolves_by_host_then_prefix_then_default() {
+        let registry = registry();
+
+        let req = TestRequest::default()
+            .insert_header(("host", "login.globex.com:8443"))
+            .to_http_request();
+        assert_eq!(registry.resolve(&req).unwrap().id, "globex");
+
+        let req = TestRequest::with_uri("/globex/login")
+            .insert_header(("host", "auth.example.com"))
+            .to_http_request();
+        assert_eq!(registry.resolve(&req).unwrap().id, "globex");
+
+        let req = TestRequest::with_uri("/login")
+            .insert_header(("host", "auth.example.com"))
+            .to_http_request();
+        assert_eq!(registry.resolve(&req).unwrap().id, "acme");
+    }
+
+    #[test]
+    fn tenant_header_must_match_host_unless_trusted() {
+        let mut registry = registry();
+
+        let req = TestRequest::default()
+            .insert_header(("host", "login.acme.com"))
+            .insert_header((TENANT_HEADER, "globex"))
+            .to_http_request();
+        assert!(matches!(
+            registry.resolve(&req),
+            Err(TenantError::HeaderNotTrusted(_))
+        ));
+
+        let req = TestRequest::default()
+            .insert_header(("host", "login.acme.com"))
+            .insert_header((TENANT_HEADER, "acme"))
+            .to_http_request();
+        assert_eq!(registry.resolve(&req).unwrap().id, "acme");
+
+        let req = TestRequest::default()
+            .insert_header((TENANT_HEADER, "initech"))
+            .to_http_request();
+        assert!(matches!(
+            registry.resolve(&req),
+            Err(TenantError::Unknown(_))
+        ));
+
+        // Forwarding headers don't make a host the client didn't connect to
+        let req = TestRequest::default()
+            .insert_header(("host", "login.acme.com"))
+            .insert_header(("x-forwarded-host", "login.globex.com"))
+            .insert_header(("forwarded", "host=login.globex.com"))
+            .insert_header((TENANT_HEADER, "globex"))
+            .to_http_request();
+        assert!(matches!(
+            registry.resolve(&req),
+            Err(TenantError::HeaderNotTrusted(_))
+        ));
+        let req = TestRequest::default()
+            .insert_header(("host", "auth.example.com"))
+            .insert_header(("x-forwarded-host", "login.globex.com"))
+            .to_http_request();
+        assert_eq!(registry.resolve(&req).unwrap().id, "acme");
+
+        registry.trust_tenant_header = true;
+        let req = TestRequest::default()
+            .insert_header(("host", "login.acme.com"))
+            .insert_header((TENANT_HEADER, "globex"))
+            .to_http_request();
+        assert_eq!(registry.resolve(&req).unwrap().id, "globex");
+    }
+
+    #[test]
+    fn strips_only_whole_prefix_segments() {
+        let registry = registry();
+        let globex = registry.get("globex").unwrap();
+        assert_eq!(globex.strip_prefix("/globex"), Some("/"));
+        assert_eq!(globex.strip_prefix("/globex/login"), Some("/login"));
+        assert_eq!(globex.strip_prefix("/globexcorp/login"), None);
+        assert_eq!(globex.strip_prefix("/login"), None);
+        assert_eq!(registry.get("acme").unwrap().strip_prefix("/login"), None);
+    }
+}
@@ -0,0 +1,126 @@
+// Fixtures the unit tests share
+use crate::operations::authenticated_user::{AuthenticatedUser, Credential};
+use crate::operations::cookie_policy::{CookieDomain, CookiePolicy, CookiePrefix, CookieSecure};
+use crate::operations::session_token::now;
+use crate::operations::tenant::{Tenant, TenantRegistry};
+use actix_web::{cookie::SameSite, web, App, HttpRequest, HttpResponse, HttpServer};
+use aws_config::SdkConfig;
+use aws_sdk_dynamodb::config::{Credentials, Region};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+// Host-only cookies on plain HTTP, with nothing trusted from forwarding headers
+pub fn cookie_policy() -> CookiePolicy {
+    CookiePolicy::new(
+        CookieDomain::HostOnly,
+        CookieSecure::Never,
+        SameSite::Lax,
+        "/".into(),
+        CookiePrefix::None,
+        false,
+    )
+}
+
+// A signed-in alice of the acme organization, with the given groups and granted scopes
+pub fn user(groups: &[&str], scopes: &[&str]) -> AuthenticatedUser {
+    let claims = serde_json::from_value(json!({
+        "sub": "sub-1",
+        "iss": "https://issuer",
+        "client_id": "client",
+        "token_use": "access",
+        "username": "alice",
+        "iat": now(),
+        "exp": now() + 3600,
+        "custom:org_id": "acme",
+    }))
+    .unwrap();
+
+    AuthenticatedUser {
+        sub: "sub-1".into(),
+        username: Some("alice".into()),
+        groups: groups.iter().map(|g| g.to_string()).collect(),
+        scopes: scopes.iter().map(|s| s.to_string()).collect(),
+        credential: Credential::CognitoUser,
+        expires_at: now() + 3600,
+        claims: Some(claims),
+    }
+}
+
+pub fn service(client_id: &str, scopes: &[&str]) -> AuthenticatedUser {
+    AuthenticatedUser {
+        credential: Credential::Service {
+            client_id: client_id.into(),
+        },
+        username: None,
+        claims: None,
+        ..user(&[], scopes)
+    }
+}
+
+// Enough for code that takes a config but never reaches AWS in the test
+pub fn sdk_config() -> SdkConfig {
+    SdkConfig::builder().build()
+}
+
+// A config whose DynamoDB calls are answered by `respond`, given the operation (e.g.
+// "DeleteItem") and the request body
+pub async fn dynamodb(respond: fn(&str, Value) -> HttpResponse) -> SdkConfig {
+    let server = HttpServer::new(move || {
+        App::new().default_service(web::to(move |req: HttpRequest, body: web::Bytes| {
+            let operation = req
+                .headers()
+                .get("x-amz-target")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.split_once('.'))
+                .map(|(_, operation)| operation.to_string())
+                .unwrap_or_default();
+            let body = serde_json::from_slice(&body).unwrap_or_default();
+            async move { respond(&operation, body) }
+        }))
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let address = server.addrs()[0];
+    actix_rt::spawn(server.run());
+
+    aws_config::from_env()
+        .region(Region::new("eu-west-1"))
+        .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+        .endpoint_url(format!("http://{}", address))
+        .load()
+        .await
+}
+
+pub fn dynamodb_error(error: &str) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type("application/x-amz-json-1.0")
+        .json(json!({
+            "__type": format!("com.amazonaws.dynamodb.v20120810#{}", error),
+            "message": error,
+        }))
+}
+
+// The acme tenant, on login.acme.com
+pub fn tenant(admin_group: Option<&str>) -> Arc<Tenant> {
+    tenants(admin_group).get("acme").unwrap()
+}
+
+pub fn tenants(admin_group: Option<&str>) -> TenantRegistry {
+    let admin_group = admin_group
+        .map(|group| format!("admin_group = \"{}\"", group))
+        .unwrap_or_default();
+    let toml = format!(
+        r#"
+        [[tenant]]
+        id = "acme"
+        region = "eu-west-1"
+        user_pool_id = "eu-west-1_acme"
+        client_id = "acme-client"
+        hosts = ["login.acme.com"]
+        {}
+        "#,
+        admin_group
+    );
+    TenantRegistry::parse(&toml).unwrap()
+}
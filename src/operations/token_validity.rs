@@ -0,0 +1,302 @@
+use crate::errors::server::ServerError;
+use actix_web::cookie::time::Duration;
+use aws_config::SdkConfig;
+use aws_sdk_cognitoidentityprovider::{
+    types::{TimeUnitsType, UserPoolClientType},
+    Client,
+};
+use std::{collections::HashMap, env, sync::RwLock};
+
+// Cognito's own defaults for an app client that never changed them
+const DEFAULT_ACCESS_TOKEN_VALIDITY: i64 = 60 * 60;
+const DEFAULT_ID_TOKEN_VALIDITY: i64 = 60 * 60;
+const DEFAULT_REFRESH_TOKEN_VALIDITY: i64 = 30 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenValidity {
+    pub access_token: Duration,
+    pub id_token: Duration,
+    pub refresh_token: Duration,
+}
+
+impl Default for TokenValidity {
+    fn default() -> Self {
+        TokenValidity {
+            access_token: Duration::seconds(DEFAULT_ACCESS_TOKEN_VALIDITY),
+            id_token: Duration::seconds(DEFAULT_ID_TOKEN_VALIDITY),
+            refresh_token: Duration::seconds(DEFAULT_REFRESH_TOKEN_VALIDITY),
+        }
+    }
+}
+
+impl TokenValidity {
+    // Values are in seconds; any that are left unset fall back to Cognito's defaults
+    pub fn from_env() -> Option<TokenValidity> {
+        TokenValidity::from_vars(|name| env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Option<TokenValidity> {
+        let seconds = |name: &str| {
+            var(name)
+                .and_then(|v| v.parse::<i64>().ok())
+                .map(Duration::seconds)
+        };
+        let access_token = seconds("ACCESS_TOKEN_VALIDITY");
+        let id_token = seconds("ID_TOKEN_VALIDITY");
+        let refresh_token = seconds("REFRESH_TOKEN_VALIDITY");
+
+        if access_token.is_none() && id_token.is_none() && refresh_token.is_none() {
+            return None;
+        }
+
+        let defaults = TokenValidity::default();
+        Some(TokenValidity {
+            access_token: access_token.unwrap_or(defaults.access_token),
+            id_token: id_token.unwrap_or(defaults.id_token),
+            refresh_token: refresh_token.unwrap_or(defaults.refresh_token),
+        })
+    }
+
+    pub async fn describe(
+        config: &SdkConfig,
+        user_pool_id: Option<String>,
+        client_id: Option<String>,
+    ) -> Result<TokenValidity, ServerError> {
+        let output = Client::new(config)
+            .describe_user_pool_client()
+            .set_user_pool_id(user_pool_id)
+            .set_client_id(client_id)
+            .send()
+            .await?;
+
+        Ok(TokenValidity::from_app_client(output.user_pool_client()))
+    }
+
+    fn from_app_client(app_client: Option<&UserPoolClientType>) -> TokenValidity {
+        let defaults = TokenValidity::default();
+        let app_client = match app_client {
+            Some(app_client) => app_client,
+            None => return defaults,
+        };
+        let units = app_client.token_validity_units();
+
+        // Without explicit units Cognito counts access and ID tokens in hours, refresh tokens in days
+        TokenValidity {
+            access_token: app_client
+                .access_token_validity()
+                .map(|v| {
+                    to_duration(
+                        v,
+                        units.and_then(|u| u.access_token()),
+                        &TimeUnitsType::Hours,
+                    )
+                })
+                .unwrap_or(defaults.access_token),
+            id_token: app_client
+                .id_token_validity()
+                .map(|v| to_duration(v, units.and_then(|u| u.id_token()), &TimeUnitsType::Hours))
+                .unwrap_or(defaults.id_token),
+            refresh_token: match app_client.refresh_token_validity() {
+                0 => defaults.refresh_token,
+                v => to_duration(
+                    v,
+                    units.and_then(|u| u.refresh_token()),
+                    &TimeUnitsType::Days,
+                ),
+            },
+        }
+    }
+
+    pub fn for_token(&self, name: &str) -> Option<Duration> {
+        match name {
+            "access_token" => Some(self.access_token),
+            "id_token" => Some(self.id_token),
+            "refresh_token" => Some(self.refresh_token),
+            _ => None,
+        }
+    }
+}
+
+// Config wins; otherwise each app client is described once and the answer reused. A failed
+// describe isn't cached, so the next request asks again rather than keeping the defaults.
+#[derive(Debug)]
+pub struct TokenValidityCache {
+    configured: Option<TokenValidity>,
+    described: RwLock<HashMap<String, TokenValidity>>,
+}
+
+impl TokenValidityCache {
+    pub fn from_env() -> TokenValidityCache {
+        TokenValidityCache {
+            configured: TokenValidity::from_env(),
+            described: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub async fn get(
+        &self,
+        config: &SdkConfig,
+        user_pool_id: Option<String>,
+        client_id: Option<String>,
+    ) -> TokenValidity {
+        if let Some(validity) = self.configured {
+            return validity;
+        }
+
+        let key = client_id.clone().unwrap_or_default();
+        if let Some(validity) = self.described.read().unwrap().get(&key) {
+            return *validity;
+        }
+
+        let validity = match TokenValidity::describe(config, user_pool_id, client_id).await {
+            Ok(validity) => validity,
+            Err(e) => {
+                println!(
+                    "DescribeUserPoolClient failed, using default token validity: {}",
+                    e.cause
+                );
+                return TokenValidity::default();
+            }
+        };
+
+        self.described.write().unwrap().insert(key, validity);
+        validity
+    }
+}
+
+fn to_duration(value: i32, unit: Option<&TimeUnitsType>, default_unit: &TimeUnitsType) -> Duration {
+    let value = i64::from(value);
+    match unit.unwrap_or(default_unit) {
+        TimeUnitsType::Seconds => Duration::seconds(value),
+        TimeUnitsType::Minutes => Duration::minutes(value),
+        TimeUnitsType::Hours => Duration::hours(value),
+        TimeUnitsType::Days => Duration::days(value),
+        _ => Duration::seconds(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_cognitoidentityprovider::types::TokenValidityUnitsType;
+
+    fn vars(vars: &[(&str, &str)]) -> Option<TokenValidity> {
+        TokenValidity::from_vars(|name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn converts_units() {
+        let seconds = TimeUnitsType::Seconds;
+        assert_eq!(
+            to_duration(90, Some(&seconds), &TimeUnitsType::Hours),
+            Duration::seconds(90)
+        );
+        assert_eq!(
+            to_duration(5, Some(&TimeUnitsType::Minutes), &seconds),
+            Duration::minutes(5)
+        );
+        assert_eq!(
+            to_duration(2, Some(&TimeUnitsType::Hours), &seconds),
+            Duration::hours(2)
+        );
+        assert_eq!(
+            to_duration(7, Some(&TimeUnitsType::Days), &seconds),
+            Duration::days(7)
+        );
+        assert_eq!(
+            to_duration(3, None, &TimeUnitsType::Days),
+            Duration::days(3)
+        );
+    }
+
+    #[test]
+    fn app_clients_without_units_count_hours_and_days() {
+        let app_client = UserPoolClientType::builder()
+            .access_token_validity(2)
+            .id_token_validity(3)
+            .refresh_token_validity(10)
+            .build();
+
+        assert_eq!(
+            TokenValidity::from_app_client(Some(&app_client)),
+            TokenValidity {
+                access_token: Duration::hours(2),
+                id_token: Duration::hours(3),
+                refresh_token: Duration::days(10),
+            }
+        );
+    }
+
+    #[test]
+    fn app_clients_with_units_use_them() {
+        let app_client = UserPoolClientType::builder()
+            .access_token_validity(30)
+            .refresh_token_validity(12)
+            .token_validity_units(
+                TokenValidityUnitsType::builder()
+                    .access_token(TimeUnitsType::Minutes)
+                    .refresh_token(TimeUnitsType::Hours)
+                    .build(),
+            )
+            .build();
+
+        let validity = TokenValidity::from_app_client(Some(&app_client));
+        assert_eq!(validity.access_token, Duration::minutes(30));
+        assert_eq!(validity.id_token, TokenValidity::default().id_token);
+        assert_eq!(validity.refresh_token, Duration::hours(12));
+    }
+
+    #[test]
+    fn unset_refresh_validity_falls_back_to_the_default() {
+        let app_client = UserPoolClientType::builder()
+            .refresh_token_validity(0)
+            .build();
+
+        assert_eq!(
+            TokenValidity::from_app_client(Some(&app_client)),
+            TokenValidity::default()
+        );
+        assert_eq!(
+            TokenValidity::from_app_client(None),
+            TokenValidity::default()
+        );
+    }
+
+    #[test]
+    fn env_overrides_only_what_is_set() {
+        assert_eq!(vars(&[]), None);
+        assert_eq!(vars(&[("ACCESS_TOKEN_VALIDITY", "not a number")]), None);
+        assert_eq!(
+            vars(&[("ID_TOKEN_VALIDITY", "300")]),
+            Some(TokenValidity {
+                id_token: Duration::seconds(300),
+                ..TokenValidity::default()
+            })
+        );
+        assert_eq!(
+            vars(&[
+                ("ACCESS_TOKEN_VALIDITY", "600"),
+                ("REFRESH_TOKEN_VALIDITY", "86400"),
+            ]),
+            Some(TokenValidity {
+                access_token: Duration::seconds(600),
+                id_token: TokenValidity::default().id_token,
+                refresh_token: Duration::days(1),
+            })
+        );
+    }
+
+    #[test]
+    fn cookies_only_get_lifetimes_for_tokens() {
+        let validity = TokenValidity::default();
+        assert_eq!(
+            validity.for_token("refresh_token"),
+            Some(validity.refresh_token)
+        );
+        assert_eq!(validity.for_token("session_token"), None);
+    }
+}
@@ -1,24 +1,81 @@
 use crate::errors::server::ServerError;
+use crate::operations::claims::{decode_unverified, AccessTokenClaims, IdTokenClaims};
+use crate::operations::cookie_policy::CookiePolicy;
+use crate::operations::session_token::SessionKeys;
+use crate::operations::token_validity::TokenValidity;
 use crate::operations::user_token::UserToken;
 use actix_web::{
-    cookie::{
-        time::{Duration, OffsetDateTime},
-        Cookie, SameSite,
-    },
-    HttpRequest, HttpResponse,
+    cookie::time::OffsetDateTime,
+    http::header::{self, HeaderValue as ActixHeaderValue},
+    web, HttpRequest, HttpResponse,
 };
 use aws_sdk_cognitoidentityprovider::types::AuthenticationResultType;
 use reqwest::header::HeaderValue;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenMode {
+    Cookie,
+    Body,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenModeQuery {
+    pub token_mode: Option<TokenMode>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct UserLoginRequest {
     pub email: String,
+    // Left empty by a form that only asks for an email to discover the user's IdP
+    #[serde(default)]
     pub password: String,
+    #[serde(default)]
+    pub remember_me: Option<String>,
+    #[serde(default)]
+    pub token_mode: Option<TokenMode>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub previous_password: String,
+    pub proposed_password: String,
 }
 
 impl UserLoginRequest {
+    // An explicit token_mode wins, then the Accept header; browsers get cookies by default
+    pub fn token_mode(&self, req: &HttpRequest) -> TokenMode {
+        let query = web::Query::<TokenModeQuery>::from_query(req.query_string())
+            .ok()
+            .and_then(|q| q.token_mode);
+
+        if let Some(mode) = self.token_mode.or(query) {
+            return mode;
+        }
+
+        let wants_json = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| {
+                v.split(',')
+                    .any(|mime| mime.trim().starts_with("application/json"))
+            })
+            .unwrap_or(false);
+
+        if wants_json {
+            TokenMode::Body
+        } else {
+            TokenMode::Cookie
+        }
+    }
+
+    pub fn remember_me(&self) -> bool {
+        is_checked(self.remember_me.as_deref())
+    }
+
     pub fn package_data(&self) -> HashMap<String, String> {
         HashMap::from([
             ("USERNAME".into(), self.email.clone()),
@@ -27,55 +84,53 @@ impl UserLoginRequest {
     }
 }
 
+#[derive(Debug, Serialize)]
+pub struct UserLoginResponse {
+    pub access_token: Option<String>,
+    pub id_token: Option<String>,
+    pub refresh_token: Option<String>,
+    pub session_token: Option<String>,
+    pub expires_in: i32,
+    pub token_type: String,
+}
+
 #[derive(Debug, Clone)]
 pub struct UserAuthCredentials<'a> {
     message: String,
     pub tokens: HashMap<&'a str, UserToken<'a>>,
     expires_in: i32,
+    token_type: Option<String>,
+    lifetimes: Option<TokenValidity>,
     domain: Option<HeaderValue>,
-}
-
-impl From<HttpRequest> for UserAuthCredentials<'_> {
-    fn from(req: HttpRequest) -> Self {
-        let mut user_auth_credentials = UserAuthCredentials::new("".into(), 0, None);
-
-        // let foo = req.cookie("id_token").unwrap().max_age();
-        // println!("{:?}", foo);
-
-        user_auth_credentials.tokens.insert(
-            "access_token",
-            UserToken::Cookie(req.cookie("access_token").unwrap()),
-        );
-        user_auth_credentials.tokens.insert(
-            "refresh_token",
-            UserToken::Cookie(req.cookie("refresh_token").unwrap()),
-        );
-        user_auth_credentials.tokens.insert(
-            "id_token",
-            UserToken::Cookie(req.cookie("id_token").unwrap()),
-        );
-
-        user_auth_credentials.domain = req.head().headers.get("host").cloned();
-
-        user_auth_credentials
-    }
+    secure: bool,
 }
 
 impl UserAuthCredentials<'_> {
-    pub fn new(message: String, expires_in: i32, domain: Option<HeaderValue>) -> Self {
+    pub fn new(
+        message: String,
+        expires_in: i32,
+        domain: Option<HeaderValue>,
+        secure: bool,
+    ) -> Self {
         UserAuthCredentials {
             message: message,
             tokens: HashMap::new(),
             expires_in: expires_in,
+            token_type: None,
+            lifetimes: None,
             domain: domain,
+            secure,
         }
     }
 
     pub fn build(
         auth_data: AuthenticationResultType,
         domain: Option<HeaderValue>,
+        secure: bool,
     ) -> UserAuthCredentials<'static> {
-        let mut res = UserAuthCredentials::new("Logged In".into(), auth_data.expires_in, domain);
+        let mut res =
+            UserAuthCredentials::new("Logged In".into(), auth_data.expires_in, domain, secure);
+        res.token_type = auth_data.token_type().map(|t| t.into());
         if let Some(access_token) = auth_data.access_token() {
             res.tokens.insert("access_token", access_token.into());
         }
@@ -88,27 +143,93 @@ impl UserAuthCredentials<'_> {
         res.clone()
     }
 
+    // Our own token for downstream services, minted from whatever Cognito just issued. Those
+    // tokens came straight from Cognito, so their claims are read without verifying them again.
+    pub fn add_session_token(
+        &mut self,
+        session_keys: &SessionKeys,
+        tenant: &str,
+    ) -> Result<(), ServerError> {
+        let (sub, groups) = match (self.tokens.get("id_token"), self.tokens.get("access_token")) {
+            (Some(UserToken::String(token)), _) => {
+                let claims: IdTokenClaims = decode_unverified(token)?;
+                (claims.sub, claims.groups)
+            }
+            (_, Some(UserToken::String(token))) => {
+                let claims: AccessTokenClaims = decode_unverified(token)?;
+                (claims.sub, claims.groups)
+            }
+            _ => return Ok(()),
+        };
+
+        let session_token = session_keys.mint(&sub, tenant, groups)?;
+        self.tokens.insert("session_token", session_token.into());
+
+        Ok(())
+    }
+
+    // None leaves every token as a session cookie
+    pub fn set_lifetimes(&mut self, lifetimes: Option<TokenValidity>) {
+        self.lifetimes = lifetimes;
+    }
+
     // pub fn set_tokens(&mut self, tokens: HashMap<String, Token>) {
     //     &mut self.tokens = &mut tokens.clone();
     // }
 
-    pub fn cookify(&mut self) {
+    pub fn cookify(&mut self, policy: &CookiePolicy, req: &HttpRequest) {
         let mut new_tokens: HashMap<&str, UserToken> = HashMap::new();
         for (k, v) in self.tokens.iter_mut() {
             if let UserToken::String(token) = v {
-                let age = Duration::seconds(self.expires_in.into());
-                let cookie = Cookie::build(*k, token.clone())
-                    .max_age(age)
-                    .expires(OffsetDateTime::now_utc().checked_add(age))
-                    .same_site(SameSite::Lax)
-                    .path("/")
-                    .http_only(true)
-                    .domain("0.0.0.0")
-                    .finish();
-
-                // println!("{:?}", cookie);
-
-                new_tokens.insert(&k, UserToken::Cookie(cookie));
+                // The session token is already signed and is read as-is by downstream services
+                let value = if *k == "session_token" {
+                    token.clone()
+                } else {
+                    policy.seal(k, token)
+                };
+                let age = self.lifetimes.and_then(|l| l.for_token(k));
+                let build = |name: &str, value: String| {
+                    let mut cookie = policy.build(name, value, self.domain.as_ref(), self.secure);
+                    if let Some(age) = age {
+                        cookie = cookie
+                            .max_age(age)
+                            .expires(OffsetDateTime::now_utc().checked_add(age));
+                    }
+                    cookie.finish()
+                };
+
+                // Whichever form is written, anything left over from the other one has to go
+                let (mut cookies, first_stale_chunk) = match policy.split(&value) {
+                    Some(chunks) => {
+                        let count = chunks.len();
+                        let mut cookies: Vec<_> = chunks
+                            .into_iter()
+                            .enumerate()
+                            .map(|(i, chunk)| build(&format!("{}.{}", k, i), chunk))
+                            .collect();
+                        if req.cookie(&policy.name(k)).is_some() {
+                            cookies.push(policy.removal(k, self.domain.as_ref(), self.secure));
+                        }
+                        (cookies, count)
+                    }
+                    None => (vec![build(k, value)], 0),
+                };
+
+                let mut index = first_stale_chunk;
+                while req.cookie(&policy.chunk_name(k, index)).is_some() {
+                    cookies.push(policy.removal(
+                        &format!("{}.{}", k, index),
+                        self.domain.as_ref(),
+                        self.secure,
+                    ));
+                    index += 1;
+                }
+
+                let token = match cookies.len() {
+                    1 => UserToken::Cookie(cookies.remove(0)),
+                    _ => UserToken::Cookies(cookies),
+                };
+                new_tokens.insert(k, token);
             } else {
                 new_tokens.insert(&k, v.clone());
             }
@@ -118,14 +239,58 @@ impl UserAuthCredentials<'_> {
 
     pub fn response(&self) -> Result<HttpResponse, ServerError> {
         let mut res = HttpResponse::Ok().body(self.message.clone());
+        self.add_cookies(&mut res)?;
 
+        Ok(res)
+    }
+
+    pub fn redirect_response(&self, location: &str) -> Result<HttpResponse, ServerError> {
+        let mut res = HttpResponse::Found()
+            .insert_header((header::LOCATION, location))
+            .finish();
+        self.add_cookies(&mut res)?;
+
+        Ok(res)
+    }
+
+    fn add_cookies(&self, res: &mut HttpResponse) -> Result<(), ServerError> {
         for (_k, v) in &self.tokens {
-            if let UserToken::Cookie(cookie) = v {
-                res.add_cookie(cookie)?;
+            match v {
+                UserToken::Cookie(cookie) => res.add_cookie(cookie)?,
+                UserToken::Cookies(cookies) => {
+                    for cookie in cookies {
+                        res.add_cookie(cookie)?;
+                    }
+                }
+                UserToken::String(_) => {}
             }
         }
 
-        Ok(res)
+        Ok(())
+    }
+
+    // Hands the raw tokens back in the body, for clients that can't or won't keep cookies
+    pub fn json_response(&self) -> Result<HttpResponse, ServerError> {
+        let token = |name: &str| match self.tokens.get(name) {
+            Some(UserToken::String(token)) => Some(token.clone()),
+            _ => None,
+        };
+
+        let body = UserLoginResponse {
+            access_token: token("access_token"),
+            id_token: token("id_token"),
+            refresh_token: token("refresh_token"),
+            session_token: token("session_token"),
+            expires_in: self.expires_in,
+            token_type: self.token_type.clone().unwrap_or_else(|| "Bearer".into()),
+        };
+
+        Ok(HttpResponse::Ok()
+            .insert_header((
+                header::CACHE_CONTROL,
+                ActixHeaderValue::from_static("no-store"),
+            ))
+            .json(body))
     }
 
     // pub fn validate(&self) -> Result<HttpResponse, ServerError> {
@@ -133,9 +298,124 @@ impl UserAuthCredentials<'_> {
     // }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct UserClaim {
-    sub: String,
-    company: String,
-    exp: usize,
+// HTML checkboxes post "on" when ticked and nothing at all otherwise
+pub fn is_checked(value: Option<&str>) -> bool {
+    matches!(value, Some("on") | Some("true") | Some("1"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::cookie_crypto::CookieSealer;
+    use crate::operations::test_support::cookie_policy;
+    use actix_web::test::TestRequest;
+    use std::sync::Arc;
+
+    fn sealing_policy() -> CookiePolicy {
+        let mut sealer = CookieSealer::new();
+        sealer.insert("k1", &[7; 32]).unwrap();
+
+        let mut policy = cookie_policy();
+        policy.sealer = Some(Arc::new(sealer));
+        policy
+    }
+
+    fn login(token_mode: Option<TokenMode>) -> UserLoginRequest {
+        UserLoginRequest {
+            email: "alice@acme.com".into(),
+            password: "secret".into(),
+            remember_me: None,
+            token_mode,
+        }
+    }
+
+    fn cookie_value(credentials: &UserAuthCredentials, name: &str) -> String {
+        match credentials.tokens.get(name) {
+            Some(UserToken::Cookie(cookie)) => cookie.value().into(),
+            other => panic!("{} is not a single cookie: {:?}", name, other),
+        }
+    }
+
+    #[test]
+    fn browsers_get_cookies_by_default() {
+        let req = TestRequest::post()
+            .insert_header(("accept", "text/html,application/xhtml+xml,*/*;q=0.8"))
+            .to_http_request();
+        assert_eq!(login(None).token_mode(&req), TokenMode::Cookie);
+
+        let req = TestRequest::post().to_http_request();
+        assert_eq!(login(None).token_mode(&req), TokenMode::Cookie);
+    }
+
+    #[test]
+    fn json_clients_get_the_body() {
+        let req = TestRequest::post()
+            .insert_header(("accept", "text/plain, application/json; charset=utf-8"))
+            .to_http_request();
+        assert_eq!(login(None).token_mode(&req), TokenMode::Body);
+    }
+
+    #[test]
+    fn explicit_modes_win() {
+        let json = TestRequest::post()
+            .insert_header(("accept", "application/json"))
+            .to_http_request();
+        assert_eq!(
+            login(Some(TokenMode::Cookie)).token_mode(&json),
+            TokenMode::Cookie
+        );
+
+        let req = TestRequest::post()
+            .uri("/login?token_mode=body")
+            .to_http_request();
+        assert_eq!(login(None).token_mode(&req), TokenMode::Body);
+
+        let req = TestRequest::post()
+            .uri("/login?token_mode=cookie")
+            .insert_header(("accept", "application/json"))
+            .to_http_request();
+        assert_eq!(login(None).token_mode(&req), TokenMode::Cookie);
+
+        // The request body wins over the query string
+        let req = TestRequest::post()
+            .uri("/login?token_mode=cookie")
+            .to_http_request();
+        assert_eq!(
+            login(Some(TokenMode::Body)).token_mode(&req),
+            TokenMode::Body
+        );
+    }
+
+    #[test]
+    fn unknown_query_modes_are_ignored() {
+        let req = TestRequest::post()
+            .uri("/login?token_mode=header")
+            .to_http_request();
+        assert_eq!(login(None).token_mode(&req), TokenMode::Cookie);
+    }
+
+    #[test]
+    fn cookify_leaves_the_session_token_unsealed() {
+        let policy = sealing_policy();
+        let req = TestRequest::default().to_http_request();
+
+        let mut credentials = UserAuthCredentials::new("Logged In".into(), 3600, None, false);
+        credentials
+            .tokens
+            .insert("session_token", "header.claims.signature".into());
+        credentials.tokens.insert("access_token", "access".into());
+        credentials.cookify(&policy, &req);
+
+        assert_eq!(
+            cookie_value(&credentials, "session_token"),
+            "header.claims.signature"
+        );
+
+        let access_token = cookie_value(&credentials, "access_token");
+        assert_ne!(access_token, "access");
+        assert_eq!(
+            policy.open("access_token", &access_token).unwrap(),
+            "access"
+        );
+    }
 }
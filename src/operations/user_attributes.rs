@@ -0,0 +1,115 @@
+use crate::errors::server::ServerError;
+use crate::operations::authenticated_user::{AuthenticatedUser, VerifiedIdToken};
+use crate::operations::session_token::now;
+use crate::operations::tenant::{CurrentTenant, Tenant};
+use crate::operations::user_directory::UserDirectory;
+use actix_web::{web, FromRequest, HttpRequest};
+use std::{collections::HashMap, env, sync::Mutex};
+
+// How long an email looked up in the pool is used before asking again
+const DEFAULT_CACHE_SECS: i64 = 300;
+
+struct CachedEmail {
+    email: Option<String>,
+    valid_until: i64,
+}
+
+// Verified emails of users whose request came without an ID token, looked up in their tenant's
+// pool. Access tokens carry no email of their own, and anything claiming to be one there can't
+// be trusted as the user's address.
+pub struct UserEmails {
+    entries: Mutex<HashMap<String, CachedEmail>>,
+    cache_secs: i64,
+}
+
+impl UserEmails {
+    pub fn from_env() -> UserEmails {
+        UserEmails {
+            entries: Mutex::new(HashMap::new()),
+            cache_secs: env::var("USER_EMAIL_CACHE_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_CACHE_SECS),
+        }
+    }
+
+    async fn lookup(
+        &self,
+        tenant: &Tenant,
+        user: &AuthenticatedUser,
+    ) -> Result<Option<String>, ServerError> {
+        let key = format!("{}#{}", tenant.id, user.sub);
+        let now = now();
+        if let Some(cached) = self
+            .entries
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|cached| cached.valid_until > now)
+        {
+            return Ok(cached.email.clone());
+        }
+
+        let directory = UserDirectory::new(tenant.sdk_config().await, tenant.user_pool_id.clone());
+        let mut account = directory
+            .get(user.username.as_deref().unwrap_or(&user.sub))
+            .await?;
+        let email = match account.attributes.get("email_verified").map(String::as_str) {
+            Some("true") => account.attributes.remove("email"),
+            _ => None,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, cached| cached.valid_until > now);
+        entries.insert(
+            key,
+            CachedEmail {
+                email: email.clone(),
+                valid_until: now + self.cache_secs,
+            },
+        );
+
+        Ok(email)
+    }
+}
+
+// The email upstreams may rely on: from the request's ID token when it verifies and names the
+// same user, otherwise from the user's pool. Only verified addresses are given out.
+pub async fn verified_email(
+    req: &HttpRequest,
+    user: &AuthenticatedUser,
+) -> Result<Option<String>, ServerError> {
+    if user.is_service() {
+        return Ok(None);
+    }
+
+    if let Ok(VerifiedIdToken(claims)) = VerifiedIdToken::extract(req).await {
+        if claims.sub == user.sub {
+            return Ok(claims.email.filter(|_| claims.email_verified == Some(true)));
+        }
+    }
+
+    match req.app_data::<web::Data<UserEmails>>() {
+        Some(emails) => emails.lookup(&*CurrentTenant::get(req)?, user).await,
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::test_support::{service, user};
+    use actix_web::test::TestRequest;
+
+    // Nothing the access token says is taken for an email, so without an ID token or a pool
+    // to ask there is none
+    #[actix_rt::test]
+    async fn no_email_without_id_token_or_lookup() {
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(verified_email(&req, &user(&[], &[])).await.unwrap(), None);
+        assert_eq!(
+            verified_email(&req, &service("client", &[])).await.unwrap(),
+            None
+        );
+    }
+}
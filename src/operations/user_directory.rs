@@ -0,0 +1,522 @@
+use crate::errors::{admin::AdminError, auth::AuthError, server::ServerError};
+use aws_config::SdkConfig;
+use aws_sdk_cognitoidentityprovider::{
+    error::ProvideErrorMetadata,
+    types::{AttributeType, DeliveryMediumType, GroupType, MessageActionType, UserType},
+    Client,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// ListUsers refuses anything above 60
+const MAX_PAGE_SIZE: i32 = 60;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserSummary {
+    pub username: String,
+    pub attributes: HashMap<String, String>,
+    pub enabled: bool,
+    pub status: Option<String>,
+    pub created_at: Option<i64>,
+    pub updated_at: Option<i64>,
+}
+
+impl From<&UserType> for UserSummary {
+    fn from(user: &UserType) -> Self {
+        UserSummary {
+            username: user.username().unwrap_or_default().into(),
+            attributes: to_attributes(user.attributes()),
+            enabled: user.enabled(),
+            status: user.user_status().map(|s| s.as_str().into()),
+            created_at: user.user_create_date().map(|d| d.secs()),
+            updated_at: user.user_last_modified_date().map(|d| d.secs()),
+        }
+    }
+}
+
+// Lower precedence wins: it decides which group's IAM role a user gets, and which role is
+// treated as theirs when they hold several
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupSummary {
+    pub name: String,
+    pub description: Option<String>,
+    pub precedence: Option<i32>,
+    pub created_at: Option<i64>,
+    pub updated_at: Option<i64>,
+}
+
+impl From<&GroupType> for GroupSummary {
+    fn from(group: &GroupType) -> Self {
+        GroupSummary {
+            name: group.group_name().unwrap_or_default().into(),
+            description: group.description().map(|d| d.into()),
+            precedence: group.precedence(),
+            created_at: group.creation_date().map(|d| d.secs()),
+            updated_at: group.last_modified_date().map(|d| d.secs()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateGroupRequest {
+    pub name: String,
+    pub description: Option<String>,
+    pub precedence: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListGroupMembersQuery {
+    pub limit: Option<i32>,
+    pub pagination_token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserPage {
+    pub users: Vec<UserSummary>,
+    pub pagination_token: Option<String>,
+}
+
+// filter uses ListUsers' own syntax, e.g. email ^= "jane"
+#[derive(Debug, Deserialize)]
+pub struct ListUsersQuery {
+    pub limit: Option<i32>,
+    pub pagination_token: Option<String>,
+    pub filter: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUserRequest {
+    pub email: String,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    // Cognito generates one when left out
+    pub temporary_password: Option<String>,
+    #[serde(default = "send_invite_default")]
+    pub send_invite: bool,
+}
+
+fn send_invite_default() -> bool {
+    true
+}
+
+pub struct UserDirectory {
+    client: Client,
+    user_pool_id: String,
+}
+
+impl UserDirectory {
+    pub fn new(config: &SdkConfig, user_pool_id: String) -> UserDirectory {
+        UserDirectory {
+            client: Client::new(config),
+            user_pool_id,
+        }
+    }
+
+    pub async fn list(&self, query: &ListUsersQuery) -> Result<UserPage, ServerError> {
+        let limit = query.limit.unwrap_or(MAX_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+        let output = self
+            .client
+            .list_users()
+            .user_pool_id(&self.user_pool_id)
+            .limit(limit)
+            .set_pagination_token(query.pagination_token.clone())
+            .set_filter(query.filter.clone())
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+
+        Ok(UserPage {
+            users: output
+                .users()
+                .unwrap_or_default()
+                .iter()
+                .map(UserSummary::from)
+                .collect(),
+            pagination_token: output.pagination_token().map(|t| t.into()),
+        })
+    }
+
+    pub async fn get(&self, username: &str) -> Result<UserSummary, ServerError> {
+        let output = self
+            .client
+            .admin_get_user()
+            .user_pool_id(&self.user_pool_id)
+            .username(username)
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+
+        Ok(UserSummary {
+            username: output.username().unwrap_or_default().into(),
+            attributes: to_attributes(output.user_attributes()),
+            enabled: output.enabled(),
+            status: output.user_status().map(|s| s.as_str().into()),
+            created_at: output.user_create_date().map(|d| d.secs()),
+            updated_at: output.user_last_modified_date().map(|d| d.secs()),
+        })
+    }
+
+    // The email doubles as the username and counts as verified, since the invite goes to it
+    pub async fn create(&self, request: CreateUserRequest) -> Result<UserSummary, ServerError> {
+        let mut attributes = request.attributes;
+        attributes.insert("email".into(), request.email.clone());
+        attributes.insert("email_verified".into(), "true".into());
+
+        let mut builder = self
+            .client
+            .admin_create_user()
+            .user_pool_id(&self.user_pool_id)
+            .username(&request.email)
+            .set_temporary_password(request.temporary_password)
+            .desired_delivery_mediums(DeliveryMediumType::Email);
+        for (name, value) in attributes {
+            builder =
+                builder.user_attributes(AttributeType::builder().name(name).value(value).build());
+        }
+        if !request.send_invite {
+            builder = builder.message_action(MessageActionType::Suppress);
+        }
+
+        let output = builder.send().await.map_err(AdminError::from_sdk)?;
+
+        Ok(output
+            .user()
+            .map(UserSummary::from)
+            .unwrap_or_else(|| UserSummary {
+                username: request.email,
+                attributes: HashMap::new(),
+                enabled: true,
+                status: None,
+                created_at: None,
+                updated_at: None,
+            }))
+    }
+
+    // Emails aren't necessarily usernames, and ListUsers filters are exact on them
+    pub async fn find_by_email(&self, email: &str) -> Result<Option<UserSummary>, ServerError> {
+        if email.contains('"') || email.contains('\\') {
+            return Err(AdminError::InvalidParameter("invalid email".into()).into());
+        }
+
+        let output = self
+            .client
+            .list_users()
+            .user_pool_id(&self.user_pool_id)
+            .limit(1)
+            .filter(format!("email = \"{}\"", email))
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+
+        Ok(output
+            .users()
+            .unwrap_or_default()
+            .first()
+            .map(UserSummary::from))
+    }
+
+    pub async fn set_attribute(
+        &self,
+        username: &str,
+        name: &str,
+        value: &str,
+    ) -> Result<(), ServerError> {
+        self.client
+            .admin_update_user_attributes()
+            .user_pool_id(&self.user_pool_id)
+            .username(username)
+            .user_attributes(AttributeType::builder().name(name).value(value).build())
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+
+        Ok(())
+    }
+
+    pub async fn delete_attribute(&self, username: &str, name: &str) -> Result<(), ServerError> {
+        self.client
+            .admin_delete_user_attributes()
+            .user_pool_id(&self.user_pool_id)
+            .username(username)
+            .user_attribute_names(name)
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+
+        Ok(())
+    }
+
+    pub async fn disable(&self, username: &str) -> Result<(), ServerError> {
+        self.client
+            .admin_disable_user()
+            .user_pool_id(&self.user_pool_id)
+            .username(username)
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+        Ok(())
+    }
+
+    pub async fn enable(&self, username: &str) -> Result<(), ServerError> {
+        self.client
+            .admin_enable_user()
+            .user_pool_id(&self.user_pool_id)
+            .username(username)
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+        Ok(())
+    }
+
+    pub async fn reset_password(&self, username: &str) -> Result<(), ServerError> {
+        self.client
+            .admin_reset_user_password()
+            .user_pool_id(&self.user_pool_id)
+            .username(username)
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, username: &str) -> Result<(), ServerError> {
+        self.client
+            .admin_delete_user()
+            .user_pool_id(&self.user_pool_id)
+            .username(username)
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+        Ok(())
+    }
+
+    // Revokes the user's refresh tokens; access tokens already issued live until they expire
+    pub async fn sign_out(&self, username: &str) -> Result<(), ServerError> {
+        self.client
+            .admin_user_global_sign_out()
+            .user_pool_id(&self.user_pool_id)
+            .username(username)
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+        Ok(())
+    }
+
+    // The signed-in user's own change, proven by their access token and current password
+    pub async fn change_password(
+        &self,
+        access_token: &str,
+        previous_password: &str,
+        proposed_password: &str,
+    ) -> Result<(), ServerError> {
+        self.client
+            .change_password()
+            .access_token(access_token)
+            .previous_password(previous_password)
+            .proposed_password(proposed_password)
+            .send()
+            .await
+            .map_err(|e| match e.code() {
+                Some("NotAuthorizedException") => {
+                    AuthError::InvalidRequest("incorrect password".into()).into()
+                }
+                _ => AdminError::from_sdk(e),
+            })?;
+        Ok(())
+    }
+
+    // Like sign_out, for the holder of the access token
+    pub async fn sign_out_self(&self, access_token: &str) -> Result<(), ServerError> {
+        self.client
+            .global_sign_out()
+            .access_token(access_token)
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+        Ok(())
+    }
+
+    pub async fn revoke_refresh_token(
+        &self,
+        refresh_token: &str,
+        client_id: &str,
+        client_secret: Option<&str>,
+    ) -> Result<(), ServerError> {
+        self.client
+            .revoke_token()
+            .token(refresh_token)
+            .client_id(client_id)
+            .set_client_secret(client_secret.map(|s| s.into()))
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+        Ok(())
+    }
+
+    pub async fn list_groups(&self) -> Result<Vec<GroupSummary>, ServerError> {
+        let mut groups = vec![];
+        let mut next_token = None;
+        loop {
+            let output = self
+                .client
+                .list_groups()
+                .user_pool_id(&self.user_pool_id)
+                .set_next_token(next_token)
+                .send()
+                .await
+                .map_err(AdminError::from_sdk)?;
+
+            groups.extend(
+                output
+                    .groups()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(GroupSummary::from),
+            );
+
+            next_token = output.next_token().map(|t| t.to_string());
+            if next_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(by_precedence(groups))
+    }
+
+    pub async fn create_group(
+        &self,
+        request: CreateGroupRequest,
+    ) -> Result<GroupSummary, ServerError> {
+        let output = self
+            .client
+            .create_group()
+            .user_pool_id(&self.user_pool_id)
+            .group_name(&request.name)
+            .set_description(request.description.clone())
+            .set_precedence(request.precedence)
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+
+        Ok(output
+            .group()
+            .map(GroupSummary::from)
+            .unwrap_or(GroupSummary {
+                name: request.name,
+                description: request.description,
+                precedence: request.precedence,
+                created_at: None,
+                updated_at: None,
+            }))
+    }
+
+    pub async fn delete_group(&self, group: &str) -> Result<(), ServerError> {
+        self.client
+            .delete_group()
+            .user_pool_id(&self.user_pool_id)
+            .group_name(group)
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+        Ok(())
+    }
+
+    pub async fn list_group_members(
+        &self,
+        group: &str,
+        query: &ListGroupMembersQuery,
+    ) -> Result<UserPage, ServerError> {
+        let limit = query.limit.unwrap_or(MAX_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+        let output = self
+            .client
+            .list_users_in_group()
+            .user_pool_id(&self.user_pool_id)
+            .group_name(group)
+            .limit(limit)
+            .set_next_token(query.pagination_token.clone())
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+
+        Ok(UserPage {
+            users: output
+                .users()
+                .unwrap_or_default()
+                .iter()
+                .map(UserSummary::from)
+                .collect(),
+            pagination_token: output.next_token().map(|t| t.into()),
+        })
+    }
+
+    pub async fn add_to_group(&self, username: &str, group: &str) -> Result<(), ServerError> {
+        self.client
+            .admin_add_user_to_group()
+            .user_pool_id(&self.user_pool_id)
+            .username(username)
+            .group_name(group)
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+        Ok(())
+    }
+
+    pub async fn remove_from_group(&self, username: &str, group: &str) -> Result<(), ServerError> {
+        self.client
+            .admin_remove_user_from_group()
+            .user_pool_id(&self.user_pool_id)
+            .username(username)
+            .group_name(group)
+            .send()
+            .await
+            .map_err(AdminError::from_sdk)?;
+        Ok(())
+    }
+
+    pub async fn groups_for_user(&self, username: &str) -> Result<Vec<GroupSummary>, ServerError> {
+        let mut groups = vec![];
+        let mut next_token = None;
+        loop {
+            let output = self
+                .client
+                .admin_list_groups_for_user()
+                .user_pool_id(&self.user_pool_id)
+                .username(username)
+                .set_next_token(next_token)
+                .send()
+                .await
+                .map_err(AdminError::from_sdk)?;
+
+            groups.extend(
+                output
+                    .groups()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(GroupSummary::from),
+            );
+
+            next_token = output.next_token().map(|t| t.to_string());
+            if next_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(by_precedence(groups))
+    }
+}
+
+// Groups without a precedence rank below every group that has one
+fn by_precedence(mut groups: Vec<GroupSummary>) -> Vec<GroupSummary> {
+    groups.sort_by_key(|group| (group.precedence.unwrap_or(i32::MAX), group.name.clone()));
+    groups
+}
+
+fn to_attributes(attributes: Option<&[AttributeType]>) -> HashMap<String, String> {
+    attributes
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|a| Some((a.name()?.to_string(), a.value()?.to_string())))
+        .collect()
+}
@@ -4,6 +4,8 @@ use actix_web::cookie::Cookie;
 pub enum UserToken<'a> {
     String(String),
     Cookie(Cookie<'a>),
+    // A token spread over several cookies, along with removals for chunks it no longer needs
+    Cookies(Vec<Cookie<'a>>),
 }
 
 impl From<String> for UserToken<'_> {
@@ -0,0 +1,424 @@
+use crate::errors::{auth::AuthError, server::ServerError};
+use crate::operations::authenticated_user::AuthenticatedUser;
+use crate::operations::claims::AccessTokenClaims;
+use crate::operations::denylist::TokenDenylist;
+use crate::operations::session_token::now;
+use aws_config::SdkConfig;
+use aws_sdk_dynamodb::{
+    error::SdkError,
+    types::{AttributeValue, ReturnValue},
+    Client,
+};
+use base64::{engine::general_purpose, Engine};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, env, sync::Mutex};
+
+const TICKET_TTL: i64 = 30;
+
+// Who opened the connection, as the WebSocket service gets it back for a ticket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TicketClaims {
+    pub sub: String,
+    pub username: Option<String>,
+    pub groups: Vec<String>,
+    pub scopes: Vec<String>,
+    pub tenant: String,
+    // When the credential the ticket was issued for expires
+    pub exp: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IssuedTicket {
+    pub ticket: String,
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RedeemTicketRequest {
+    pub ticket: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+struct PendingTicket {
+    claims: TicketClaims,
+    // The Cognito session behind the ticket, checked against the denylist again on redemption
+    session: Option<AccessTokenClaims>,
+    expires_at: i64,
+}
+
+// Single-use tickets for WebSocket upgrades, which browsers can't send an Authorization header
+// with. Redeeming is a conditional delete only one caller can win, so a replayed ticket fails.
+pub struct WsTickets {
+    table: Option<String>,
+    pending: Mutex<HashMap<String, PendingTicket>>,
+}
+
+impl WsTickets {
+    // WS_TICKET_TABLE is keyed on "id", with "expires_at" as its TTL attribute; without it
+    // tickets are kept here and can only be redeemed at the instance that issued them
+    pub fn from_env() -> WsTickets {
+        WsTickets {
+            table: env::var("WS_TICKET_TABLE").ok(),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn issue(
+        &self,
+        config: &SdkConfig,
+        denylist: Option<&TokenDenylist>,
+        tenant: &str,
+        user: &AuthenticatedUser,
+    ) -> Result<IssuedTicket, ServerError> {
+        if user.expires_at <= now() {
+            return Err(AuthError::TokenExpired.into());
+        }
+        if let (Some(denylist), Some(session)) = (denylist, &user.claims) {
+            denylist.check(config, tenant, session).await?;
+        }
+
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let ticket = general_purpose::URL_SAFE_NO_PAD.encode(secret);
+
+        let claims = TicketClaims {
+            sub: user.sub.clone(),
+            username: user.username.clone(),
+            groups: user.groups.clone(),
+            scopes: user.scopes.clone(),
+            tenant: tenant.into(),
+            exp: user.expires_at,
+        };
+        let expires_at = now() + TICKET_TTL;
+
+        match &self.table {
+            Some(table) => {
+                let mut put = Client::new(config)
+                    .put_item()
+                    .table_name(table)
+                    .item("id", AttributeValue::S(digest(&ticket)))
+                    .item(
+                        "claims",
+                        AttributeValue::S(serde_json::to_string(&claims).unwrap_or_default()),
+                    )
+                    .item("tenant", AttributeValue::S(tenant.into()))
+                    .item("expires_at", AttributeValue::N(expires_at.to_string()))
+                    .condition_expression("attribute_not_exists(id)");
+                if let Some(session) = &user.claims {
+                    put = put.item(
+                        "session",
+                        AttributeValue::S(serde_json::to_string(session).unwrap_or_default()),
+                    );
+                }
+                put.send().await?;
+            }
+            None => {
+                let mut pending = self.pending.lock().unwrap();
+                let now = now();
+                pending.retain(|_, ticket| ticket.expires_at > now);
+                pending.insert(
+                    digest(&ticket),
+                    PendingTicket {
+                        claims,
+                        session: user.claims.clone(),
+                        expires_at,
+                    },
+                );
+            }
+        }
+
+        Ok(IssuedTicket {
+            ticket,
+            expires_in: TICKET_TTL,
+        })
+    }
+
+    pub async fn redeem(
+        &self,
+        config: &SdkConfig,
+        denylist: Option<&TokenDenylist>,
+        tenant: &str,
+        ticket: &str,
+    ) -> Result<TicketClaims, ServerError> {
+        let invalid = || AuthError::InvalidToken("unknown or already used ticket".into());
+
+        // Only the tenant the ticket was issued in can use it up, so presenting it at another
+        // tenant's host leaves it for its owner
+
+        let pending = match &self.table {
+            Some(table) => {
+                let result = Client::new(config)
+                    .delete_item()
+                    .table_name(table)
+                    .key("id", AttributeValue::S(digest(ticket)))
+                    .condition_expression("tenant = :tenant")
+                    .expression_attribute_values(":tenant", AttributeValue::S(tenant.into()))
+                    .return_values(ReturnValue::AllOld)
+                    .send()
+                    .await;
+
+                let item = match result {
+                    Ok(output) => output.attributes().cloned().ok_or_else(invalid)?,
+                    Err(SdkError::ServiceError(e))
+                        if e.err().is_conditional_check_failed_exception() =>
+                    {
+                        return Err(invalid().into())
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+
+                PendingTicket {
+                    claims: item
+                        .get("claims")
+                        .and_then(|v| v.as_s().ok())
+                        .and_then(|v| serde_json::from_str(v).ok())
+                        .ok_or_else(invalid)?,
+                    session: item
+                        .get("session")
+                        .and_then(|v| v.as_s().ok())
+                        .and_then(|v| serde_json::from_str(v).ok()),
+                    expires_at: item
+                        .get("expires_at")
+                        .and_then(|v| v.as_n().ok())
+                        .and_then(|v| v.parse().ok())
+                        .unwrap_or_default(),
+                }
+            }
+            None => {
+                let mut pending = self.pending.lock().unwrap();
+                let key = digest(ticket);
+                match pending.get(&key) {
+                    Some(ticket) if ticket.claims.tenant == tenant => pending.remove(&key),
+                    _ => None,
+                }
+                .ok_or_else(invalid)?
+            }
+        };
+
+        // DynamoDB's TTL deletes items when it gets round to it, not when they expire
+        // The ticket can't outlive the credential it was issued for, nor its revocation
+        let now = now();
+        if pending.expires_at <= now || pending.claims.exp <= now {
+            return Err(AuthError::TokenExpired.into());
+        }
+        if let (Some(denylist), Some(session)) = (denylist, &pending.session) {
+            denylist.check(config, tenant, session).await?;
+        }
+
+        Ok(pending.claims)
+    }
+}
+
+// Tickets are bearer credentials, so only their digest is stored
+fn digest(ticket: &str) -> String {
+    general_purpose::STANDARD.encode(Sha256::digest(ticket.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::test_support::{dynamodb, dynamodb_error, sdk_config, user};
+    use actix_web::{HttpResponse, ResponseError};
+    use serde_json::{json, Value};
+
+    fn tickets() -> WsTickets {
+        WsTickets {
+            table: None,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn table() -> WsTickets {
+        WsTickets {
+            table: Some("tickets".into()),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn status(result: Result<TicketClaims, ServerError>) -> u16 {
+        result.unwrap_err().error_response().status().as_u16()
+    }
+
+    fn claims(exp: i64) -> TicketClaims {
+        TicketClaims {
+            sub: "sub-1".into(),
+            username: Some("alice".into()),
+            groups: vec!["staff".into()],
+            scopes: vec![],
+            tenant: "acme".into(),
+            exp,
+        }
+    }
+
+    // The stored item as DeleteItem hands it back
+    fn deleted(claims: TicketClaims, expires_at: i64) -> HttpResponse {
+        HttpResponse::Ok().json(json!({
+            "Attributes": {
+                "id": {"S": digest("ticket")},
+                "claims": {"S": serde_json::to_string(&claims).unwrap()},
+                "tenant": {"S": "acme"},
+                "expires_at": {"N": expires_at.to_string()},
+            }
+        }))
+    }
+
+    #[actix_rt::test]
+    async fn tickets_are_single_use() {
+        let tickets = tickets();
+        let config = sdk_config();
+        let issued = tickets
+            .issue(&config, None, "acme", &user(&["staff"], &[]))
+            .await
+            .unwrap();
+
+        let claims = tickets
+            .redeem(&config, None, "acme", &issued.ticket)
+            .await
+            .unwrap();
+        assert_eq!(claims.sub, "sub-1");
+        assert_eq!(claims.groups, vec!["staff"]);
+
+        assert!(tickets
+            .redeem(&config, None, "acme", &issued.ticket)
+            .await
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn other_tenants_cannot_use_up_a_ticket() {
+        let tickets = tickets();
+        let config = sdk_config();
+        let issued = tickets
+            .issue(&config, None, "acme", &user(&["staff"], &[]))
+            .await
+            .unwrap();
+
+        assert!(tickets
+            .redeem(&config, None, "globex", &issued.ticket)
+            .await
+            .is_err());
+        assert!(tickets
+            .redeem(&config, None, "acme", &issued.ticket)
+            .await
+            .is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn redeems_through_a_conditional_delete() {
+        fn respond(operation: &str, body: Value) -> HttpResponse {
+            assert_eq!(operation, "DeleteItem");
+            assert_eq!(body["Key"]["id"]["S"], digest("ticket"));
+            assert_eq!(body["ConditionExpression"], "tenant = :tenant");
+            match body["ExpressionAttributeValues"][":tenant"]["S"].as_str() {
+                Some("acme") => deleted(claims(now() + 3600), now() + 30),
+                _ => dynamodb_error("ConditionalCheckFailedException"),
+            }
+        }
+        let config = dynamodb(respond).await;
+
+        let claims = table()
+            .redeem(&config, None, "acme", "ticket")
+            .await
+            .unwrap();
+        assert_eq!(claims.sub, "sub-1");
+        assert_eq!(
+            status(table().redeem(&config, None, "globex", "ticket").await),
+            401
+        );
+    }
+
+    #[actix_rt::test]
+    async fn used_up_tickets_are_unknown() {
+        fn respond(_: &str, _: Value) -> HttpResponse {
+            HttpResponse::Ok().json(json!({}))
+        }
+        let config = dynamodb(respond).await;
+
+        assert_eq!(
+            status(table().redeem(&config, None, "acme", "ticket").await),
+            401
+        );
+    }
+
+    #[actix_rt::test]
+    async fn expired_tickets_are_rejected() {
+        fn stale_ticket(_: &str, _: Value) -> HttpResponse {
+            deleted(claims(now() + 3600), now() - 1)
+        }
+        fn expired_credential(_: &str, _: Value) -> HttpResponse {
+            deleted(claims(now() - 1), now() + 30)
+        }
+
+        for respond in [stale_ticket, expired_credential] {
+            let config = dynamodb(respond).await;
+            let err = table()
+                .redeem(&config, None, "acme", "ticket")
+                .await
+                .unwrap_err();
+            assert_eq!(err.error_response().status().as_u16(), 401);
+            assert_eq!(err.cause.to_string(), AuthError::TokenExpired.to_string());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn tickets_expire_with_their_credential() {
+        let tickets = tickets();
+        let config = sdk_config();
+
+        let mut expired = user(&["staff"], &[]);
+        expired.expires_at = now() - 1;
+        assert!(tickets
+            .issue(&config, None, "acme", &expired)
+            .await
+            .is_err());
+
+        tickets.pending.lock().unwrap().insert(
+            digest("ticket"),
+            PendingTicket {
+                claims: claims(now() - 1),
+                session: None,
+                expires_at: now() + 30,
+            },
+        );
+        assert_eq!(
+            status(tickets.redeem(&config, None, "acme", "ticket").await),
+            401
+        );
+    }
+
+    #[actix_rt::test]
+    async fn revoked_sessions_cannot_use_or_get_tickets() {
+        let tickets = tickets();
+        let config = sdk_config();
+        let denylist = TokenDenylist::from_env();
+        let mut user = user(&["staff"], &[]);
+        if let Some(claims) = user.claims.as_mut() {
+            claims.auth_time = Some(now() - 60);
+        }
+
+        let issued = tickets
+            .issue(&config, Some(&denylist), "acme", &user)
+            .await
+            .unwrap();
+        denylist
+            .revoke_user(&config, "acme", "sub-1")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            status(
+                tickets
+                    .redeem(&config, Some(&denylist), "acme", &issued.ticket)
+                    .await
+            ),
+            401
+        );
+        assert!(tickets
+            .issue(&config, Some(&denylist), "acme", &user)
+            .await
+            .is_err());
+    }
+}